@@ -0,0 +1,19 @@
+#![no_main]
+use iota::data::{decode, payload};
+use iota::ui;
+use libfuzzer_sys::fuzz_target;
+use std::sync::Once;
+
+fuzz_target!(|data: &[u8]| {
+    // Plain mode, since that renders the flags as text too.
+    static INIT: Once = Once::new();
+    INIT.call_once(|| ui::init(ui::OutputFormat::Human, ui::ColorChoice::Never));
+
+    if let Ok(id) = decode::parse_id_message(data, payload::sniff(data)) {
+        let partitions = &id.software.partitions;
+        let flash_size = id.hardware.as_ref().and_then(|hw| hw.flash_size);
+        decode::format_partition_table(partitions);
+        decode::format_flash_map(partitions, flash_size, 80);
+        decode::decode_id_message(id);
+    }
+});
@@ -0,0 +1,7 @@
+#![no_main]
+use iota::data::{model, payload};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = payload::parse::<model::OtaMessage>(data, payload::sniff(data));
+});
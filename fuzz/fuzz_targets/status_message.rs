@@ -0,0 +1,9 @@
+#![no_main]
+use iota::data::{decode, model, payload};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(status) = payload::parse::<model::StatusMessage>(data, payload::sniff(data)) {
+        decode::decode_status_message(&status);
+    }
+});
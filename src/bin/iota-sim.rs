@@ -0,0 +1,105 @@
+use iota::{data, net::mqtt, sim};
+use rumqttc::{LastWill, QoS};
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// Simulate an iota device on the broker (set $IOTA_BROKER to pick a local one)
+#[derive(StructOpt, Debug)]
+#[structopt(name = "iota-sim")]
+pub struct Opts {
+    /// Name to publish the device under
+    device: String,
+    /// Partition layout, as a partitions.csv or compiled partitions.bin (default: two OTA slots)
+    #[structopt(long)]
+    partitions: Option<PathBuf>,
+    /// Project name to report
+    #[structopt(long, default_value = "sim")]
+    project: String,
+    /// Firmware version to report
+    #[structopt(long, default_value = "1.0.0")]
+    version: String,
+    /// Schema version to speak (default: the latest)
+    #[structopt(long)]
+    schema: Option<u32>,
+    /// Publish CBOR rather than JSON payloads
+    #[structopt(long)]
+    cbor: bool,
+    /// How long a simulated OTA download takes
+    #[structopt(long, default_value = "3s", parse(try_from_str = humantime::parse_duration))]
+    ota_duration: Duration,
+    /// How long the device stays down for when restarting
+    #[structopt(long, default_value = "1s", parse(try_from_str = humantime::parse_duration))]
+    restart_delay: Duration,
+}
+
+fn main() {
+    let opts = Opts::from_args();
+
+    let mut config = sim::Config::new(&opts.device);
+    if let Some(schema) = opts.schema {
+        config.schema = schema;
+    }
+    config.app_desc.project_name = opts.project;
+    config.app_desc.version = opts.version;
+    config.cbor = opts.cbor;
+    config.ota_duration = opts.ota_duration;
+    config.restart_delay = opts.restart_delay;
+
+    if let Some(path) = &opts.partitions {
+        config.layout = data::partition_table::load(path).unwrap_or_else(|e| {
+            eprintln!("Could not load partition table '{}': {}", path.display(), e);
+            std::process::exit(-1);
+        });
+    }
+
+    let mut device = sim::Device::new(config).unwrap_or_else(|e| {
+        eprintln!("Could not start simulator: {}", e);
+        std::process::exit(-1);
+    });
+
+    let (will_topic, will_payload) = device.last_will();
+    let mut mqtt_opts = mqtt::broker_options(&format!("iota-sim-{}", device.name()));
+    mqtt_opts.set_last_will(LastWill::new(
+        will_topic,
+        will_payload,
+        QoS::AtLeastOnce,
+        true,
+    ));
+
+    let (mut client, rx) = mqtt::connect_with(mqtt_opts);
+
+    for topic in device.subscriptions() {
+        client.subscribe(topic, QoS::ExactlyOnce).unwrap();
+    }
+
+    let run = |client: &mut rumqttc::Client, actions: Vec<sim::Action>| {
+        for action in actions {
+            match action {
+                sim::Action::Publish {
+                    topic,
+                    payload,
+                    retain,
+                } => {
+                    println!("-> {}", topic);
+                    client
+                        .publish(topic, QoS::ExactlyOnce, retain, payload)
+                        .unwrap();
+                }
+                sim::Action::Delay(delay) => std::thread::sleep(delay),
+            }
+        }
+    };
+
+    println!("Simulating device '{}'...", device.name());
+    run(&mut client, device.announce());
+
+    for msg in rx.iter() {
+        println!("<- {}", msg.topic);
+        let actions = device.handle(&msg.topic, &msg.payload);
+        run(&mut client, actions);
+    }
+
+    eprintln!("Disconnected from broker!");
+    std::process::exit(-1);
+}
@@ -0,0 +1,224 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::data::model;
+use crate::net::capture;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Cached<T> {
+    // Seconds since the UNIX epoch.
+    pub seen_at: u64,
+    pub value: T,
+}
+
+impl<T> Cached<T> {
+    fn now(value: T) -> Self {
+        Cached {
+            seen_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_secs(),
+            value,
+        }
+    }
+
+    pub fn seen_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.seen_at)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CachedDevice {
+    pub status: Option<Cached<model::StatusMessage>>,
+    pub id: Option<Cached<model::IdMessage>>,
+}
+
+thread_local! {
+    static ISOLATED_ROOT: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+// Keeps the cache for everything on this thread under `dir` from now on, so
+// that tests can each have one of their own.
+pub fn isolate(dir: PathBuf) {
+    ISOLATED_ROOT.with(|root| *root.borrow_mut() = Some(dir));
+}
+
+fn cache_root() -> Option<PathBuf> {
+    // A replay has to see just what was recorded, and isn't news to remember.
+    if capture::is_replaying() {
+        return None;
+    }
+
+    if let Some(dir) = ISOLATED_ROOT.with(|root| root.borrow().clone()) {
+        return Some(dir.join("iota"));
+    }
+
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+
+    Some(base.join("iota"))
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    Some(cache_root()?.join("devices"))
+}
+
+// Device names come from the command line as well as from topics, so make sure
+// they can't escape the cache directory.
+fn device_path(device_name: &str) -> Option<PathBuf> {
+    let valid = !device_name.is_empty()
+        && device_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if !valid {
+        return None;
+    }
+
+    Some(cache_dir()?.join(format!("{}.json", device_name)))
+}
+
+pub fn load(device_name: &str) -> Option<CachedDevice> {
+    let raw = fs::read(device_path(device_name)?).ok()?;
+    serde_json::from_slice(&raw).ok()
+}
+
+pub fn load_all() -> Vec<(String, CachedDevice)> {
+    let entries = match cache_dir().and_then(|dir| fs::read_dir(dir).ok()) {
+        None => return Vec::new(),
+        Some(entries) => entries,
+    };
+
+    entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let device_name = path.file_stem()?.to_str()?.to_owned();
+            let cached = load(&device_name)?;
+            Some((device_name, cached))
+        })
+        .collect()
+}
+
+// The cache is only ever a convenience, so failing to update it is not an
+// error worth interrupting anything for.
+fn update(device_name: &str, f: impl FnOnce(&mut CachedDevice)) {
+    let path = match device_path(device_name) {
+        None => return,
+        Some(path) => path,
+    };
+
+    let mut cached = load(device_name).unwrap_or_default();
+    f(&mut cached);
+
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    if let Ok(raw) = serde_json::to_vec(&cached) {
+        let _ = fs::write(&path, raw);
+    }
+}
+
+pub fn store_status(device_name: &str, status: &model::StatusMessage) {
+    update(device_name, |cached| {
+        cached.status = Some(Cached::now(status.clone()))
+    });
+}
+
+pub fn store_id(device_name: &str, id: &model::IdMessage) {
+    update(device_name, |cached| {
+        cached.id = Some(Cached::now(id.clone()))
+    });
+}
+
+// Hashes of the retained payloads we've seen on each topic, by when we first
+// saw them, since MQTT doesn't tell us when a retained message was published.
+type SeenPayloads = BTreeMap<String, Cached<u64>>;
+
+fn topics_path() -> Option<PathBuf> {
+    Some(cache_root()?.join("topics.json"))
+}
+
+// 64-bit FNV-1a, since the hashes outlive the binary which wrote them (unlike
+// those of the standard library's hashers, which may change between releases).
+fn payload_hash(payload: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    payload.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
+
+// When we first saw each of these retained payloads on its topic, or `None`
+// where it's new to us (and is remembered from now on).
+pub fn retained_first_seen(retained: &[(&str, &[u8])]) -> Vec<Option<SystemTime>> {
+    let path = topics_path();
+    let mut seen: SeenPayloads = path
+        .as_ref()
+        .and_then(|path| fs::read(path).ok())
+        .and_then(|raw| serde_json::from_slice(&raw).ok())
+        .unwrap_or_default();
+
+    let first_seen = retained
+        .iter()
+        .map(|(topic, payload)| {
+            let hash = payload_hash(payload);
+
+            match seen.get(*topic) {
+                Some(cached) if cached.value == hash => Some(cached.seen_at()),
+                _ => {
+                    seen.insert((*topic).to_owned(), Cached::now(hash));
+                    None
+                }
+            }
+        })
+        .collect();
+
+    if let Some(path) = path {
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        if let Ok(raw) = serde_json::to_vec(&seen) {
+            let _ = fs::write(&path, raw);
+        }
+    }
+
+    first_seen
+}
+
+// Drops everything cached about the device, returning whether there was any.
+pub fn forget_device(device_name: &str) -> bool {
+    match device_path(device_name) {
+        None => false,
+        Some(path) => fs::remove_file(path).is_ok(),
+    }
+}
+
+// Drops the first-seen times of the retained payloads on topics starting with
+// `prefix`.
+pub fn forget_retained(prefix: &str) {
+    let path = match topics_path() {
+        None => return,
+        Some(path) => path,
+    };
+
+    let mut seen: SeenPayloads = match fs::read(&path)
+        .ok()
+        .and_then(|raw| serde_json::from_slice(&raw).ok())
+    {
+        None => return,
+        Some(seen) => seen,
+    };
+
+    seen.retain(|topic, _| !topic.starts_with(prefix));
+
+    if let Ok(raw) = serde_json::to_vec(&seen) {
+        let _ = fs::write(&path, raw);
+    }
+}
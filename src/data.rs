@@ -1,2 +1,6 @@
 pub mod decode;
 pub mod model;
+pub mod partition_table;
+pub mod payload;
+pub mod report;
+pub mod schema;
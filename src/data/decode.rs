@@ -1,8 +1,11 @@
-use console::{style, Color};
-use single::Single;
+use console::{style, Color, StyledObject};
 use std::fmt::{Display, Write};
+use std::str::FromStr;
+use std::time::Duration;
 
 use super::model;
+use super::payload::{self, ContentType, PayloadError};
+use crate::ui;
 
 impl Display for model::DeviceState {
     fn fmt(&self, out: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
@@ -13,35 +16,220 @@ impl Display for model::DeviceState {
     }
 }
 
+impl Display for model::OtaState {
+    fn fmt(&self, out: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            model::OtaState::NotPresent => write!(out, "NotPresent"),
+            model::OtaState::New => write!(out, "New"),
+            model::OtaState::PendingVerify => write!(out, "PendingVerify"),
+            model::OtaState::Valid => write!(out, "Valid"),
+            model::OtaState::Invalid => write!(out, "Invalid"),
+            model::OtaState::Aborted => write!(out, "Aborted"),
+            model::OtaState::Undefined => write!(out, "Undefined"),
+            model::OtaState::Unknown(_) => write!(out, "?"),
+        }
+    }
+}
+
+impl Display for model::PartitionType {
+    fn fmt(&self, out: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let (name, subtype) = self.names();
+        write!(out, "{}/{}", name, subtype)
+    }
+}
+
+impl FromStr for model::DeviceState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_owned()))
+            .map_err(|_| format!("unknown device state '{}'", s))
+    }
+}
+
+impl FromStr for model::OtaState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match model::OtaState::from(s.to_owned()) {
+            model::OtaState::Unknown(_) => Err(format!("unknown OTA state '{}'", s)),
+            state => Ok(state),
+        }
+    }
+}
+
+// What a device has to understand for an operation. The commands themselves
+// all predate versioning, so only what was added to the protocol later on can
+// make a device too old.
+#[derive(Debug, Clone, Copy)]
+pub enum Capability {
+    Restart,
+    OtaUpdate,
+    OtaValidate,
+    OtaRollback,
+    Ack,
+    IdRefresh,
+}
+
+impl Capability {
+    pub fn min_schema_version(&self) -> u32 {
+        match self {
+            Capability::Restart => 1,
+            Capability::OtaUpdate => 1,
+            Capability::OtaValidate => 1,
+            Capability::OtaRollback => 1,
+            Capability::Ack => 3,
+            Capability::IdRefresh => 4,
+        }
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, out: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Capability::Restart => write!(out, "restart"),
+            Capability::OtaUpdate => write!(out, "OTA update"),
+            Capability::OtaValidate => write!(out, "OTA validate"),
+            Capability::OtaRollback => write!(out, "OTA rollback"),
+            Capability::Ack => write!(out, "command acknowledgement"),
+            Capability::IdRefresh => write!(out, "id refresh"),
+        }
+    }
+}
+
+pub fn parse_id_message(
+    payload: &[u8],
+    content_type: ContentType,
+) -> Result<model::IdMessage, PayloadError> {
+    let model::SchemaProbe { schema } = payload::parse(payload, content_type)?;
+
+    match schema {
+        model::LEGACY_SCHEMA_VERSION => {
+            payload::parse::<model::LenientIdMessage>(payload, content_type).map(From::from)
+        }
+        // Versions since only added commands (acks and id refreshes), so their
+        // id messages all read alike, and have to be what they say they are.
+        2..=model::SCHEMA_VERSION => payload::parse(payload, content_type),
+        // Newer versions than we know about are decoded on a best-effort
+        // basis, and `decode_id_message()` warns about them.
+        _ => payload::parse::<model::LenientIdMessage>(payload, content_type).map(From::from),
+    }
+}
+
 pub fn decode_status_message(status: &model::StatusMessage) -> String {
     let mut fmt = String::new();
     write!(fmt, "{}", status.state).unwrap();
     fmt
 }
 
-pub struct DecodedIdMessage<'a> {
-    pub msg: model::IdMessage<'a>,
+pub struct DecodedIdMessage {
+    pub msg: model::IdMessage,
     pub ota_info: RuntimeOtaInfo,
+    pub warnings: Vec<DecodeWarning>,
+    // Set by whoever received it, if it was retained for long enough that the
+    // device may well have moved on since.
+    pub stale: bool,
+}
+
+impl DecodedIdMessage {
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.msg.schema >= capability.min_schema_version()
+    }
 }
 
 pub struct RuntimeOtaInfo {
-    pub running_addr: usize,
-    pub running_on_part: RunningOnPart,
-    pub running_ota_state: model::OtaState,
+    pub running: Option<RunningPartInfo>,
 
-    pub next_update_addr: usize,
+    // Perfectly normal to be missing, e.g. for a device with only one app slot.
+    pub next_update_addr: Option<usize>,
 
     pub fmt: String,
 }
 
+pub struct RunningPartInfo {
+    pub addr: usize,
+    pub part: RunningOnPart,
+    pub ota_state: model::OtaState,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RunningOnPart {
     Factory,
     Ota { id: usize },
 }
 
+#[derive(Debug, Clone)]
+pub enum DecodeWarning {
+    NewerSchema { version: u32 },
+    RunningNotReported,
+    RunningPartitionMissing { addr: usize },
+    RunningPartitionAmbiguous { addr: usize },
+    RunningOnUnexpectedPartition { part_type: model::PartitionType },
+}
+
+impl Display for DecodeWarning {
+    fn fmt(&self, out: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            DecodeWarning::NewerSchema { version } => write!(
+                out,
+                "device speaks schema v{}, newer than our v{} (upgrade iota?)",
+                version,
+                model::SCHEMA_VERSION
+            ),
+            DecodeWarning::RunningNotReported => {
+                write!(out, "running partition address not reported")
+            }
+            DecodeWarning::RunningPartitionMissing { addr } => {
+                write!(out, "no partition at running address 0x{:x}", addr)
+            }
+            DecodeWarning::RunningPartitionAmbiguous { addr } => {
+                write!(out, "multiple partitions at running address 0x{:x}", addr)
+            }
+            DecodeWarning::RunningOnUnexpectedPartition { part_type } => {
+                write!(out, "running on unexpected partition type {:?}", part_type)
+            }
+        }
+    }
+}
+
+fn decode_running_part(
+    list: &[&model::Partition],
+    addr: usize,
+) -> Result<RunningPartInfo, DecodeWarning> {
+    let mut candidates = list.iter().filter(|p| p.address == addr);
+
+    let part = match (candidates.next(), candidates.next()) {
+        (None, _) => return Err(DecodeWarning::RunningPartitionMissing { addr }),
+        (Some(_), Some(_)) => return Err(DecodeWarning::RunningPartitionAmbiguous { addr }),
+        (Some(part), None) => part,
+    };
+
+    let running_on_part = match &part.part_type {
+        model::PartitionType::App(model::PartitionAppSubtype::Factory) => RunningOnPart::Factory,
+        model::PartitionType::App(model::PartitionAppSubtype::Ota { id }) => {
+            RunningOnPart::Ota { id: *id }
+        }
+        part_type => {
+            return Err(DecodeWarning::RunningOnUnexpectedPartition {
+                part_type: part_type.clone(),
+            })
+        }
+    };
+
+    Ok(RunningPartInfo {
+        addr,
+        part: running_on_part,
+        ota_state: part.ota_state.clone(),
+    })
+}
+
 pub fn decode_id_message(id: model::IdMessage) -> DecodedIdMessage {
     let mut fmt = String::new();
+    let mut warnings = Vec::new();
+
+    if id.schema > model::SCHEMA_VERSION {
+        warnings.push(DecodeWarning::NewerSchema { version: id.schema });
+    }
 
     {
         let model::AppDesc {
@@ -50,7 +238,7 @@ pub fn decode_id_message(id: model::IdMessage) -> DecodedIdMessage {
             secure_version,
             date,
             time,
-        } = id.software.app_desc;
+        } = &id.software.app_desc;
 
         writeln!(
             fmt,
@@ -60,115 +248,361 @@ pub fn decode_id_message(id: model::IdMessage) -> DecodedIdMessage {
         .unwrap();
     }
 
+    if let Some(model::Hardware {
+        chip_model,
+        chip_revision,
+        mac,
+        flash_size,
+    }) = &id.hardware
+    {
+        writeln!(
+            fmt,
+            "         Hardware: {} rev {}, {} flash, MAC {}",
+            or_unknown(chip_model.as_ref()),
+            or_unknown(chip_revision.as_ref()),
+            or_unknown(flash_size.map(|size| format!("{} MB", size / (1024 * 1024)))),
+            or_unknown(mac.as_ref())
+        )
+        .unwrap();
+    }
+
+    if let Some(model::Runtime {
+        idf_version,
+        uptime_s,
+        reset_reason,
+        free_heap,
+        rssi,
+    }) = &id.runtime
+    {
+        write!(
+            fmt,
+            "          Runtime: ESP-IDF {}, up {}, last reset: {}, {} free heap",
+            or_unknown(idf_version.as_ref()),
+            or_unknown(uptime_s.map(|s| humantime::format_duration(Duration::from_secs(s)))),
+            style_reset_reason(reset_reason.as_deref().unwrap_or("unknown")),
+            or_unknown(free_heap.map(|heap| format!("{} kB", heap / 1024)))
+        )
+        .unwrap();
+        if let Some(rssi) = rssi {
+            write!(fmt, ", RSSI {} dBm", rssi).unwrap();
+        }
+        writeln!(fmt).unwrap();
+    }
+
     let model::Partitions {
         list,
         running: running_addr,
-        boot: boot_addr,
         next_update: next_update_addr,
-        last_invalid: last_invalid_addr,
         ..
     } = &id.software.partitions;
 
     let mut list = list.iter().collect::<Vec<&model::Partition>>();
 
-    let running_addr = running_addr.expect("running partition address not reported!");
-
-    let (running_type, running_ota_state) = list
-        .iter()
-        .filter(|p| p.address == running_addr)
-        .map(|p| (p.part_type, p.ota_state))
-        .single()
-        .expect("multiple running partition");
-
-    let running_on_part = match running_type {
-        model::PartitionType::App(model::PartitionAppSubtype::Factory) => RunningOnPart::Factory,
-        model::PartitionType::App(model::PartitionAppSubtype::Ota { id }) => {
-            RunningOnPart::Ota { id }
-        }
-        model::PartitionType::App(subtype) => {
-            panic!("running on unknown App partition subtype: {:?}", subtype);
-        }
-        part_type => {
-            panic!("running on unknown partition type: {:?}", part_type);
+    let running = match running_addr {
+        None => {
+            warnings.push(DecodeWarning::RunningNotReported);
+            None
         }
+        Some(addr) => match decode_running_part(&list, *addr) {
+            Ok(running) => Some(running),
+            Err(warning) => {
+                warnings.push(warning);
+                None
+            }
+        },
     };
 
     list.sort_by_key(|p| p.address);
 
-    write!(fmt, "       Partitions: ").unwrap();
-    for part in list.iter() {
-        let sym = match part.part_type {
-            model::PartitionType::Data(_) => "~",
-            model::PartitionType::App(model::PartitionAppSubtype::Factory) => "F",
-            model::PartitionType::App(model::PartitionAppSubtype::Test) => "T",
-            model::PartitionType::App(model::PartitionAppSubtype::Ota { .. }) => "O",
-        };
+    let flags_of = |part: &model::Partition| partition_flags(&id.software.partitions, part);
 
-        let style = style(sym).black();
-        let style = match part.ota_state {
-            model::OtaState::Aborted => style.bg(Color::Red),
-            model::OtaState::Invalid => style.bg(Color::Red),
-            model::OtaState::New => style.bg(Color::Yellow),
-            model::OtaState::NotPresent => style.bg(Color::White),
-            model::OtaState::PendingVerify => style.bg(Color::Yellow),
-            model::OtaState::Undefined => style.bg(Color::Cyan),
-            model::OtaState::Valid => style.bg(Color::Green),
-        };
-        write!(fmt, "{}", style).unwrap();
+    write!(fmt, "       Partitions: ").unwrap();
+    if ui::is_plain() {
+        let cells = list
+            .iter()
+            .map(|part| {
+                let flags = flags_of(part);
+                if flags.is_empty() {
+                    format!("{}:{}", part_symbol(part), part.ota_state)
+                } else {
+                    format!("{}:{}[{}]", part_symbol(part), part.ota_state, flags)
+                }
+            })
+            .collect::<Vec<_>>();
+        write!(fmt, "{}", cells.join(" ")).unwrap();
+    } else {
+        for part in list.iter() {
+            write!(
+                fmt,
+                "{}",
+                style_ota_state(&part.ota_state, part_symbol(part))
+            )
+            .unwrap();
+        }
     }
 
     write!(fmt, ", running on ").unwrap();
-    match running_on_part {
-        RunningOnPart::Factory => {
-            write!(fmt, "factory partition (0x{:x})", running_addr)
+    match (&running, running_addr) {
+        (
+            Some(RunningPartInfo {
+                part: RunningOnPart::Factory,
+                addr,
+                ..
+            }),
+            _,
+        ) => {
+            write!(fmt, "factory partition (0x{:x})", addr)
         }
-        RunningOnPart::Ota { id } => {
-            write!(fmt, "ota {} partition (0x{:x})", id, running_addr)
+        (
+            Some(RunningPartInfo {
+                part: RunningOnPart::Ota { id },
+                addr,
+                ..
+            }),
+            _,
+        ) => {
+            write!(fmt, "ota {} partition (0x{:x})", id, addr)
         }
+        (None, Some(addr)) => write!(fmt, "unknown partition (0x{:x})", addr),
+        (None, None) => write!(fmt, "unknown partition"),
     }
     .unwrap();
-    writeln!(fmt).unwrap();
 
-    write!(fmt, "                   ").unwrap();
-    for part in list {
-        let sym = if running_addr == part.address {
-            "R"
-        } else if boot_addr.map_or(false, |addr| addr == part.address) {
-            "B"
-        } else if next_update_addr.map_or(false, |addr| addr == part.address) {
-            "U"
-        } else if last_invalid_addr.map_or(false, |addr| addr == part.address) {
-            "I"
-        } else {
-            " "
-        };
-        write!(fmt, "{}", sym).unwrap();
+    // In plain mode the flags are printed inline, since there are no
+    // single-character glyphs to line them up beneath.
+    if !ui::is_plain() {
+        writeln!(fmt).unwrap();
+
+        write!(fmt, "                   ").unwrap();
+        for part in list {
+            // Only the most important flag fits in the glyph row.
+            let sym = flags_of(part).chars().next().unwrap_or(' ');
+            write!(fmt, "{}", sym).unwrap();
+        }
     }
 
-    let next_update_addr = match *next_update_addr {
-        None => panic!("device reports no free OTA partition for upload"),
-        Some(addr) => addr,
-    };
+    for warning in warnings.iter() {
+        writeln!(fmt).unwrap();
+        write!(fmt, "          Warning: {}", style(warning).yellow()).unwrap();
+    }
+
+    let next_update_addr = *next_update_addr;
 
     DecodedIdMessage {
         msg: id,
         ota_info: RuntimeOtaInfo {
-            running_addr,
-            running_on_part,
-            running_ota_state,
-
+            running,
             next_update_addr,
-
             fmt,
         },
+        warnings,
+        stale: false,
+    }
+}
+
+// Only the most significant unit, e.g. "3h", since this is just to give a
+// rough idea of how stale something is.
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+fn or_unknown<T: Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "unknown".to_owned(), |value| value.to_string())
+}
+
+// Highlight the reset reasons (as named by `esp_reset_reason_t`) which
+// indicate that the device crashed.
+fn style_reset_reason(reason: &str) -> StyledObject<&str> {
+    match reason {
+        "panic" | "int_wdt" | "task_wdt" | "wdt" | "brownout" => style(reason).red(),
+        _ => style(reason),
+    }
+}
+
+fn style_ota_state<D>(state: &model::OtaState, val: D) -> StyledObject<D> {
+    let style = style(val).black();
+    match state {
+        model::OtaState::Aborted => style.bg(Color::Red),
+        model::OtaState::Invalid => style.bg(Color::Red),
+        model::OtaState::New => style.bg(Color::Yellow),
+        model::OtaState::NotPresent => style.bg(Color::White),
+        model::OtaState::PendingVerify => style.bg(Color::Yellow),
+        model::OtaState::Undefined => style.bg(Color::Cyan),
+        model::OtaState::Valid => style.bg(Color::Green),
+        model::OtaState::Unknown(_) => style.bg(Color::Magenta),
+    }
+}
+
+pub fn partition_flags(partitions: &model::Partitions, part: &model::Partition) -> String {
+    let mut flags = String::new();
+    if partitions.running == Some(part.address) {
+        flags.push('R');
+    }
+    if partitions.boot == Some(part.address) {
+        flags.push('B');
+    }
+    if partitions.next_update == Some(part.address) {
+        flags.push('U');
+    }
+    if partitions.last_invalid == Some(part.address) {
+        flags.push('I');
+    }
+    flags
+}
+
+// Sizes in the partition table are nearly always whole kB (and often whole
+// MB), so show them the way they'd be written in a `partitions.csv`.
+pub fn format_size(size: usize) -> String {
+    const K: usize = 1024;
+    const M: usize = 1024 * 1024;
+
+    if size >= M && size % M == 0 {
+        format!("{}M", size / M)
+    } else if size % K == 0 {
+        format!("{}K", size / K)
+    } else {
+        format!("{}", size)
+    }
+}
+
+pub fn format_partition_table(partitions: &model::Partitions) -> String {
+    let mut list = partitions.list.iter().collect::<Vec<&model::Partition>>();
+    list.sort_by_key(|p| p.address);
+
+    let mut fmt = String::new();
+    writeln!(
+        fmt,
+        "{:<10} {:>7}  {:<16} {:<14} {:>4}  {:<14} {:<3}  Flags",
+        "Offset", "Size", "Label", "Type", "Chip", "State", "Enc"
+    )
+    .unwrap();
+
+    for part in list {
+        // Pad before styling, since the escape codes would otherwise count
+        // towards the column width.
+        let state = format!("{:<14}", part.ota_state.to_string());
+        let state = if ui::is_plain() {
+            state
+        } else {
+            style_ota_state(&part.ota_state, state).to_string()
+        };
+
+        let row = format!(
+            "{:<10} {:>7}  {:<16} {:<14} {:>4}  {} {:<3}  {}",
+            format!("0x{:x}", part.address),
+            format_size(part.size),
+            part.label,
+            part.part_type.to_string(),
+            part.flash_chip_id,
+            state,
+            if part.encrypted { "yes" } else { "no" },
+            partition_flags(partitions, part)
+        );
+        writeln!(fmt, "{}", row.trim_end()).unwrap();
+    }
+
+    fmt.truncate(fmt.trim_end().len());
+    fmt
+}
+
+// Lays the partitions out across `width` columns in proportion to their size,
+// with `.` for flash which no partition covers. Every partition gets at least
+// one column, so the map may run slightly over `width` on busy layouts.
+pub fn format_flash_map(
+    partitions: &model::Partitions,
+    flash_size: Option<usize>,
+    width: usize,
+) -> String {
+    let mut list = partitions.list.iter().collect::<Vec<&model::Partition>>();
+    list.sort_by_key(|p| p.address);
+
+    // Devices can report anything, so don't trust the sums not to overflow.
+    let end_of = |part: &model::Partition| part.address.saturating_add(part.size);
+    let end = list.iter().map(|p| end_of(p)).max().unwrap_or(0);
+    let total = flash_size.unwrap_or(0).max(end).max(1);
+    let cols = |size: usize| {
+        let cols = (size as u128 * width as u128 + total as u128 / 2) / total as u128;
+        (cols as usize).max(1)
+    };
+
+    let mut fmt = String::new();
+    let mut pos = 0;
+    for part in list {
+        if part.address > pos {
+            write!(fmt, "{}", ".".repeat(cols(part.address - pos))).unwrap();
+        }
+
+        let n = cols(part.size);
+        let fill = part_symbol(part);
+        // Only label partitions with room for a recognisable prefix.
+        let mut cell: String = if n >= 3 {
+            part.label.chars().take(n).collect()
+        } else {
+            String::new()
+        };
+        while cell.chars().count() < n {
+            cell.push_str(fill);
+        }
+
+        if ui::is_plain() {
+            write!(fmt, "{}", cell).unwrap();
+        } else {
+            write!(fmt, "{}", style_ota_state(&part.ota_state, cell)).unwrap();
+        }
+
+        pos = pos.max(end_of(part));
+    }
+
+    if total > pos {
+        write!(fmt, "{}", ".".repeat(cols(total - pos))).unwrap();
+    }
+
+    writeln!(fmt).unwrap();
+    let end_label = format!("0x{:x} ({})", total, format_size(total));
+    write!(
+        fmt,
+        "0x0{:>width$}",
+        end_label,
+        width = width.saturating_sub(3)
+    )
+    .unwrap();
+
+    fmt
+}
+
+fn part_symbol(part: &model::Partition) -> &'static str {
+    if let model::OtaState::Unknown(_) = part.ota_state {
+        return "?";
+    }
+
+    match part.part_type {
+        model::PartitionType::Data(model::PartitionDataSubtype::Unknown(_)) => "?",
+        model::PartitionType::Data(_) => "~",
+        model::PartitionType::App(model::PartitionAppSubtype::Factory) => "F",
+        model::PartitionType::App(model::PartitionAppSubtype::Test) => "T",
+        model::PartitionType::App(model::PartitionAppSubtype::Ota { .. }) => "O",
+        model::PartitionType::App(model::PartitionAppSubtype::Unknown(_)) => "?",
+        model::PartitionType::Unknown { .. } => "?",
     }
 }
 
 pub fn print_parts_legend() {
-    println!("Legend (parts): (~)Data (F)App:Factory (T)App:Test (O)App:Ota");
-    println!("       (flags): (R)Running (B)Boot (U)NextUpdate (I)LastInvalid");
-    println!(
-        "       (state): {} {} {}/{} {}/{} {}",
+    sayln!("Legend (parts): (~)Data (F)App:Factory (T)App:Test (O)App:Ota (?)Unknown");
+    sayln!("       (flags): (R)Running (B)Boot (U)NextUpdate (I)LastInvalid");
+
+    if ui::is_plain() {
+        sayln!("       (state): <part>:<state>[<flags>], e.g. O:PendingVerify[RB]");
+        return;
+    }
+
+    sayln!(
+        "       (state): {} {} {}/{} {}/{} {} {}",
         style("NotPresent").black().bg(Color::White),
         style("Valid").black().bg(Color::Green),
         style("New").black().bg(Color::Yellow),
@@ -176,5 +610,6 @@ pub fn print_parts_legend() {
         style("Aborted").black().bg(Color::Red),
         style("Invalid").black().bg(Color::Red),
         style("Undefined").black().bg(Color::Cyan),
+        style("Unknown").black().bg(Color::Magenta),
     );
 }
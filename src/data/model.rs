@@ -1,18 +1,37 @@
-use serde::{Deserialize, Serialize};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Metadata, Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Debug, PartialEq, Deserialize)]
+// A device speaks a single schema version across all of its topics, and
+// advertises it in its id message. Firmware which predates versioning doesn't
+// send a version at all, and is treated as speaking version 1.
+pub const LEGACY_SCHEMA_VERSION: u32 = 1;
+pub const SCHEMA_VERSION: u32 = 4;
+
+fn legacy_schema_version() -> u32 {
+    LEGACY_SCHEMA_VERSION
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SchemaProbe {
+    #[serde(default = "legacy_schema_version")]
+    pub schema: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum DeviceState {
     Up,
     Down,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct StatusMessage {
     pub state: DeviceState,
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case", tag = "state")]
 pub enum OtaMessage {
     Start,
@@ -21,7 +40,40 @@ pub enum OtaMessage {
     Fail,
 }
 
-#[derive(Debug, Serialize)]
+// Every command we send is tagged with the schema version it's written in (the
+// device's own, up to ours, so it's never told of a version it doesn't know),
+// and with who sent it under which request id, for devices to acknowledge it by.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Command<T: Serialize> {
+    pub schema: u32,
+    pub request_id: String,
+    pub client_id: String,
+    #[serde(flatten)]
+    pub body: T,
+}
+
+impl<T: Serialize> Command<T> {
+    pub fn new(schema: u32, request_id: String, client_id: String, body: T) -> Self {
+        Command {
+            schema,
+            request_id,
+            client_id,
+            body,
+        }
+    }
+}
+
+// Older firmware restarts on anything sent to `_cmd/restart`, so this only
+// carries the `Command` envelope.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct RestartCommand {}
+
+// Sent on `_cmd/id` (from schema v4) for the device to republish its id
+// message, rather than us having to trust whatever is retained.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct IdCommand {}
+
+#[derive(Debug, Serialize, JsonSchema)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum OtaCommand<'a> {
     Update { url: &'a str, ca_cert: &'a str },
@@ -29,32 +81,67 @@ pub enum OtaCommand<'a> {
     Rollback,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct AppDesc<'a> {
-    pub project_name: &'a str,
-    pub version: &'a str,
+// Not sent by devices, but retained on their `_lock` topic by whoever is
+// performing a mutating operation on them, and cleared once done.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct LockMessage {
+    // Who to ask about it, as `user@host`.
+    pub owner: String,
+    pub client_id: String,
+    pub op: String,
+    // Seconds since the Unix epoch, after which the lock is stale.
+    pub expires: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AckStatus {
+    Accepted,
+    Rejected,
+}
+
+// Sent on `_info/ack` (from schema v3) once a device has decided what to do
+// about a command, echoing its request id.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AckMessage {
+    pub request_id: String,
+    pub status: AckStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AppDesc {
+    pub project_name: String,
+    pub version: String,
     pub secure_version: usize,
-    pub date: &'a str,
-    pub time: &'a str,
+    pub date: String,
+    pub time: String,
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
-#[serde(rename_all = "snake_case", tag = "name", content = "subtype")]
+// Newer ESP-IDF versions keep adding partition types, subtypes and OTA
+// states, so all of these keep whatever we don't recognise in an `Unknown`
+// variant instead of failing to parse the whole message.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "RawPartitionType", into = "RawPartitionType")]
 pub enum PartitionType {
     App(PartitionAppSubtype),
     Data(PartitionDataSubtype),
+    Unknown { name: String, subtype: String },
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
-#[serde(rename_all = "snake_case", tag = "name")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "RawPartitionSubtype", into = "RawPartitionSubtype")]
 pub enum PartitionAppSubtype {
     Factory,
     Test,
     Ota { id: usize },
+    Unknown(String),
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
-#[serde(rename_all = "snake_case", tag = "name")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "RawPartitionSubtype", into = "RawPartitionSubtype")]
 pub enum PartitionDataSubtype {
     Ota,
     Phy,
@@ -65,10 +152,11 @@ pub enum PartitionDataSubtype {
     Esphttpd,
     Fat,
     Spiffs,
+    Unknown(String),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
-#[serde(rename_all = "snake_case")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
 pub enum OtaState {
     NotPresent,
     New,
@@ -77,41 +165,375 @@ pub enum OtaState {
     Invalid,
     Aborted,
     Undefined,
+    Unknown(String),
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Partition<'a> {
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct RawPartitionType {
+    name: String,
+    subtype: RawPartitionSubtype,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(from = "WirePartitionSubtype")]
+struct RawPartitionSubtype {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<usize>,
+}
+
+// Firmware which doesn't know a subtype by name may report it bare, as just
+// its name or its numeric value.
+#[derive(Deserialize, JsonSchema)]
+#[schemars(rename = "PartitionSubtype")]
+#[serde(untagged)]
+enum WirePartitionSubtype {
+    Object {
+        name: String,
+        #[serde(default)]
+        id: Option<usize>,
+    },
+    Name(String),
+    Value(u64),
+}
+
+impl From<WirePartitionSubtype> for RawPartitionSubtype {
+    fn from(wire: WirePartitionSubtype) -> Self {
+        match wire {
+            WirePartitionSubtype::Object { name, id } => RawPartitionSubtype { name, id },
+            WirePartitionSubtype::Name(name) => RawPartitionSubtype { name, id: None },
+            WirePartitionSubtype::Value(value) => RawPartitionSubtype {
+                name: format!("0x{:02x}", value),
+                id: None,
+            },
+        }
+    }
+}
+
+// `schemars` doesn't understand `#[serde(from = "...", into = "...")]`, so
+// we describe these types by their wire representations directly.
+
+impl JsonSchema for RawPartitionSubtype {
+    fn schema_name() -> String {
+        WirePartitionSubtype::schema_name()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        WirePartitionSubtype::json_schema(gen)
+    }
+}
+
+impl JsonSchema for PartitionType {
+    fn schema_name() -> String {
+        "PartitionType".to_owned()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        RawPartitionType::json_schema(gen)
+    }
+}
+
+impl JsonSchema for PartitionAppSubtype {
+    fn schema_name() -> String {
+        "PartitionAppSubtype".to_owned()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        RawPartitionSubtype::json_schema(gen)
+    }
+}
+
+impl JsonSchema for PartitionDataSubtype {
+    fn schema_name() -> String {
+        "PartitionDataSubtype".to_owned()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        RawPartitionSubtype::json_schema(gen)
+    }
+}
+
+impl JsonSchema for OtaState {
+    fn schema_name() -> String {
+        "OtaState".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        let names: Vec<&str> = OTA_STATE_NAMES.iter().map(|(name, _)| *name).collect();
+
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            metadata: Some(Box::new(Metadata {
+                description: Some(format!(
+                    "One of: {} (unrecognised states are tolerated).",
+                    names.join(", ")
+                )),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+impl PartitionType {
+    // The type and subtype names as the device reports them, with the OTA
+    // slot folded into the subtype (e.g. `("app", "ota_1")`).
+    pub fn names(&self) -> (String, String) {
+        let raw = RawPartitionType::from(self.clone());
+        let subtype = match raw.subtype.id {
+            Some(id) => format!("{}_{}", raw.subtype.name, id),
+            None => raw.subtype.name,
+        };
+
+        (raw.name, subtype)
+    }
+}
+
+impl RawPartitionSubtype {
+    fn named(name: &str) -> Self {
+        RawPartitionSubtype {
+            name: name.to_owned(),
+            id: None,
+        }
+    }
+}
+
+impl From<RawPartitionType> for PartitionType {
+    fn from(raw: RawPartitionType) -> Self {
+        match raw.name.as_str() {
+            "app" => PartitionType::App(raw.subtype.into()),
+            "data" => PartitionType::Data(raw.subtype.into()),
+            _ => PartitionType::Unknown {
+                name: raw.name,
+                subtype: raw.subtype.name,
+            },
+        }
+    }
+}
+
+impl From<PartitionType> for RawPartitionType {
+    fn from(part_type: PartitionType) -> Self {
+        let (name, subtype) = match part_type {
+            PartitionType::App(subtype) => ("app".to_owned(), subtype.into()),
+            PartitionType::Data(subtype) => ("data".to_owned(), subtype.into()),
+            PartitionType::Unknown { name, subtype } => {
+                (name, RawPartitionSubtype::named(&subtype))
+            }
+        };
+
+        RawPartitionType { name, subtype }
+    }
+}
+
+impl From<RawPartitionSubtype> for PartitionAppSubtype {
+    fn from(raw: RawPartitionSubtype) -> Self {
+        match (raw.name.as_str(), raw.id) {
+            ("factory", _) => PartitionAppSubtype::Factory,
+            ("test", _) => PartitionAppSubtype::Test,
+            ("ota", Some(id)) => PartitionAppSubtype::Ota { id },
+            _ => PartitionAppSubtype::Unknown(raw.name),
+        }
+    }
+}
+
+impl From<PartitionAppSubtype> for RawPartitionSubtype {
+    fn from(subtype: PartitionAppSubtype) -> Self {
+        match subtype {
+            PartitionAppSubtype::Factory => RawPartitionSubtype::named("factory"),
+            PartitionAppSubtype::Test => RawPartitionSubtype::named("test"),
+            PartitionAppSubtype::Ota { id } => RawPartitionSubtype {
+                name: "ota".to_owned(),
+                id: Some(id),
+            },
+            PartitionAppSubtype::Unknown(name) => RawPartitionSubtype::named(&name),
+        }
+    }
+}
+
+const DATA_SUBTYPE_NAMES: [(&str, PartitionDataSubtype); 9] = [
+    ("ota", PartitionDataSubtype::Ota),
+    ("phy", PartitionDataSubtype::Phy),
+    ("nvs", PartitionDataSubtype::Nvs),
+    ("core_dump", PartitionDataSubtype::CoreDump),
+    ("nvs_keys", PartitionDataSubtype::NvsKeys),
+    ("efuse_em", PartitionDataSubtype::EfuseEm),
+    ("esphttpd", PartitionDataSubtype::Esphttpd),
+    ("fat", PartitionDataSubtype::Fat),
+    ("spiffs", PartitionDataSubtype::Spiffs),
+];
+
+impl From<RawPartitionSubtype> for PartitionDataSubtype {
+    fn from(raw: RawPartitionSubtype) -> Self {
+        DATA_SUBTYPE_NAMES
+            .iter()
+            .find(|(name, _)| *name == raw.name)
+            .map_or(PartitionDataSubtype::Unknown(raw.name), |(_, subtype)| {
+                subtype.clone()
+            })
+    }
+}
+
+impl From<PartitionDataSubtype> for RawPartitionSubtype {
+    fn from(subtype: PartitionDataSubtype) -> Self {
+        match subtype {
+            PartitionDataSubtype::Unknown(name) => RawPartitionSubtype::named(&name),
+            subtype => RawPartitionSubtype::named(
+                DATA_SUBTYPE_NAMES
+                    .iter()
+                    .find(|(_, s)| *s == subtype)
+                    .map(|(name, _)| *name)
+                    .unwrap(),
+            ),
+        }
+    }
+}
+
+const OTA_STATE_NAMES: [(&str, OtaState); 7] = [
+    ("not_present", OtaState::NotPresent),
+    ("new", OtaState::New),
+    ("pending_verify", OtaState::PendingVerify),
+    ("valid", OtaState::Valid),
+    ("invalid", OtaState::Invalid),
+    ("aborted", OtaState::Aborted),
+    ("undefined", OtaState::Undefined),
+];
+
+impl From<String> for OtaState {
+    fn from(name: String) -> Self {
+        OTA_STATE_NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map_or(OtaState::Unknown(name), |(_, state)| state.clone())
+    }
+}
+
+impl From<OtaState> for String {
+    fn from(state: OtaState) -> Self {
+        match state {
+            OtaState::Unknown(name) => name,
+            state => OTA_STATE_NAMES
+                .iter()
+                .find(|(_, s)| *s == state)
+                .map(|(name, _)| (*name).to_owned())
+                .unwrap(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Partition {
     pub flash_chip_id: usize,
     #[serde(rename = "type")]
     pub part_type: PartitionType,
     pub address: usize,
     pub size: usize,
-    pub label: &'a str,
+    pub label: String,
     pub encrypted: bool,
     pub ota_state: OtaState,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Partitions<'a> {
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Partitions {
     pub boot: Option<usize>,
     pub running: Option<usize>,
     pub last_invalid: Option<usize>,
     pub next_update: Option<usize>,
     pub is_rollback_possible: bool,
 
-    #[serde(borrow)]
-    pub list: Vec<Partition<'a>>,
+    pub list: Vec<Partition>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Software<'a> {
-    #[serde(borrow)]
-    pub app_desc: AppDesc<'a>,
-    pub partitions: Partitions<'a>,
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Software {
+    pub app_desc: AppDesc,
+    pub partitions: Partitions,
+}
+
+// Firmware fills in what it can find out, so any of these may be missing.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Hardware {
+    #[serde(default)]
+    pub chip_model: Option<String>,
+    #[serde(default)]
+    pub chip_revision: Option<usize>,
+    #[serde(default)]
+    pub mac: Option<String>,
+    #[serde(default)]
+    pub flash_size: Option<usize>,
+}
+
+// As for `Hardware`, any of these may be missing.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Runtime {
+    #[serde(default)]
+    pub idf_version: Option<String>,
+    #[serde(default)]
+    pub uptime_s: Option<u64>,
+    #[serde(default)]
+    pub reset_reason: Option<String>,
+    #[serde(default)]
+    pub free_heap: Option<usize>,
+    // Only reported while connected over Wi-Fi.
+    #[serde(default)]
+    pub rssi: Option<i32>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct IdMessage {
+    #[serde(default = "legacy_schema_version")]
+    pub schema: u32,
+
+    pub software: Software,
+
+    // Older firmware doesn't report these at all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hardware: Option<Hardware>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<Runtime>,
+}
+
+// Firmware which predates versioning sent `hardware` and `runtime` however it
+// liked, and firmware newer than us may have changed them, so from either we
+// keep them where they fit and drop them where they don't.
 #[derive(Debug, Deserialize)]
-pub struct IdMessage<'a> {
-    #[serde(borrow)]
-    pub software: Software<'a>,
+pub struct LenientIdMessage {
+    #[serde(default = "legacy_schema_version")]
+    pub schema: u32,
+
+    pub software: Software,
+
+    #[serde(default, deserialize_with = "if_it_fits")]
+    pub hardware: Option<Hardware>,
+    #[serde(default, deserialize_with = "if_it_fits")]
+    pub runtime: Option<Runtime>,
+}
+
+impl From<LenientIdMessage> for IdMessage {
+    fn from(id: LenientIdMessage) -> Self {
+        IdMessage {
+            schema: id.schema,
+            software: id.software,
+            hardware: id.hardware,
+            runtime: id.runtime,
+        }
+    }
+}
+
+fn if_it_fits<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum MaybeFits<T> {
+        Fits(Option<T>),
+        Doesnt(serde::de::IgnoredAny),
+    }
+
+    Ok(match MaybeFits::deserialize(deserializer)? {
+        MaybeFits::Fits(value) => value,
+        MaybeFits::Doesnt(_) => None,
+    })
 }
@@ -0,0 +1,418 @@
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use super::{decode, model};
+
+// A partition as laid out by a local ESP-IDF `partitions.csv` or the
+// `partitions.bin` compiled from it. Flags aren't compared, so aren't kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpectedPartition {
+    pub label: String,
+    pub part_type: model::PartitionType,
+    pub address: usize,
+    pub size: usize,
+}
+
+#[derive(Debug)]
+pub enum TableError {
+    Io(io::Error),
+    Csv { line: usize, reason: String },
+    Bin { offset: usize, reason: String },
+}
+
+impl fmt::Display for TableError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TableError::Io(e) => write!(fmt, "could not read partition table: {}", e),
+            TableError::Csv { line, reason } => write!(fmt, "line {}: {}", line, reason),
+            TableError::Bin { offset, reason } => write!(fmt, "offset 0x{:x}: {}", offset, reason),
+        }
+    }
+}
+
+impl std::error::Error for TableError {}
+
+// These match the defaults of ESP-IDF's `gen_esp32part.py`, which we need to
+// reproduce the offsets it assigns to rows which leave them blank.
+const FIRST_PARTITION_OFFSET: usize = 0x9000;
+const APP_ALIGNMENT: usize = 0x10000;
+const DATA_ALIGNMENT: usize = 0x1000;
+
+const TYPE_APP: u8 = 0x00;
+const TYPE_DATA: u8 = 0x01;
+
+const APP_SUBTYPE_FACTORY: u8 = 0x00;
+const APP_SUBTYPE_OTA_MIN: u8 = 0x10;
+const APP_SUBTYPE_OTA_MAX: u8 = 0x1f;
+const APP_SUBTYPE_TEST: u8 = 0x20;
+
+// The ESP-IDF names, which differ slightly from the ones devices report.
+const DATA_SUBTYPES: [(&str, u8, model::PartitionDataSubtype); 9] = [
+    ("ota", 0x00, model::PartitionDataSubtype::Ota),
+    ("phy", 0x01, model::PartitionDataSubtype::Phy),
+    ("nvs", 0x02, model::PartitionDataSubtype::Nvs),
+    ("coredump", 0x03, model::PartitionDataSubtype::CoreDump),
+    ("nvs_keys", 0x04, model::PartitionDataSubtype::NvsKeys),
+    ("efuse", 0x05, model::PartitionDataSubtype::EfuseEm),
+    ("esphttpd", 0x80, model::PartitionDataSubtype::Esphttpd),
+    ("fat", 0x81, model::PartitionDataSubtype::Fat),
+    ("spiffs", 0x82, model::PartitionDataSubtype::Spiffs),
+];
+
+// Newer ESP-IDF versions know this one too, but devices report it by name as
+// one we have no variant for.
+const DATA_SUBTYPE_LITTLEFS: u8 = 0x83;
+
+const BIN_ENTRY_SIZE: usize = 32;
+const BIN_ENTRY_MAGIC: [u8; 2] = [0xaa, 0x50];
+const BIN_MD5_MAGIC: [u8; 2] = [0xeb, 0xeb];
+
+fn part_type_from_ids(part_type: u8, subtype: u8) -> model::PartitionType {
+    match (part_type, subtype) {
+        (TYPE_APP, APP_SUBTYPE_FACTORY) => {
+            model::PartitionType::App(model::PartitionAppSubtype::Factory)
+        }
+        (TYPE_APP, APP_SUBTYPE_TEST) => model::PartitionType::App(model::PartitionAppSubtype::Test),
+        (TYPE_APP, APP_SUBTYPE_OTA_MIN..=APP_SUBTYPE_OTA_MAX) => {
+            model::PartitionType::App(model::PartitionAppSubtype::Ota {
+                id: (subtype - APP_SUBTYPE_OTA_MIN) as usize,
+            })
+        }
+        (TYPE_APP, _) => model::PartitionType::App(model::PartitionAppSubtype::Unknown(format!(
+            "0x{:02x}",
+            subtype
+        ))),
+        (TYPE_DATA, DATA_SUBTYPE_LITTLEFS) => {
+            model::PartitionType::Data(model::PartitionDataSubtype::Unknown("littlefs".to_owned()))
+        }
+        (TYPE_DATA, _) => model::PartitionType::Data(
+            DATA_SUBTYPES
+                .iter()
+                .find(|(_, id, _)| *id == subtype)
+                .map_or(
+                    model::PartitionDataSubtype::Unknown(format!("0x{:02x}", subtype)),
+                    |(_, _, subtype)| subtype.clone(),
+                ),
+        ),
+        _ => model::PartitionType::Unknown {
+            name: format!("0x{:02x}", part_type),
+            subtype: format!("0x{:02x}", subtype),
+        },
+    }
+}
+
+fn parse_number(s: &str) -> Option<usize> {
+    let (digits, multiplier) = match s.chars().last()? {
+        'k' | 'K' => (&s[..s.len() - 1], 1024),
+        'm' | 'M' => (&s[..s.len() - 1], 1024 * 1024),
+        _ => (s, 1),
+    };
+
+    let value = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+
+    value.checked_mul(multiplier)
+}
+
+fn parse_csv_type(part_type: &str, subtype: &str) -> Result<model::PartitionType, String> {
+    let type_id = match part_type {
+        "app" => TYPE_APP,
+        "data" => TYPE_DATA,
+        other => parse_number(other)
+            .and_then(|id| u8::try_from(id).ok())
+            .ok_or_else(|| format!("unknown partition type `{}`", other))?,
+    };
+
+    let subtype_id = match (type_id, subtype) {
+        (TYPE_APP, "factory") => APP_SUBTYPE_FACTORY,
+        (TYPE_APP, "test") => APP_SUBTYPE_TEST,
+        (TYPE_APP, ota) if ota.starts_with("ota_") => ota["ota_".len()..]
+            .parse::<u8>()
+            .ok()
+            .filter(|id| *id <= APP_SUBTYPE_OTA_MAX - APP_SUBTYPE_OTA_MIN)
+            .map(|id| APP_SUBTYPE_OTA_MIN + id)
+            .ok_or_else(|| format!("invalid OTA subtype `{}`", ota))?,
+        (TYPE_DATA, name) if DATA_SUBTYPES.iter().any(|(n, _, _)| *n == name) => DATA_SUBTYPES
+            .iter()
+            .find(|(n, _, _)| *n == name)
+            .map(|(_, id, _)| *id)
+            .unwrap(),
+        // Named subtypes we don't have an id for (e.g. `littlefs`) are kept
+        // by name, which is how devices report them too.
+        (TYPE_DATA, name) if parse_number(name).is_none() => {
+            return Ok(model::PartitionType::Data(
+                model::PartitionDataSubtype::Unknown(name.to_owned()),
+            ));
+        }
+        (_, other) => parse_number(other)
+            .and_then(|id| u8::try_from(id).ok())
+            .ok_or_else(|| format!("unknown partition subtype `{}`", other))?,
+    };
+
+    Ok(part_type_from_ids(type_id, subtype_id))
+}
+
+pub fn parse_csv(text: &str) -> Result<Vec<ExpectedPartition>, TableError> {
+    let mut parts = Vec::new();
+    let mut next_offset = FIRST_PARTITION_OFFSET;
+
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let csv_error = |reason: String| TableError::Csv {
+            line: idx + 1,
+            reason,
+        };
+
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if fields.len() < 5 {
+            return Err(csv_error(format!(
+                "expected at least 5 fields, found {}",
+                fields.len()
+            )));
+        }
+
+        let part_type = parse_csv_type(fields[1], fields[2]).map_err(csv_error)?;
+
+        let alignment = match part_type {
+            model::PartitionType::App(_) => APP_ALIGNMENT,
+            _ => DATA_ALIGNMENT,
+        };
+
+        let address = match fields[3] {
+            "" => next_offset
+                .div_ceil(alignment)
+                .checked_mul(alignment)
+                .ok_or_else(|| csv_error("offset out of range".to_owned()))?,
+            offset => parse_number(offset)
+                .ok_or_else(|| csv_error(format!("invalid offset `{}`", offset)))?,
+        };
+
+        let size = parse_number(fields[4])
+            .ok_or_else(|| csv_error(format!("invalid size `{}`", fields[4])))?;
+
+        next_offset = address
+            .checked_add(size)
+            .ok_or_else(|| csv_error("partition ends out of range".to_owned()))?;
+        parts.push(ExpectedPartition {
+            label: fields[0].to_owned(),
+            part_type,
+            address,
+            size,
+        });
+    }
+
+    Ok(parts)
+}
+
+pub fn parse_bin(data: &[u8]) -> Result<Vec<ExpectedPartition>, TableError> {
+    let mut parts = Vec::new();
+
+    for (idx, entry) in data.chunks(BIN_ENTRY_SIZE).enumerate() {
+        let offset = idx * BIN_ENTRY_SIZE;
+        let bin_error = |reason: &str| TableError::Bin {
+            offset,
+            reason: reason.to_owned(),
+        };
+
+        // The table is terminated by an optional MD5 entry and then erased
+        // (all `0xff`) flash.
+        if entry.starts_with(&BIN_MD5_MAGIC) || entry.iter().all(|b| *b == 0xff) {
+            break;
+        }
+
+        if entry.len() < BIN_ENTRY_SIZE {
+            return Err(bin_error("truncated entry"));
+        }
+
+        if !entry.starts_with(&BIN_ENTRY_MAGIC) {
+            return Err(bin_error("bad entry magic"));
+        }
+
+        let u32_at = |at: usize| {
+            u32::from_le_bytes([entry[at], entry[at + 1], entry[at + 2], entry[at + 3]])
+        };
+
+        let label = &entry[12..28];
+        let label = &label[..label.iter().position(|b| *b == 0).unwrap_or(label.len())];
+
+        parts.push(ExpectedPartition {
+            label: String::from_utf8_lossy(label).into_owned(),
+            part_type: part_type_from_ids(entry[2], entry[3]),
+            address: u32_at(4) as usize,
+            size: u32_at(8) as usize,
+        });
+    }
+
+    Ok(parts)
+}
+
+// Accepts either format, telling them apart by the binary entry magic.
+pub fn load(path: &Path) -> Result<Vec<ExpectedPartition>, TableError> {
+    let data = std::fs::read(path).map_err(TableError::Io)?;
+
+    if data.starts_with(&BIN_ENTRY_MAGIC) {
+        parse_bin(&data)
+    } else {
+        let text = String::from_utf8(data).map_err(|_| TableError::Csv {
+            line: 0,
+            reason: "file is neither a partition table binary nor UTF-8 text".to_owned(),
+        })?;
+        parse_csv(&text)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum Difference {
+    Missing {
+        label: String,
+        address: usize,
+        size: usize,
+    },
+    Extra {
+        label: String,
+        address: usize,
+        size: usize,
+    },
+    Moved {
+        label: String,
+        expected: usize,
+        reported: usize,
+    },
+    Resized {
+        label: String,
+        expected: usize,
+        reported: usize,
+    },
+    Retyped {
+        label: String,
+        expected: String,
+        reported: String,
+    },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Missing {
+                label,
+                address,
+                size,
+            } => write!(
+                fmt,
+                "missing  {} ({} at 0x{:x})",
+                label,
+                decode::format_size(*size),
+                address
+            ),
+            Difference::Extra {
+                label,
+                address,
+                size,
+            } => write!(
+                fmt,
+                "extra    {} ({} at 0x{:x})",
+                label,
+                decode::format_size(*size),
+                address
+            ),
+            Difference::Moved {
+                label,
+                expected,
+                reported,
+            } => write!(
+                fmt,
+                "moved    {}: expected at 0x{:x}, device has it at 0x{:x}",
+                label, expected, reported
+            ),
+            Difference::Resized {
+                label,
+                expected,
+                reported,
+            } => write!(
+                fmt,
+                "resized  {}: expected {}, device has {}",
+                label,
+                decode::format_size(*expected),
+                decode::format_size(*reported)
+            ),
+            Difference::Retyped {
+                label,
+                expected,
+                reported,
+            } => write!(
+                fmt,
+                "retyped  {}: expected {}, device has {}",
+                label, expected, reported
+            ),
+        }
+    }
+}
+
+// Partitions are matched up by label, since that is what the firmware uses to
+// find them.
+pub fn compare(expected: &[ExpectedPartition], reported: &[model::Partition]) -> Vec<Difference> {
+    let mut diffs = Vec::new();
+
+    for exp in expected {
+        let rep = match reported.iter().find(|rep| rep.label == exp.label) {
+            None => {
+                diffs.push(Difference::Missing {
+                    label: exp.label.clone(),
+                    address: exp.address,
+                    size: exp.size,
+                });
+                continue;
+            }
+            Some(rep) => rep,
+        };
+
+        if rep.address != exp.address {
+            diffs.push(Difference::Moved {
+                label: exp.label.clone(),
+                expected: exp.address,
+                reported: rep.address,
+            });
+        }
+
+        if rep.size != exp.size {
+            diffs.push(Difference::Resized {
+                label: exp.label.clone(),
+                expected: exp.size,
+                reported: rep.size,
+            });
+        }
+
+        if rep.part_type != exp.part_type {
+            diffs.push(Difference::Retyped {
+                label: exp.label.clone(),
+                expected: exp.part_type.to_string(),
+                reported: rep.part_type.to_string(),
+            });
+        }
+    }
+
+    for rep in reported {
+        if !expected.iter().any(|exp| exp.label == rep.label) {
+            diffs.push(Difference::Extra {
+                label: rep.label.clone(),
+                address: rep.address,
+                size: rep.size,
+            });
+        }
+    }
+
+    diffs
+}
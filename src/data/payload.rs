@@ -0,0 +1,58 @@
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::io;
+
+// MQTT 3.1.1 has no way to attach a content type to a message, so we have to
+// guess it from the payload itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentType {
+    Text,
+    Cbor,
+    Binary,
+}
+
+// The CBOR "self-described CBOR" tag (55799), which devices may prefix.
+const CBOR_SELF_DESCRIBE_TAG: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+pub fn sniff(payload: &[u8]) -> ContentType {
+    // All of our messages are maps, which CBOR encodes with major type 5.
+    if payload.starts_with(&CBOR_SELF_DESCRIBE_TAG)
+        || payload.first().is_some_and(|b| (0xa0..=0xbf).contains(b))
+    {
+        ContentType::Cbor
+    } else if std::str::from_utf8(payload).is_ok() {
+        ContentType::Text
+    } else {
+        ContentType::Binary
+    }
+}
+
+#[derive(Debug)]
+pub enum PayloadError {
+    Json(serde_json::Error),
+    Cbor(ciborium::de::Error<io::Error>),
+    Binary,
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadError::Json(e) => write!(fmt, "JSON parse error: {}", e),
+            PayloadError::Cbor(e) => write!(fmt, "CBOR parse error: {}", e),
+            PayloadError::Binary => write!(fmt, "payload is neither JSON nor CBOR"),
+        }
+    }
+}
+
+impl std::error::Error for PayloadError {}
+
+pub fn parse<T: DeserializeOwned>(
+    payload: &[u8],
+    content_type: ContentType,
+) -> Result<T, PayloadError> {
+    match content_type {
+        ContentType::Text => serde_json::from_slice(payload).map_err(PayloadError::Json),
+        ContentType::Cbor => ciborium::de::from_reader(payload).map_err(PayloadError::Cbor),
+        ContentType::Binary => Err(PayloadError::Binary),
+    }
+}
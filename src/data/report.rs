@@ -0,0 +1,49 @@
+use serde::Serialize;
+
+use super::model;
+
+#[derive(Debug, Serialize)]
+pub struct DeviceReport<'a> {
+    pub name: &'a str,
+    pub state: Option<model::DeviceState>,
+    pub schema: Option<u32>,
+    pub app_desc: Option<&'a model::AppDesc>,
+    pub partitions: Option<&'a model::Partitions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hardware: Option<&'a model::Hardware>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<&'a model::Runtime>,
+    // Whether any of this came from the local cache rather than the broker.
+    pub cached: bool,
+    // Whether the broker had been retaining it for long enough that the device
+    // may well have moved on since.
+    pub stale: bool,
+    // Seconds since the UNIX epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<u64>,
+    // Why the last message from the device couldn't be used, if it couldn't.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<&'a str>,
+}
+
+impl<'a> DeviceReport<'a> {
+    pub fn new(
+        name: &'a str,
+        state: Option<model::DeviceState>,
+        id: Option<&'a model::IdMessage>,
+    ) -> Self {
+        DeviceReport {
+            name,
+            state,
+            schema: id.map(|id| id.schema),
+            app_desc: id.map(|id| &id.software.app_desc),
+            partitions: id.map(|id| &id.software.partitions),
+            hardware: id.and_then(|id| id.hardware.as_ref()),
+            runtime: id.and_then(|id| id.runtime.as_ref()),
+            cached: false,
+            stale: false,
+            last_seen: None,
+            warning: None,
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::model;
+
+#[derive(Debug, Serialize)]
+pub struct Document {
+    #[serde(skip)]
+    pub name: &'static str,
+    // Relative to the device's namespace, e.g. `hoek/iot/<device>/`.
+    pub topic: &'static str,
+    pub schema: RootSchema,
+    pub example: Value,
+}
+
+fn document(
+    name: &'static str,
+    topic: &'static str,
+    mut schema: RootSchema,
+    example: impl Serialize,
+) -> Document {
+    let metadata = schema.schema.metadata();
+    metadata.description = Some(format!(
+        "Payload of `{}` in iota device protocol schema v{}.",
+        topic,
+        model::SCHEMA_VERSION
+    ));
+
+    Document {
+        name,
+        topic,
+        schema,
+        example: serde_json::to_value(example).expect("Could not build JSON"),
+    }
+}
+
+pub fn documents() -> Vec<Document> {
+    vec![
+        document(
+            "id_message",
+            "_info/id",
+            schema_for!(model::IdMessage),
+            example_id_message(),
+        ),
+        document(
+            "status_message",
+            "_info/status",
+            schema_for!(model::StatusMessage),
+            model::StatusMessage {
+                state: model::DeviceState::Up,
+            },
+        ),
+        document(
+            "ota_message",
+            "_info/ota",
+            schema_for!(model::OtaMessage),
+            model::OtaMessage::InProgress { rx_kb: 512 },
+        ),
+        document(
+            "ota_command",
+            "_cmd/ota",
+            schema_for!(model::Command<model::OtaCommand>),
+            example_command(model::OtaCommand::Update {
+                url: "https://example.com/firmware.bin",
+                ca_cert: "-----BEGIN CERTIFICATE-----\n...\n-----END CERTIFICATE-----\n",
+            }),
+        ),
+        document(
+            "restart_command",
+            "_cmd/restart",
+            schema_for!(model::Command<model::RestartCommand>),
+            example_command(model::RestartCommand {}),
+        ),
+        document(
+            "id_command",
+            "_cmd/id",
+            schema_for!(model::Command<model::IdCommand>),
+            example_command(model::IdCommand {}),
+        ),
+        document(
+            "ack_message",
+            "_info/ack",
+            schema_for!(model::AckMessage),
+            model::AckMessage {
+                request_id: EXAMPLE_REQUEST_ID.to_owned(),
+                status: model::AckStatus::Rejected,
+                reason: Some("no partition to update".to_owned()),
+            },
+        ),
+        document(
+            "lock_message",
+            "_lock",
+            schema_for!(model::LockMessage),
+            model::LockMessage {
+                owner: "alice@workstation".to_owned(),
+                client_id: EXAMPLE_CLIENT_ID.to_owned(),
+                op: "ota".to_owned(),
+                expires: 1614600000,
+            },
+        ),
+    ]
+}
+
+const EXAMPLE_CLIENT_ID: &str = "iota-1a2b3c4d-4242";
+const EXAMPLE_REQUEST_ID: &str = "iota-1a2b3c4d-4242-1";
+
+fn example_command<T: Serialize>(body: T) -> model::Command<T> {
+    model::Command::new(
+        model::SCHEMA_VERSION,
+        EXAMPLE_REQUEST_ID.to_owned(),
+        EXAMPLE_CLIENT_ID.to_owned(),
+        body,
+    )
+}
+
+fn partition(
+    part_type: model::PartitionType,
+    address: usize,
+    size: usize,
+    label: &str,
+    ota_state: model::OtaState,
+) -> model::Partition {
+    model::Partition {
+        flash_chip_id: 0,
+        part_type,
+        address,
+        size,
+        label: label.to_owned(),
+        encrypted: false,
+        ota_state,
+    }
+}
+
+// A device with the stock ESP-IDF two-slot OTA layout, running an update
+// which hasn't been validated yet.
+pub fn example_id_message() -> model::IdMessage {
+    use model::PartitionType::{App as AppPart, Data as DataPart};
+    use model::{OtaState, PartitionAppSubtype as App, PartitionDataSubtype as Data};
+
+    model::IdMessage {
+        schema: model::SCHEMA_VERSION,
+        software: model::Software {
+            app_desc: model::AppDesc {
+                project_name: "example".to_owned(),
+                version: "1.2.0".to_owned(),
+                secure_version: 0,
+                date: "Mar  1 2021".to_owned(),
+                time: "12:00:00".to_owned(),
+            },
+            partitions: model::Partitions {
+                boot: Some(0x110000),
+                running: Some(0x110000),
+                last_invalid: None,
+                next_update: Some(0x10000),
+                is_rollback_possible: true,
+                list: vec![
+                    partition(
+                        DataPart(Data::Nvs),
+                        0x9000,
+                        0x4000,
+                        "nvs",
+                        OtaState::NotPresent,
+                    ),
+                    partition(
+                        DataPart(Data::Ota),
+                        0xd000,
+                        0x2000,
+                        "otadata",
+                        OtaState::NotPresent,
+                    ),
+                    partition(
+                        DataPart(Data::Phy),
+                        0xf000,
+                        0x1000,
+                        "phy_init",
+                        OtaState::NotPresent,
+                    ),
+                    partition(
+                        AppPart(App::Ota { id: 0 }),
+                        0x10000,
+                        0x100000,
+                        "ota_0",
+                        OtaState::Valid,
+                    ),
+                    partition(
+                        AppPart(App::Ota { id: 1 }),
+                        0x110000,
+                        0x100000,
+                        "ota_1",
+                        OtaState::PendingVerify,
+                    ),
+                ],
+            },
+        },
+        hardware: Some(model::Hardware {
+            chip_model: Some("esp32".to_owned()),
+            chip_revision: Some(3),
+            mac: Some("24:0a:c4:00:00:01".to_owned()),
+            flash_size: Some(4 * 1024 * 1024),
+        }),
+        runtime: Some(model::Runtime {
+            idf_version: Some("v4.4".to_owned()),
+            uptime_s: Some(3600),
+            reset_reason: Some("sw".to_owned()),
+            free_heap: Some(150 * 1024),
+            rssi: Some(-60),
+        }),
+    }
+}
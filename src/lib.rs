@@ -0,0 +1,8 @@
+#[macro_use]
+pub mod ui;
+
+pub mod cache;
+pub mod data;
+pub mod net;
+pub mod op;
+pub mod sim;
@@ -1,28 +1,102 @@
-pub mod data;
-pub mod net;
-pub mod op;
-
+use iota::{data, net, op, sayln, ui};
+use regex::Regex;
 use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 
+#[derive(StructOpt, Debug)]
+#[structopt(name = "iota")]
+pub struct Opts {
+    /// Output format for `list`, `status`, `cmd`, `topics`, `sub` and `ping`: human, json, or jsonl
+    #[structopt(long, global = true, default_value = "human")]
+    output: ui::OutputFormat,
+
+    /// When to use colour and cursor movement: auto, always, or never
+    #[structopt(long, global = true, default_value = "auto")]
+    color: ui::ColorChoice,
+
+    /// Record everything sent to and received from the broker to this JSONL file
+    #[structopt(long, global = true)]
+    record: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: CommandRoot,
+}
+
 #[derive(StructOpt, Debug)]
 pub enum CommandRoot {
     Status(SubcommandStatus),
     List(SubcommandList),
+    Partitions(SubcommandPartitions),
     Ota(SubcommandOta),
     Restart(SubcommandRestart),
     Validate(SubcommandValidate),
     Rollback(SubcommandRollback),
+    Cmd(SubcommandCmd),
+    Topics(SubcommandTopics),
+    Sub(SubcommandSub),
+    Pub(SubcommandPub),
+    Forget(SubcommandForget),
+    Ping(SubcommandPing),
+    Schema(SubcommandSchema),
+    Replay(SubcommandReplay),
 }
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "list")]
-pub struct SubcommandList {}
+pub struct SubcommandList {
+    /// Print the discovered devices once and exit, instead of live updating
+    #[structopt(long)]
+    once: bool,
+    /// How long to collect retained messages for before printing (e.g. "500ms", "3s")
+    #[structopt(long, requires = "once", parse(try_from_str = humantime::parse_duration))]
+    settle: Option<Duration>,
+    /// Only show devices with names matching this regex
+    #[structopt(long)]
+    name: Option<Regex>,
+    /// Only show devices in this state: up or down
+    #[structopt(long)]
+    state: Option<data::model::DeviceState>,
+    /// Only show devices running firmware with this project name
+    #[structopt(long)]
+    project: Option<String>,
+    /// Only show devices running firmware with this version
+    #[structopt(long)]
+    version: Option<String>,
+    /// Only show devices whose running partition is in this OTA state (e.g. pending_verify)
+    #[structopt(long)]
+    ota_state: Option<data::model::OtaState>,
+    /// Sort devices by: name, version, date (of build), or last-seen (live or cached, not retained)
+    #[structopt(long, default_value = "name")]
+    sort: op::list::SortKey,
+    /// Reverse the sort order
+    #[structopt(long)]
+    reverse: bool,
+}
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "status")]
 pub struct SubcommandStatus {
     device: String,
+    /// Show the last-known state from the local cache, without contacting the broker
+    #[structopt(long)]
+    offline: bool,
+}
+
+/// Re-run a command recorded with `--record`, against the capture instead of the broker
+#[derive(StructOpt, Debug)]
+#[structopt(name = "replay")]
+pub struct SubcommandReplay {
+    file: PathBuf,
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "partitions")]
+pub struct SubcommandPartitions {
+    device: String,
+    /// Compare against the layout in this partitions.csv (or compiled partitions.bin)
+    #[structopt(long)]
+    expect: Option<PathBuf>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -30,38 +104,205 @@ pub struct SubcommandStatus {
 pub struct SubcommandOta {
     device: String,
     file: PathBuf,
+    /// Take the device's lock even if someone else is holding it
+    #[structopt(long)]
+    force: bool,
 }
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "restart")]
 pub struct SubcommandRestart {
     device: String,
+    /// Take the device's lock even if someone else is holding it
+    #[structopt(long)]
+    force: bool,
 }
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "validate")]
 pub struct SubcommandValidate {
     device: String,
+    /// Take the device's lock even if someone else is holding it
+    #[structopt(long)]
+    force: bool,
 }
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "rollback")]
 pub struct SubcommandRollback {
     device: String,
+    /// Take the device's lock even if someone else is holding it
+    #[structopt(long)]
+    force: bool,
+}
+
+/// Publish a command to one of the device's `_cmd/*` topics
+#[derive(StructOpt, Debug)]
+#[structopt(name = "cmd")]
+pub struct SubcommandCmd {
+    device: String,
+    /// The command, published to `_cmd/<name>`
+    #[structopt(parse(try_from_str = op::cmd::parse_name))]
+    name: String,
+    /// JSON payload, or `@<file>` to send a file's contents as is (default: `{}`)
+    payload: Option<String>,
+    /// QoS to publish with: 0, 1 or 2
+    #[structopt(long, default_value = "2", parse(try_from_str = op::cmd::parse_qos))]
+    qos: rumqttc::QoS,
+    /// Publish as a retained message, for the device to pick up when it next connects
+    #[structopt(long)]
+    retain: bool,
+    /// Wait for a response on the device's `_info/<name>` topic, and print it
+    #[structopt(long, parse(try_from_str = op::cmd::parse_name))]
+    response: Option<String>,
+    /// How long to wait for the response (e.g. "500ms", "30s")
+    #[structopt(long, requires = "response", parse(try_from_str = humantime::parse_duration))]
+    timeout: Option<Duration>,
+}
+
+/// List every topic holding a retained message, with its size, age and payload
+#[derive(StructOpt, Debug)]
+#[structopt(name = "topics")]
+pub struct SubcommandTopics {
+    /// Only list the topics of this device
+    #[structopt(long)]
+    device: Option<String>,
+    /// How long the broker must go quiet for before all retained messages are taken to have arrived (e.g. "500ms", "3s")
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    settle: Option<Duration>,
+}
+
+/// Print every message published to topics matching an MQTT filter
+#[derive(StructOpt, Debug)]
+#[structopt(name = "sub")]
+pub struct SubcommandSub {
+    /// Topic filter, e.g. `hoek/iot/+/_info/status` or `hoek/iot/#`
+    #[structopt(parse(try_from_str = op::topics::parse_filter))]
+    pattern: String,
+}
+
+/// Publish a message to any topic
+#[derive(StructOpt, Debug)]
+#[structopt(name = "pub")]
+pub struct SubcommandPub {
+    #[structopt(parse(try_from_str = op::cmd::parse_name))]
+    topic: String,
+    /// Payload, or `@<file>` to send a file's contents
+    payload: String,
+    /// QoS to publish with: 0, 1 or 2
+    #[structopt(long, default_value = "2", parse(try_from_str = op::cmd::parse_qos))]
+    qos: rumqttc::QoS,
+    /// Publish as a retained message (an empty one clears what the topic retains)
+    #[structopt(long)]
+    retain: bool,
+}
+
+/// Clear a decommissioned device's retained messages from the broker, and its cached state
+#[derive(StructOpt, Debug)]
+#[structopt(name = "forget")]
+pub struct SubcommandForget {
+    #[structopt(parse(try_from_str = op::cmd::parse_name))]
+    device: String,
+    /// How long the broker must go quiet for before all the device's retained messages are taken to have arrived (e.g. "500ms", "3s")
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    settle: Option<Duration>,
+    /// Don't ask for confirmation first
+    #[structopt(long)]
+    yes: bool,
 }
 
-fn command_list(_: SubcommandList) {
-    op::list::perform();
+/// Check that a device answers, and how quickly, by repeatedly asking for its id
+#[derive(StructOpt, Debug)]
+#[structopt(name = "ping")]
+pub struct SubcommandPing {
+    device: String,
+    /// How many times to ping the device
+    #[structopt(short = "c", long)]
+    count: Option<usize>,
+    /// How long to wait between pings (e.g. "500ms", "2s")
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    interval: Option<Duration>,
+    /// How long to wait for each answer
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    timeout: Option<Duration>,
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "schema")]
+pub enum SubcommandSchema {
+    /// Export JSON Schema documents and example payloads for the device protocol
+    Export {
+        /// Write one file per document into this directory, instead of printing them all
+        #[structopt(long)]
+        out_dir: Option<PathBuf>,
+    },
+}
+
+fn command_list(cmd: SubcommandList) {
+    let mode = if cmd.once {
+        op::list::Mode::Once {
+            settle: cmd.settle.unwrap_or(op::list::DEFAULT_SETTLE),
+        }
+    } else {
+        if ui::output_format() == ui::OutputFormat::Json {
+            sayln!("Live updates cannot be printed as a single JSON document, use `--once` or `--output jsonl`.");
+            std::process::exit(-1);
+        }
+
+        op::list::Mode::Live
+    };
+
+    op::list::perform(
+        mode,
+        op::list::Filter {
+            name: cmd.name,
+            state: cmd.state,
+            project: cmd.project,
+            version: cmd.version,
+            ota_state: cmd.ota_state,
+        },
+        op::list::Order {
+            key: cmd.sort,
+            reverse: cmd.reverse,
+        },
+    );
 }
 
 fn command_status(cmd: SubcommandStatus) {
-    op::perform_op(op::status::Operation {}, &cmd.device);
+    if cmd.offline {
+        op::status::perform_offline(&cmd.device);
+    }
+
+    op::perform_op(op::status::Operation {}, &cmd.device, false);
+}
+
+fn command_partitions(cmd: SubcommandPartitions) {
+    let expect = cmd.expect.map(|path| {
+        data::partition_table::load(&path).unwrap_or_else(|e| {
+            sayln!(
+                "Could not load expected partition table '{}': {}",
+                path.display(),
+                e
+            );
+            std::process::exit(-1);
+        })
+    });
+
+    op::perform_op(op::partitions::Operation { expect }, &cmd.device, false);
 }
 
 fn command_ota(cmd: SubcommandOta) {
     loop {
-        let url = net::https::upload_tmp_file(cmd.file.clone());
-        let ca_cert = net::https::download_root_ca_cert_pem(&url);
+        let (url, ca_cert) = match net::capture::replayed_upload() {
+            Some(upload) => upload,
+            None => {
+                op::interrupt::enter(&cmd.device, op::interrupt::Phase::Uploading);
+                let url = net::https::upload_tmp_file(cmd.file.clone());
+                let ca_cert = net::https::download_root_ca_cert_pem(&url);
+                net::capture::record_upload(&url, &ca_cert);
+                (url, ca_cert)
+            }
+        };
 
         println!("-------------------");
         println!("Starting OTA Update");
@@ -73,6 +314,7 @@ fn command_ota(cmd: SubcommandOta) {
                 ca_cert: &ca_cert,
             },
             &cmd.device,
+            cmd.force,
         ) {
             break;
         }
@@ -80,7 +322,7 @@ fn command_ota(cmd: SubcommandOta) {
 }
 
 fn command_restart(cmd: SubcommandRestart) {
-    op::perform_op(op::restart::Operation {}, &cmd.device);
+    op::perform_op(op::restart::Operation {}, &cmd.device, cmd.force);
 }
 
 fn command_validate(cmd: SubcommandValidate) {
@@ -89,6 +331,7 @@ fn command_validate(cmd: SubcommandValidate) {
             mark: op::mark::Mark::Validate,
         },
         &cmd.device,
+        cmd.force,
     );
 }
 
@@ -98,16 +341,133 @@ fn command_rollback(cmd: SubcommandRollback) {
             mark: op::mark::Mark::Rollback,
         },
         &cmd.device,
+        cmd.force,
+    );
+}
+
+fn command_cmd(cmd: SubcommandCmd) {
+    let payload = op::cmd::load_payload(cmd.payload.as_deref()).unwrap_or_else(|e| {
+        sayln!("Invalid payload: {}", e);
+        std::process::exit(-1);
+    });
+
+    op::cmd::perform(
+        &cmd.device,
+        op::cmd::Request {
+            name: cmd.name,
+            payload,
+            qos: cmd.qos,
+            retain: cmd.retain,
+            response: cmd.response,
+            timeout: cmd.timeout.unwrap_or(op::cmd::DEFAULT_TIMEOUT),
+        },
     );
 }
 
+fn command_topics(cmd: SubcommandTopics) {
+    op::topics::list(
+        cmd.device.as_deref(),
+        cmd.settle.unwrap_or(op::list::DEFAULT_SETTLE),
+    );
+}
+
+fn command_sub(cmd: SubcommandSub) {
+    if ui::output_format() == ui::OutputFormat::Json {
+        sayln!("Messages cannot be printed as a single JSON document, use `--output jsonl`.");
+        std::process::exit(-1);
+    }
+
+    op::topics::subscribe(&cmd.pattern);
+}
+
+fn command_pub(cmd: SubcommandPub) {
+    let payload = op::topics::load_payload(&cmd.payload).unwrap_or_else(|e| {
+        sayln!("Invalid payload: {}", e);
+        std::process::exit(-1);
+    });
+
+    op::topics::publish(&cmd.topic, &payload, cmd.qos, cmd.retain);
+}
+
+fn command_forget(cmd: SubcommandForget) {
+    op::forget::perform(
+        &cmd.device,
+        cmd.settle.unwrap_or(op::list::DEFAULT_SETTLE),
+        cmd.yes,
+    );
+}
+
+fn command_ping(cmd: SubcommandPing) {
+    op::ping::perform(
+        &cmd.device,
+        op::ping::Options {
+            count: cmd.count.unwrap_or(op::ping::DEFAULT_COUNT),
+            interval: cmd.interval.unwrap_or(op::ping::DEFAULT_INTERVAL),
+            timeout: cmd.timeout.unwrap_or(op::ping::DEFAULT_TIMEOUT),
+        },
+    );
+}
+
+fn command_schema(cmd: SubcommandSchema) {
+    match cmd {
+        SubcommandSchema::Export { out_dir } => op::schema::export(out_dir.as_deref()),
+    }
+}
+
+// A replayed command runs with the options it was recorded with, so that it
+// takes the same code path (e.g. for `--output`).
+fn load_replay(cmd: SubcommandReplay) -> Opts {
+    let argv = net::capture::replay(&cmd.file).unwrap_or_else(|e| {
+        sayln!("Could not load capture '{}': {}", cmd.file.display(), e);
+        std::process::exit(-1);
+    });
+
+    let opts = Opts::from_iter_safe(&argv).unwrap_or_else(|e| {
+        sayln!("Could not parse recorded command line: {}", e.message);
+        std::process::exit(-1);
+    });
+
+    if let CommandRoot::Replay(_) = opts.command {
+        sayln!("Capture is of a replay, which cannot be replayed!");
+        std::process::exit(-1);
+    }
+
+    opts
+}
+
 fn main() {
-    match CommandRoot::from_args() {
+    let mut opts = Opts::from_args();
+
+    match opts.command {
+        CommandRoot::Replay(cmd) => opts = load_replay(cmd),
+        _ => {
+            if let Some(path) = &opts.record {
+                net::capture::record(path, std::env::args().collect()).unwrap_or_else(|e| {
+                    sayln!("Could not record to '{}': {}", path.display(), e);
+                    std::process::exit(-1);
+                });
+            }
+        }
+    }
+
+    ui::init(opts.output, opts.color);
+    op::interrupt::install();
+
+    match opts.command {
         CommandRoot::List(cmd) => command_list(cmd),
         CommandRoot::Status(cmd) => command_status(cmd),
+        CommandRoot::Partitions(cmd) => command_partitions(cmd),
         CommandRoot::Ota(cmd) => command_ota(cmd),
         CommandRoot::Restart(cmd) => command_restart(cmd),
         CommandRoot::Validate(cmd) => command_validate(cmd),
         CommandRoot::Rollback(cmd) => command_rollback(cmd),
+        CommandRoot::Cmd(cmd) => command_cmd(cmd),
+        CommandRoot::Topics(cmd) => command_topics(cmd),
+        CommandRoot::Sub(cmd) => command_sub(cmd),
+        CommandRoot::Pub(cmd) => command_pub(cmd),
+        CommandRoot::Forget(cmd) => command_forget(cmd),
+        CommandRoot::Ping(cmd) => command_ping(cmd),
+        CommandRoot::Schema(cmd) => command_schema(cmd),
+        CommandRoot::Replay(_) => unreachable!(),
     }
 }
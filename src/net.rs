@@ -1,4 +1,6 @@
+pub mod capture;
 pub mod https;
 pub mod mqtt;
+pub mod transport;
 
 mod keys;
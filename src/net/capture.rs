@@ -0,0 +1,502 @@
+// Recording of broker sessions to a JSONL capture, and replaying them in place
+// of the broker, so that a misbehaving operation can be re-run offline along
+// the exact same decision path.
+
+use once_cell::sync::OnceCell;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{RecvError, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::mqtt::{self, MqttPacket};
+use super::transport::{self, Publisher, Transport};
+use crate::data::payload;
+
+pub const CAPTURE_VERSION: u32 = 1;
+
+// Payloads are kept readable where they are text, which is most of them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Text(String),
+    Hex(String),
+}
+
+impl Payload {
+    pub fn new(payload: &[u8]) -> Self {
+        match std::str::from_utf8(payload) {
+            Ok(text) => Payload::Text(text.to_owned()),
+            Err(_) => Payload::Hex(payload.iter().map(|b| format!("{:02x}", b)).collect()),
+        }
+    }
+
+    fn to_bytes(&self) -> Option<Vec<u8>> {
+        match self {
+            Payload::Text(text) => Some(text.as_bytes().to_vec()),
+            Payload::Hex(hex) => (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Event {
+    // Always the first line, recording how to re-run the command.
+    Header {
+        version: u32,
+        argv: Vec<String>,
+        // Which we replay as, since it ends up in what we publish.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_id: Option<String>,
+    },
+    Subscribe {
+        t_ms: u64,
+        topic: String,
+    },
+    Publish {
+        t_ms: u64,
+        topic: String,
+        #[serde(flatten)]
+        payload: Payload,
+        retain: bool,
+    },
+    Recv {
+        t_ms: u64,
+        topic: String,
+        #[serde(flatten)]
+        payload: Payload,
+        // Missing from captures made before it was recorded.
+        #[serde(default)]
+        retain: bool,
+    },
+    // The firmware upload made by `iota ota`, which can't be repeated offline.
+    Upload {
+        url: String,
+        ca_cert: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    Io(io::Error),
+    Parse {
+        line: usize,
+        error: serde_json::Error,
+    },
+    NoHeader,
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::Io(e) => write!(fmt, "{}", e),
+            CaptureError::Parse { line, error } => write!(fmt, "line {}: {}", line, error),
+            CaptureError::NoHeader => write!(fmt, "capture does not start with a header"),
+            CaptureError::UnsupportedVersion(version) => {
+                write!(fmt, "unsupported capture version {}", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+enum Session {
+    Record {
+        started: Instant,
+        out: Mutex<LineWriter<File>>,
+    },
+    Replay {
+        events: Mutex<VecDeque<Event>>,
+    },
+}
+
+static SESSION: OnceCell<Session> = OnceCell::new();
+
+fn set_session(session: Session) {
+    if SESSION.set(session).is_err() {
+        panic!("capture session already initialized");
+    }
+}
+
+pub fn is_recording() -> bool {
+    matches!(SESSION.get(), Some(Session::Record { .. }))
+}
+
+pub fn is_replaying() -> bool {
+    matches!(SESSION.get(), Some(Session::Replay { .. }))
+}
+
+// Starts recording everything which goes over the broker to `path`.
+pub fn record(path: &Path, argv: Vec<String>) -> Result<(), CaptureError> {
+    let file = File::create(path).map_err(CaptureError::Io)?;
+
+    set_session(Session::Record {
+        started: Instant::now(),
+        // Flushed line by line, since we usually leave via `process::exit`.
+        out: Mutex::new(LineWriter::new(file)),
+    });
+
+    write_event(&Event::Header {
+        version: CAPTURE_VERSION,
+        argv,
+        client_id: Some(mqtt::client_id().to_owned()),
+    });
+
+    Ok(())
+}
+
+fn write_event(event: &Event) {
+    if let Some(Session::Record { out, .. }) = SESSION.get() {
+        let line = serde_json::to_string(event).expect("Could not build JSON");
+        writeln!(out.lock().unwrap(), "{}", line).expect("Could not write capture");
+    }
+}
+
+fn elapsed_ms() -> u64 {
+    match SESSION.get() {
+        Some(Session::Record { started, .. }) => started.elapsed().as_millis() as u64,
+        _ => 0,
+    }
+}
+
+// Loads a capture to replay in place of the broker, returning the command line
+// it was recorded with.
+pub fn replay(path: &Path) -> Result<Vec<String>, CaptureError> {
+    let file = File::open(path).map_err(CaptureError::Io)?;
+
+    let mut events = VecDeque::new();
+    for (idx, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(CaptureError::Io)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let event = serde_json::from_str(&line).map_err(|error| CaptureError::Parse {
+            line: idx + 1,
+            error,
+        })?;
+        events.push_back(event);
+    }
+
+    let argv = match events.pop_front() {
+        Some(Event::Header {
+            version,
+            argv,
+            client_id,
+        }) => {
+            if version > CAPTURE_VERSION {
+                return Err(CaptureError::UnsupportedVersion(version));
+            }
+            if let Some(client_id) = client_id {
+                mqtt::set_client_id(client_id);
+            }
+            argv
+        }
+        _ => return Err(CaptureError::NoHeader),
+    };
+
+    set_session(Session::Replay {
+        events: Mutex::new(events),
+    });
+
+    Ok(argv)
+}
+
+pub fn record_upload(url: &str, ca_cert: &str) {
+    write_event(&Event::Upload {
+        url: url.to_owned(),
+        ca_cert: ca_cert.to_owned(),
+    });
+}
+
+// The upload recorded in the capture being replayed, in place of a new one.
+pub fn replayed_upload() -> Option<(String, String)> {
+    let events = match SESSION.get() {
+        Some(Session::Replay { events }) => events,
+        _ => return None,
+    };
+
+    let mut events = events.lock().unwrap();
+    let idx = events
+        .iter()
+        .position(|event| matches!(event, Event::Upload { .. }))?;
+
+    match events.remove(idx) {
+        Some(Event::Upload { url, ca_cert }) => Some((url, ca_cert)),
+        _ => unreachable!(),
+    }
+}
+
+fn record_publish(topic: &str, payload: &[u8], retain: bool) {
+    write_event(&Event::Publish {
+        t_ms: elapsed_ms(),
+        topic: topic.to_owned(),
+        payload: Payload::new(payload),
+        retain,
+    });
+}
+
+// Requeued messages are kept here rather than handed back to `inner`, so that
+// they're only recorded once, as they will be replayed.
+pub struct RecordingTransport<T: Transport> {
+    inner: T,
+    pending: VecDeque<MqttPacket>,
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    pub fn new(inner: T) -> Self {
+        RecordingTransport {
+            inner,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn record_recv(&self, msg: &MqttPacket) {
+        write_event(&Event::Recv {
+            t_ms: elapsed_ms(),
+            topic: msg.topic.clone(),
+            payload: Payload::new(&msg.payload),
+            retain: msg.retain,
+        });
+    }
+}
+
+impl<T: Transport> Transport for RecordingTransport<T> {
+    fn subscribe(&mut self, topic: &str) {
+        write_event(&Event::Subscribe {
+            t_ms: elapsed_ms(),
+            topic: topic.to_owned(),
+        });
+        self.inner.subscribe(topic);
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) {
+        record_publish(topic, payload, retain);
+        self.inner.publish(topic, payload, retain);
+    }
+
+    fn publish_qos(&mut self, topic: &str, payload: &[u8], qos: QoS, retain: bool) {
+        record_publish(topic, payload, retain);
+        self.inner.publish_qos(topic, payload, qos, retain);
+    }
+
+    fn recv(&mut self) -> Result<MqttPacket, RecvError> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(msg);
+        }
+
+        let msg = self.inner.recv()?;
+        self.record_recv(&msg);
+        Ok(msg)
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<MqttPacket, RecvTimeoutError> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(msg);
+        }
+
+        let msg = self.inner.recv_timeout(timeout)?;
+        self.record_recv(&msg);
+        Ok(msg)
+    }
+
+    fn requeue(&mut self, msgs: Vec<MqttPacket>) {
+        transport::requeue(&mut self.pending, msgs);
+    }
+
+    fn publisher(&self) -> Option<Box<dyn Publisher>> {
+        let inner = self.inner.publisher()?;
+        Some(Box::new(RecordingPublisher { inner }))
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+    }
+}
+
+struct RecordingPublisher {
+    inner: Box<dyn Publisher>,
+}
+
+impl Publisher for RecordingPublisher {
+    fn publish(&self, topic: &str, payload: &[u8], retain: bool) {
+        record_publish(topic, payload, retain);
+        self.inner.publish(topic, payload, retain);
+    }
+
+    fn disconnect(&self) {
+        self.inner.disconnect();
+    }
+}
+
+// Fields of what we publish which depend on who and when rather than on what
+// happened, e.g. in device locks, so can't be expected to match on replay.
+const RUN_SPECIFIC_FIELDS: &[&str] = &["owner", "expires"];
+
+fn same_payload(recorded: &Payload, published: &[u8]) -> bool {
+    let recorded = match recorded.to_bytes() {
+        None => return false,
+        Some(recorded) => recorded,
+    };
+
+    let without_run_specific = |payload: &[u8]| -> Option<serde_json::Value> {
+        let mut value: serde_json::Value = serde_json::from_slice(payload).ok()?;
+        let fields = value.as_object_mut()?;
+        for field in RUN_SPECIFIC_FIELDS {
+            fields.remove(*field);
+        }
+        Some(value)
+    };
+
+    recorded == published
+        || without_run_specific(&recorded)
+            .is_some_and(|recorded| Some(recorded) == without_run_specific(published))
+}
+
+// Plays back the received messages of the capture loaded by `replay`, and
+// checks that we publish the same things at the same points as we did then.
+pub struct ReplayTransport {
+    pending: VecDeque<MqttPacket>,
+}
+
+impl ReplayTransport {
+    pub fn new() -> Self {
+        ReplayTransport {
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn with_events<R>(f: impl FnOnce(&mut VecDeque<Event>) -> R) -> R {
+        match SESSION.get() {
+            Some(Session::Replay { events }) => f(&mut events.lock().unwrap()),
+            _ => panic!("no capture loaded for replay"),
+        }
+    }
+
+    fn next_recv() -> Option<MqttPacket> {
+        Self::with_events(|events| loop {
+            match events.pop_front()? {
+                Event::Recv {
+                    topic,
+                    payload,
+                    retain,
+                    ..
+                } => {
+                    let payload = payload.to_bytes().unwrap_or_default();
+                    return Some(MqttPacket {
+                        topic,
+                        content_type: payload::sniff(&payload),
+                        payload,
+                        retain,
+                    });
+                }
+                Event::Publish { topic, .. } => {
+                    sayln!(
+                        "Replay diverged: capture published to '{}' at this point, we didn't",
+                        topic
+                    );
+                }
+                _ => {}
+            }
+        })
+    }
+}
+
+impl Default for ReplayTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn subscribe(&mut self, topic: &str) {
+        Self::with_events(|events| {
+            if let Some(Event::Subscribe { topic: t, .. }) = events.front() {
+                if t == topic {
+                    events.pop_front();
+                }
+            }
+        });
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8], _: bool) {
+        // `None` if we matched the capture, otherwise what it published instead.
+        let diverged = Self::with_events(|events| {
+            while let Some(Event::Subscribe { .. }) = events.front() {
+                events.pop_front();
+            }
+
+            match events.front() {
+                Some(Event::Publish {
+                    topic: t,
+                    payload: p,
+                    ..
+                }) if t == topic && same_payload(p, payload) => {
+                    events.pop_front();
+                    None
+                }
+                Some(Event::Publish { topic: t, .. }) => Some(Some(t.clone())),
+                _ => Some(None),
+            }
+        });
+
+        match diverged {
+            None => {}
+            Some(Some(expected)) => sayln!(
+                "Replay diverged: published to '{}', capture published to '{}' at this point",
+                topic,
+                expected
+            ),
+            Some(None) => sayln!(
+                "Replay diverged: published to '{}', capture didn't publish at this point",
+                topic
+            ),
+        }
+    }
+
+    fn recv(&mut self) -> Result<MqttPacket, RecvError> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(msg);
+        }
+
+        match Self::next_recv() {
+            Some(msg) => Ok(msg),
+            None => {
+                sayln!("End of capture reached.");
+                std::process::exit(-1);
+            }
+        }
+    }
+
+    // If the capture has us doing anything else (or ending) before the next
+    // message was received, that's where the recorded session timed out.
+    fn recv_timeout(&mut self, _: Duration) -> Result<MqttPacket, RecvTimeoutError> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(msg);
+        }
+
+        let received =
+            Self::with_events(|events| matches!(events.front(), Some(Event::Recv { .. })));
+
+        if !received {
+            return Err(RecvTimeoutError::Timeout);
+        }
+
+        Ok(Self::next_recv().unwrap())
+    }
+
+    fn requeue(&mut self, msgs: Vec<MqttPacket>) {
+        transport::requeue(&mut self.pending, msgs);
+    }
+}
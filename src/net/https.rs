@@ -1,4 +1,3 @@
-use console::Term;
 use once_cell::sync::Lazy;
 use reqwest::StatusCode;
 use rustls::ClientSession;
@@ -16,6 +15,7 @@ use url::Url;
 use webpki;
 
 use super::keys;
+use crate::ui;
 
 static REMOTE_KEY: Lazy<String> = Lazy::new(|| keys::read_secret("iota.remote.key"));
 struct CertificateExtractorServerCertVerifier<SCV: ServerCertVerifier> {
@@ -58,8 +58,6 @@ impl<SCV: ServerCertVerifier> ServerCertVerifier for CertificateExtractorServerC
 }
 
 pub fn download_root_ca_cert_pem(url: &str) -> String {
-    let term = Term::stdout();
-
     let url_parts = Url::parse(url).expect("could not parse url");
     let host_str = url_parts.host_str().expect("no host str");
     let path = &url_parts[url::Position::BeforePath..];
@@ -80,7 +78,7 @@ pub fn download_root_ca_cert_pem(url: &str) -> String {
     let mut sock = TcpStream::connect(host_str.to_owned() + ":443").unwrap();
     let mut tls = rustls::Stream::new(&mut conn, &mut sock);
 
-    println!("Connecting to file server...");
+    sayln!("Connecting to file server...");
 
     write!(
         tls,
@@ -95,8 +93,8 @@ pub fn download_root_ca_cert_pem(url: &str) -> String {
     )
     .unwrap();
 
-    term.clear_last_lines(1).unwrap();
-    println!("Downloading certificate...");
+    ui::clear_last_lines(1);
+    sayln!("Downloading certificate...");
 
     let mut buff = [0];
     tls.read_exact(&mut buff).unwrap();
@@ -106,7 +104,7 @@ pub fn download_root_ca_cert_pem(url: &str) -> String {
         .last()
         .expect("No certificates returned by server!");
 
-    term.clear_last_lines(1).unwrap();
+    ui::clear_last_lines(1);
 
     pem::encode(&pem::Pem {
         tag: "CERTIFICATE".to_string(),
@@ -123,14 +121,12 @@ fn gen_tmp_id() -> String {
             .to_string()
 }
 
-pub(crate) fn upload_tmp_file(file: std::path::PathBuf) -> String {
-    let term = Term::stdout();
-
+pub fn upload_tmp_file(file: std::path::PathBuf) -> String {
     let file = File::open(file).expect("invalid file");
     let id = gen_tmp_id();
     let client = reqwest::blocking::Client::new();
 
-    println!("Authorizing file upload...");
+    sayln!("Authorizing file upload...");
 
     let put_url = {
         let mut resp = client
@@ -150,8 +146,8 @@ pub(crate) fn upload_tmp_file(file: std::path::PathBuf) -> String {
         String::from_utf8(buf).unwrap()
     };
 
-    term.clear_last_lines(1).unwrap();
-    println!("Uploading file...");
+    ui::clear_last_lines(1);
+    sayln!("Uploading file...");
 
     {
         let resp = client.put(put_url).body(file).send().unwrap();
@@ -162,8 +158,8 @@ pub(crate) fn upload_tmp_file(file: std::path::PathBuf) -> String {
         }
     }
 
-    term.clear_last_lines(1).unwrap();
-    println!("Authorizing access to uploaded file...");
+    ui::clear_last_lines(1);
+    sayln!("Authorizing access to uploaded file...");
 
     let get_url = {
         let mut resp = client
@@ -183,7 +179,7 @@ pub(crate) fn upload_tmp_file(file: std::path::PathBuf) -> String {
         String::from_utf8(buf).unwrap()
     };
 
-    term.clear_last_lines(1).unwrap();
+    ui::clear_last_lines(1);
 
     get_url
 }
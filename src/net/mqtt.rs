@@ -1,18 +1,47 @@
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use rumqttc::{Client, Event, MqttOptions, Outgoing, Packet, TlsConfiguration, Transport};
 use rustls::internal::pemfile;
+use serde::de::DeserializeOwned;
 use single::Single;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
-use std::thread;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use url::Url;
 
 use super::keys;
+use crate::data::payload::{self, ContentType, PayloadError};
 
 const HOSTNAME: &str = "storagebox.local";
 const PORT: u16 = 8883;
+const PLAIN_PORT: u16 = 1883;
+
+// Set to e.g. `mqtt://localhost:1883` to talk to a local test broker (such as
+// one hosting `iota-sim` devices) instead, without TLS or credentials.
+const BROKER_ENV: &str = "IOTA_BROKER";
 
 pub struct MqttPacket {
     pub topic: String,
-    pub payload: String,
+    pub payload: Vec<u8>,
+    pub content_type: ContentType,
+    // Whether the broker sent this from its store rather than as published,
+    // i.e. it was retained from before we subscribed.
+    pub retain: bool,
+}
+
+impl MqttPacket {
+    pub fn parse<T: DeserializeOwned>(&self) -> Result<T, PayloadError> {
+        payload::parse(&self.payload, self.content_type)
+    }
+
+    // For displaying payloads which are just log messages and the like.
+    pub fn payload_text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.payload)
+    }
 }
 
 const MQTT_USERNAME: &str = "iota";
@@ -22,7 +51,7 @@ const CA_CERT_PEM: &str = include_str!("../../res/ca_cert.pem");
 const CERT_CHAIN_PEM: &str = include_str!("../../res/chain.pem");
 static PRIVKEY_PEM: Lazy<String> = Lazy::new(|| keys::read_secret("iota.privkey.pem"));
 
-pub fn connect() -> (Client, Receiver<MqttPacket>) {
+fn tls_configuration() -> TlsConfiguration {
     let cert_chain = pemfile::certs(&mut CERT_CHAIN_PEM.to_owned().as_bytes())
         .expect("Couldn't parse `CERT_CHAIN_PEM`");
     let privkey_list = pemfile::rsa_private_keys(&mut PRIVKEY_PEM.to_owned().as_bytes())
@@ -42,16 +71,108 @@ pub fn connect() -> (Client, Receiver<MqttPacket>) {
         .set_single_client_cert(cert_chain, privkey)
         .expect("Couldn't set client auth info");
 
-    // TODO hash pc hostname for name
-    let mut opts = MqttOptions::new("iota", HOSTNAME, PORT);
-    opts.set_credentials(MQTT_USERNAME, &MQTT_PASSWORD);
+    TlsConfiguration::from(tls_cfg)
+}
+
+pub fn broker_options(client_id: &str) -> MqttOptions {
+    let broker = match std::env::var(BROKER_ENV) {
+        Err(_) => None,
+        Ok(broker) => Some(
+            Url::parse(&broker).unwrap_or_else(|e| panic!("Couldn't parse ${}: {}", BROKER_ENV, e)),
+        ),
+    };
+
+    let (host, port, tls) = match &broker {
+        None => (HOSTNAME, PORT, true),
+        Some(url) => {
+            let tls = match url.scheme() {
+                "mqtt" => false,
+                "mqtts" => true,
+                scheme => panic!("Unsupported scheme in ${}: {}", BROKER_ENV, scheme),
+            };
+            let host = url
+                .host_str()
+                .unwrap_or_else(|| panic!("No host in ${}", BROKER_ENV));
+            let port = url.port().unwrap_or(if tls { PORT } else { PLAIN_PORT });
+
+            (host, port, tls)
+        }
+    };
+
+    let mut opts = MqttOptions::new(client_id, host, port);
     opts.set_keep_alive(5);
-    opts.set_transport(Transport::tls_with_config(TlsConfiguration::from(tls_cfg)));
 
+    if tls {
+        opts.set_credentials(MQTT_USERNAME, &MQTT_PASSWORD);
+        opts.set_transport(Transport::tls_with_config(tls_configuration()));
+    }
+
+    opts
+}
+
+pub fn hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+static CLIENT_ID: OnceCell<String> = OnceCell::new();
+
+// Unique to this run, since a broker drops a client when another connects with
+// the same id, and identifies us to other users (e.g. in device locks).
+pub fn client_id() -> &'static str {
+    CLIENT_ID.get_or_init(|| {
+        let mut hasher = DefaultHasher::new();
+        hostname().hash(&mut hasher);
+        format!("iota-{:08x}-{}", hasher.finish() as u32, std::process::id())
+    })
+}
+
+// For replaying a session recorded under another id.
+pub fn set_client_id(client_id: String) {
+    if CLIENT_ID.set(client_id).is_err() {
+        panic!("client id already in use");
+    }
+}
+
+// The thread running a connection, and the publishes it still has in flight.
+pub struct Connection {
+    thread: JoinHandle<()>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Connection {
+    // Disconnects once the broker has acknowledged everything we published,
+    // returning when that has gone out (or after `timeout`, regardless).
+    pub fn close(self, client: &mut Client, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let wait_for = |done: &dyn Fn() -> bool| {
+            while !done() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        wait_for(&|| self.in_flight.load(Ordering::SeqCst) == 0);
+        if client.disconnect().is_ok() {
+            wait_for(&|| self.thread.is_finished());
+        }
+    }
+}
+
+pub fn connect() -> (Client, Receiver<MqttPacket>, Connection) {
+    start(broker_options(client_id()))
+}
+
+pub fn connect_with(opts: MqttOptions) -> (Client, Receiver<MqttPacket>) {
+    let (client, rx, _) = start(opts);
+    (client, rx)
+}
+
+fn start(opts: MqttOptions) -> (Client, Receiver<MqttPacket>, Connection) {
     let (tx, rx): (Sender<MqttPacket>, Receiver<MqttPacket>) = mpsc::channel();
+    let in_flight = Arc::new(AtomicUsize::new(0));
 
     let (client, mut connection) = Client::new(opts, 10);
-    thread::spawn(move || {
+    let counter = in_flight.clone();
+    let thread = thread::spawn(move || {
         for evt in connection.iter() {
             match evt {
                 Err(_) => {
@@ -59,12 +180,21 @@ pub fn connect() -> (Client, Receiver<MqttPacket>) {
                     break;
                 }
                 Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                // Only QoS 0 publishes go without an acknowledgement.
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) if pkid != 0 => {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(Event::Incoming(Packet::PubAck(_)))
+                | Ok(Event::Incoming(Packet::PubComp(_))) => {
+                    let _ = counter
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+                }
                 Ok(Event::Incoming(Packet::Publish(msg))) => {
                     let r = tx.send(MqttPacket {
                         topic: msg.topic,
-                        payload: std::str::from_utf8(&msg.payload)
-                            .expect("mqtt payload was not valid UTF-8")
-                            .to_owned(),
+                        content_type: payload::sniff(&msg.payload),
+                        payload: msg.payload.to_vec(),
+                        retain: msg.retain,
                     });
 
                     if r.is_err() {
@@ -76,5 +206,5 @@ pub fn connect() -> (Client, Receiver<MqttPacket>) {
         }
     });
 
-    (client, rx)
+    (client, rx, Connection { thread, in_flight })
 }
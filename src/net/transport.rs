@@ -0,0 +1,312 @@
+use rumqttc::QoS;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::mpsc::{Receiver, RecvError, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::capture::{self, RecordingTransport, ReplayTransport};
+use super::mqtt::{self, MqttPacket};
+use crate::data::payload;
+
+// Everything the ops need from the broker, so that they can be run against
+// something other than a real one.
+pub trait Transport {
+    fn subscribe(&mut self, topic: &str);
+
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool);
+
+    // Only a real broker makes anything of the QoS, so the rest deliver
+    // everything as `publish` does (i.e. exactly once).
+    fn publish_qos(&mut self, topic: &str, payload: &[u8], _qos: QoS, retain: bool) {
+        self.publish(topic, payload, retain);
+    }
+
+    // These fail once the broker connection is gone for good.
+    fn recv(&mut self) -> Result<MqttPacket, RecvError>;
+
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<MqttPacket, RecvTimeoutError>;
+
+    // Puts back messages which were received while waiting for something else,
+    // to be received again (in the same order) before anything new.
+    fn requeue(&mut self, msgs: Vec<MqttPacket>);
+
+    // For publishing from another thread (i.e. the Ctrl-C handler) while this
+    // one is blocked receiving, where the transport supports that.
+    fn publisher(&self) -> Option<Box<dyn Publisher>> {
+        None
+    }
+
+    // Only returns once everything published has gone out, since we usually
+    // leave via `process::exit` straight after.
+    fn disconnect(&mut self) {}
+}
+
+pub trait Publisher: Send {
+    fn publish(&self, topic: &str, payload: &[u8], retain: bool);
+
+    fn disconnect(&self);
+}
+
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Until whichever of the transport and its publishers disconnects first takes
+// it to do so.
+type SharedConnection = Arc<Mutex<Option<mqtt::Connection>>>;
+
+fn close(connection: &SharedConnection, client: &mut rumqttc::Client) {
+    let connection = connection.lock().unwrap().take();
+
+    if let Some(connection) = connection {
+        connection.close(client, DISCONNECT_TIMEOUT);
+    }
+}
+
+pub struct MqttTransport {
+    client: rumqttc::Client,
+    rx: Receiver<MqttPacket>,
+    pending: VecDeque<MqttPacket>,
+    connection: SharedConnection,
+}
+
+impl MqttTransport {
+    pub fn connect() -> Self {
+        let (client, rx, connection) = mqtt::connect();
+        MqttTransport {
+            client,
+            rx,
+            pending: VecDeque::new(),
+            connection: Arc::new(Mutex::new(Some(connection))),
+        }
+    }
+
+    // If a publisher disconnected us from another thread it is about to exit,
+    // so there's no point in waking up just to report the connection as lost.
+    fn wait_if_disconnecting(&self) {
+        if self.connection.lock().unwrap().is_none() {
+            loop {
+                thread::park();
+            }
+        }
+    }
+}
+
+impl Transport for MqttTransport {
+    fn subscribe(&mut self, topic: &str) {
+        self.client.subscribe(topic, QoS::ExactlyOnce).unwrap();
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) {
+        self.publish_qos(topic, payload, QoS::ExactlyOnce, retain);
+    }
+
+    fn publish_qos(&mut self, topic: &str, payload: &[u8], qos: QoS, retain: bool) {
+        self.client.publish(topic, qos, retain, payload).unwrap();
+    }
+
+    fn recv(&mut self) -> Result<MqttPacket, RecvError> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(msg);
+        }
+
+        self.rx.recv().inspect_err(|_| self.wait_if_disconnecting())
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<MqttPacket, RecvTimeoutError> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(msg);
+        }
+
+        self.rx.recv_timeout(timeout).inspect_err(|e| {
+            if *e == RecvTimeoutError::Disconnected {
+                self.wait_if_disconnecting();
+            }
+        })
+    }
+
+    fn requeue(&mut self, msgs: Vec<MqttPacket>) {
+        requeue(&mut self.pending, msgs);
+    }
+
+    fn publisher(&self) -> Option<Box<dyn Publisher>> {
+        Some(Box::new(MqttPublisher {
+            client: Mutex::new(self.client.clone()),
+            connection: self.connection.clone(),
+        }))
+    }
+
+    fn disconnect(&mut self) {
+        close(&self.connection, &mut self.client);
+    }
+}
+
+struct MqttPublisher {
+    client: Mutex<rumqttc::Client>,
+    connection: SharedConnection,
+}
+
+impl Publisher for MqttPublisher {
+    fn publish(&self, topic: &str, payload: &[u8], retain: bool) {
+        self.client
+            .lock()
+            .unwrap()
+            .publish(topic, QoS::ExactlyOnce, retain, payload)
+            .unwrap();
+    }
+
+    fn disconnect(&self) {
+        close(&self.connection, &mut self.client.lock().unwrap());
+    }
+}
+
+pub fn requeue(queue: &mut VecDeque<MqttPacket>, msgs: Vec<MqttPacket>) {
+    for msg in msgs.into_iter().rev() {
+        queue.push_front(msg);
+    }
+}
+
+// Connects to the broker, or whatever is standing in for it in this session.
+pub fn connect() -> Box<dyn Transport> {
+    if capture::is_replaying() {
+        Box::new(ReplayTransport::new())
+    } else if capture::is_recording() {
+        Box::new(RecordingTransport::new(MqttTransport::connect()))
+    } else {
+        Box::new(MqttTransport::connect())
+    }
+}
+
+// MQTT topic filter matching, with `+` for one level and a trailing `#` for
+// any number of them.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+// A message published by whatever is on the other end of a `MemoryTransport`.
+pub struct Reply {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+type Responder = Box<dyn FnMut(&str, &[u8]) -> Vec<Reply>>;
+
+// A broker in a box, for tests: it keeps retained messages and delivers to our
+// subscriptions like a real one, and hands everything we publish to a
+// responder playing the part of the devices. Since nothing else can publish,
+// running out of messages counts as the connection dropping.
+pub struct MemoryTransport {
+    subscriptions: Vec<String>,
+    retained: BTreeMap<String, Vec<u8>>,
+    queue: VecDeque<MqttPacket>,
+    responder: Responder,
+    published: Vec<(String, Vec<u8>)>,
+}
+
+impl MemoryTransport {
+    pub fn new(responder: impl FnMut(&str, &[u8]) -> Vec<Reply> + 'static) -> Self {
+        MemoryTransport {
+            subscriptions: Vec::new(),
+            retained: BTreeMap::new(),
+            queue: VecDeque::new(),
+            responder: Box::new(responder),
+            published: Vec::new(),
+        }
+    }
+
+    // Publishes as the far end would, e.g. to set up retained device state.
+    pub fn inject(&mut self, reply: Reply) {
+        if reply.retain {
+            self.retain(&reply.topic, &reply.payload);
+        }
+
+        if self.is_subscribed(&reply.topic) {
+            self.queue
+                .push_back(packet(reply.topic, reply.payload, false));
+        }
+    }
+
+    // Everything we have published, in order.
+    pub fn published(&self) -> &[(String, Vec<u8>)] {
+        &self.published
+    }
+
+    // As for a broker, retaining an empty message clears the topic instead.
+    fn retain(&mut self, topic: &str, payload: &[u8]) {
+        if payload.is_empty() {
+            self.retained.remove(topic);
+        } else {
+            self.retained.insert(topic.to_owned(), payload.to_vec());
+        }
+    }
+
+    fn is_subscribed(&self, topic: &str) -> bool {
+        self.subscriptions
+            .iter()
+            .any(|filter| topic_matches(filter, topic))
+    }
+}
+
+fn packet(topic: String, payload: Vec<u8>, retain: bool) -> MqttPacket {
+    MqttPacket {
+        topic,
+        content_type: payload::sniff(&payload),
+        payload,
+        retain,
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn subscribe(&mut self, topic: &str) {
+        self.subscriptions.push(topic.to_owned());
+
+        let retained: Vec<MqttPacket> = self
+            .retained
+            .iter()
+            .filter(|(t, _)| topic_matches(topic, t))
+            .map(|(t, payload)| packet(t.clone(), payload.clone(), true))
+            .collect();
+        self.queue.extend(retained);
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) {
+        self.published.push((topic.to_owned(), payload.to_vec()));
+
+        if retain {
+            self.retain(topic, payload);
+        }
+
+        // Like a broker, we're sent what we publish if we're subscribed to it.
+        if self.is_subscribed(topic) {
+            self.queue
+                .push_back(packet(topic.to_owned(), payload.to_vec(), false));
+        }
+
+        for reply in (self.responder)(topic, payload) {
+            self.inject(reply);
+        }
+    }
+
+    fn recv(&mut self) -> Result<MqttPacket, RecvError> {
+        self.queue.pop_front().ok_or(RecvError)
+    }
+
+    fn recv_timeout(&mut self, _: Duration) -> Result<MqttPacket, RecvTimeoutError> {
+        self.queue.pop_front().ok_or(RecvTimeoutError::Disconnected)
+    }
+
+    fn requeue(&mut self, msgs: Vec<MqttPacket>) {
+        requeue(&mut self.queue, msgs);
+    }
+}
@@ -1,47 +1,71 @@
+pub mod cmd;
+pub mod forget;
+pub mod interrupt;
 pub mod list;
+pub mod lock;
 pub mod mark;
 pub mod ota;
+pub mod partitions;
+pub mod ping;
 pub mod restart;
+pub mod schema;
 pub mod status;
+pub mod topics;
 
-use console::{style, Term};
-use rumqttc::QoS;
+use console::style;
+use serde::Serialize;
 use std::fmt;
-use std::sync::mpsc::Receiver;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
 
 use crate::data::{
     decode::{self, decode_id_message},
     model,
+    payload::PayloadError,
 };
 use crate::net::mqtt::{self, MqttPacket};
+use crate::net::transport::{self, Transport};
+use crate::{cache, ui};
+
+// Every device lives under `<TOPIC_PREFIX><device>/`.
+pub const TOPIC_PREFIX: &str = "hoek/iot/";
+
+pub struct TopicBundle {
+    device_name: String,
 
-pub(crate) struct TopicBundle {
     info_ota: String,
     info_error: String,
     info_status: String,
     info_id: String,
+    info_ack: String,
 
     cmd_ota: String,
     cmd_restart: String,
+    cmd_id: String,
 }
 
 impl TopicBundle {
     fn new(device_name: &str) -> Self {
         TopicBundle {
-            info_ota: r"hoek/iot/".to_owned() + device_name + "/_info/ota",
-            info_error: r"hoek/iot/".to_owned() + device_name + "/_info/error",
-            info_status: r"hoek/iot/".to_owned() + device_name + "/_info/status",
-            info_id: r"hoek/iot/".to_owned() + device_name + "/_info/id",
+            device_name: device_name.to_owned(),
 
-            cmd_ota: r"hoek/iot/".to_owned() + device_name + "/_cmd/ota",
-            cmd_restart: r"hoek/iot/".to_owned() + device_name + "/_cmd/restart",
+            info_ota: TOPIC_PREFIX.to_owned() + device_name + "/_info/ota",
+            info_error: TOPIC_PREFIX.to_owned() + device_name + "/_info/error",
+            info_status: TOPIC_PREFIX.to_owned() + device_name + "/_info/status",
+            info_id: TOPIC_PREFIX.to_owned() + device_name + "/_info/id",
+            info_ack: TOPIC_PREFIX.to_owned() + device_name + "/_info/ack",
+
+            cmd_ota: TOPIC_PREFIX.to_owned() + device_name + "/_cmd/ota",
+            cmd_restart: TOPIC_PREFIX.to_owned() + device_name + "/_cmd/restart",
+            cmd_id: TOPIC_PREFIX.to_owned() + device_name + "/_cmd/id",
         }
     }
 }
 
-pub(crate) enum PrettyHeader {
+pub enum PrettyHeader {
     Success,
     Failed,
+    Interrupted,
 }
 
 impl fmt::Display for PrettyHeader {
@@ -49,74 +73,373 @@ impl fmt::Display for PrettyHeader {
         match self {
             PrettyHeader::Success => write!(fmt, "{}", style("SUCCESS").on_green()),
             PrettyHeader::Failed => write!(fmt, "{}", style("FAILED").on_red()),
+            PrettyHeader::Interrupted => write!(fmt, "{}", style("INTERRUPTED").on_yellow()),
         }
     }
 }
 
+#[derive(Debug, PartialEq)]
 pub enum ExitDisposition {
     Ok,
     Retry,
     Abort,
 }
 
-pub(crate) enum PostOperationWaitStrategy {
+// What a report from the device, while waiting after an operation, means for it.
+#[derive(Debug, PartialEq)]
+pub enum WaitStatus {
+    Finished,
+    Waiting,
+    // The device ended up somewhere the operation can't have put it, as said.
+    Failed(String),
+}
+
+pub enum PostOperationWaitStrategy {
     PowerCycle,
     IdMessage,
 }
 
-pub(crate) trait Operation {
+pub trait Operation {
     fn perform(
         &self,
         topics: &TopicBundle,
-        mqtt: (&mut rumqttc::Client, &Receiver<MqttPacket>),
+        transport: &mut dyn Transport,
         id: &decode::DecodedIdMessage,
     ) -> ExitDisposition;
 
+    // The device must support all of these for the operation to be attempted.
+    fn required_capabilities(&self) -> &'static [decode::Capability];
+
     fn get_wait_strategy(&self) -> Option<PostOperationWaitStrategy>;
 
+    // Operations which change anything on the device hold its lock (see
+    // `lock`) while performed, under this name.
+    fn lock_name(&self) -> Option<&'static str> {
+        None
+    }
+
+    // Called instead of `perform` when the device turns out to be down.
+    fn report_down(&self, _device_name: &str) {}
+
     fn exit_ok_is_finished_waiting(
         &self,
         _original_id: &decode::DecodedIdMessage,
         _current_id: &decode::DecodedIdMessage,
-    ) -> bool {
-        true
+    ) -> WaitStatus {
+        WaitStatus::Finished
     }
 
     fn exit_retry_is_finished_waiting(
         &self,
         _original_id: &decode::DecodedIdMessage,
         _current_id: &decode::DecodedIdMessage,
-    ) -> bool {
+    ) -> WaitStatus {
         unreachable!()
     }
 
     fn print_completed_message(&self);
 }
 
-fn perform_op_once<Op: Operation>(op: Op, device_name: &str) -> ExitDisposition {
-    let term = Term::stdout();
+static REQUEST_COUNT: AtomicU32 = AtomicU32::new(0);
 
-    let topics = TopicBundle::new(device_name);
+// Wraps `body` for sending to a device speaking `schema`, under a request id
+// unique to this run.
+pub fn command<T: Serialize>(schema: u32, body: T) -> model::Command<T> {
+    let n = REQUEST_COUNT.fetch_add(1, Ordering::SeqCst) + 1;
+
+    model::Command::new(
+        schema.min(model::SCHEMA_VERSION),
+        format!("{}-{}", mqtt::client_id(), n),
+        mqtt::client_id().to_owned(),
+        body,
+    )
+}
+
+// What came of a command sent with `send_command`.
+#[derive(Debug, PartialEq)]
+pub enum Ack {
+    Accepted,
+    Rejected,
+    // Either the device doesn't acknowledge commands, or it didn't in time,
+    // and whether it worked has to be inferred from what it reports next.
+    Missing,
+}
+
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// For messages from the device which we can't make sense of, but which aren't
+// worth giving up over: we just carry on waiting for a better one.
+pub fn warn_unreadable(what: &str, e: &PayloadError) {
+    sayln!("{} ({})", style(format!("Unreadable {}", what)).yellow(), e);
+    // Prevent the message being eaten when the previous line is cleared.
+    sayln!();
+}
+
+// Sends `body` to `topic` as a command, waiting for the device to acknowledge
+// it if it can. Says why if it was rejected.
+pub fn send_command<T: Serialize>(
+    topics: &TopicBundle,
+    transport: &mut dyn Transport,
+    id: &decode::DecodedIdMessage,
+    topic: &str,
+    body: T,
+) -> Ack {
+    let command = command(id.msg.schema, body);
+    transport.publish(
+        topic,
+        serde_json::to_string(&command)
+            .expect("Could not build JSON")
+            .as_bytes(),
+        false,
+    );
+
+    if !id.supports(decode::Capability::Ack) {
+        return Ack::Missing;
+    }
+
+    match mqtt_wait_for_ack(topics, transport, &command.request_id) {
+        None => {
+            sayln!("Device did not acknowledge the command, waiting for it to report instead.");
+            Ack::Missing
+        }
+        Some(ack) => match ack.status {
+            model::AckStatus::Accepted => Ack::Accepted,
+            model::AckStatus::Rejected => {
+                sayln!(
+                    "{}: Device rejected the command ({})!",
+                    PrettyHeader::Failed,
+                    ack.reason.as_deref().unwrap_or("no reason given")
+                );
+                Ack::Rejected
+            }
+        },
+    }
+}
+
+// Anything else received in the meantime (e.g. the device going down to carry
+// the command out, if it did so before acking) is requeued for the wait after.
+fn mqtt_wait_for_ack(
+    topics: &TopicBundle,
+    transport: &mut dyn Transport,
+    request_id: &str,
+) -> Option<model::AckMessage> {
+    let deadline = Instant::now() + ACK_TIMEOUT;
+    let mut skipped = Vec::new();
+
+    let ack = loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let msg = match transport.recv_timeout(timeout) {
+            Ok(msg) => msg,
+            Err(_) => break None,
+        };
+
+        if msg.topic == topics.info_error {
+            sayln!("{} ({})", style("Log Error").red(), msg.payload_text());
+            continue;
+        }
+
+        if msg.topic == topics.info_ack {
+            if let Ok(ack) = msg.parse::<model::AckMessage>() {
+                if ack.request_id == request_id {
+                    break Some(ack);
+                }
+            }
+        }
+
+        skipped.push(msg);
+    };
+
+    transport.requeue(skipped);
+    ack
+}
+
+// How long a device which can republish its id message on request gets to do
+// so, before we take it to be offline whatever its retained status says.
+const ID_REFRESH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// For devices which can't, how long we must have seen the same retained id
+// message for (see `cache::retained_first_seen`) before it counts as stale.
+pub const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+// The broker hands us whatever id message the device last retained, however
+// long ago that was, so we ask devices which can for a fresh one. Returns the
+// id message to go by, and whether it is stale, or `None` if the device didn't
+// answer.
+fn refresh_id_message(
+    topics: &TopicBundle,
+    transport: &mut dyn Transport,
+    raw: &MqttPacket,
+    id: model::IdMessage,
+) -> Option<(model::IdMessage, bool)> {
+    if !raw.retain {
+        return Some((id, false));
+    }
+
+    if id.schema < decode::Capability::IdRefresh.min_schema_version() {
+        let first_seen = cache::retained_first_seen(&[(&raw.topic, &raw.payload)])
+            .pop()
+            .flatten();
+        let stale = first_seen
+            .and_then(|first_seen| first_seen.elapsed().ok())
+            .is_some_and(|age| age > STALE_AFTER);
+
+        return Some((id, stale));
+    }
+
+    transport.publish(
+        &topics.cmd_id,
+        serde_json::to_string(&command(id.schema, model::IdCommand {}))
+            .expect("Could not build JSON")
+            .as_bytes(),
+        false,
+    );
+
+    let id = mqtt_wait_for_fresh_id_message(topics, transport)?;
+
+    Some((id, false))
+}
+
+// Waits for an id message which the device published just now, rather than
+// one the broker retained, giving up if the device goes down or times out.
+// As for acks, anything else received in the meantime is requeued.
+fn mqtt_wait_for_fresh_id_message(
+    topics: &TopicBundle,
+    transport: &mut dyn Transport,
+) -> Option<model::IdMessage> {
+    let deadline = Instant::now() + ID_REFRESH_TIMEOUT;
+    let mut skipped = Vec::new();
+
+    let fresh = loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let msg = match transport.recv_timeout(timeout) {
+            Ok(msg) => msg,
+            Err(_) => break None,
+        };
+
+        if msg.topic == topics.info_error {
+            sayln!("{} ({})", style("Log Error").red(), msg.payload_text());
+            // Prevent the message being eaten when the previous line is cleared.
+            sayln!();
+            continue;
+        }
 
-    println!("Connecting to broker...");
+        if msg.topic == topics.info_status {
+            // Whatever else a garbled status says, it isn't that it's down.
+            let down = msg
+                .parse::<model::StatusMessage>()
+                .is_ok_and(|status| status.state == model::DeviceState::Down);
 
-    let (mut client, rx) = mqtt::connect();
+            if down {
+                skipped.push(msg);
+                break None;
+            }
+        }
+
+        if msg.topic == topics.info_id && !msg.retain {
+            match decode::parse_id_message(&msg.payload, msg.content_type) {
+                Ok(id) => break Some(id),
+                Err(e) => {
+                    warn_unreadable("id message", &e);
+                    continue;
+                }
+            }
+        }
+
+        skipped.push(msg);
+    };
+
+    transport.requeue(skipped);
+    fresh
+}
+
+// Whether a device speaking `schema` can do everything in `capabilities`.
+// Prints why not if it can't.
+pub fn require_capabilities(schema: u32, capabilities: &[decode::Capability]) -> bool {
+    match capabilities
+        .iter()
+        .find(|capability| schema < capability.min_schema_version())
+    {
+        None => true,
+        Some(capability) => {
+            sayln!(
+                "{}: Device firmware too old for {} (speaks schema v{}, needs v{})!",
+                PrettyHeader::Failed,
+                capability,
+                schema,
+                capability.min_schema_version()
+            );
+            false
+        }
+    }
+}
+
+// For operations which can't do anything without knowing what the device is
+// running. Prints why not and returns `None` if the device didn't tell us.
+pub fn require_running_part(id: &decode::DecodedIdMessage) -> Option<&decode::RunningPartInfo> {
+    if id.ota_info.running.is_none() {
+        sayln!(
+            "{}: Could not determine which partition the device is running on!",
+            PrettyHeader::Failed
+        );
+        for warning in id.warnings.iter() {
+            sayln!("  {}", warning);
+        }
+    }
+
+    id.ota_info.running.as_ref()
+}
+
+fn perform_op_once<Op: Operation>(op: Op, device_name: &str, force: bool) -> ExitDisposition {
+    sayln!("Connecting to broker...");
+    interrupt::enter(device_name, interrupt::Phase::Connecting);
+
+    let mut transport = transport::connect();
+    interrupt::attach(transport.publisher());
+
+    ui::clear_last_lines(1);
+    let ed = perform_op_locked_on(&op, &mut *transport, device_name, force);
+    transport.disconnect();
+
+    ed
+}
+
+// As `perform_op_on`, but holding the device's lock if `op` needs it.
+pub fn perform_op_locked_on<Op: Operation>(
+    op: &Op,
+    transport: &mut dyn Transport,
+    device_name: &str,
+    force: bool,
+) -> ExitDisposition {
+    let name = match op.lock_name() {
+        None => return perform_op_on(op, transport, device_name),
+        Some(name) => name,
+    };
+
+    let lock = match lock::acquire(transport, device_name, name, force) {
+        None => return ExitDisposition::Abort,
+        Some(lock) => lock,
+    };
 
-    client
-        .subscribe(&topics.info_ota, QoS::ExactlyOnce)
-        .unwrap();
+    let mut held = lock.hold(transport);
+    perform_op_on(op, &mut *held, device_name)
+}
 
-    client
-        .subscribe(&topics.info_error, QoS::ExactlyOnce)
-        .unwrap();
+// Runs a single attempt of `op` over an already connected `transport`.
+pub fn perform_op_on<Op: Operation>(
+    op: &Op,
+    transport: &mut dyn Transport,
+    device_name: &str,
+) -> ExitDisposition {
+    let topics = TopicBundle::new(device_name);
 
-    client.subscribe(&topics.info_id, QoS::ExactlyOnce).unwrap();
-    client
-        .subscribe(&topics.info_status, QoS::ExactlyOnce)
-        .unwrap();
+    transport.subscribe(&topics.info_ota);
+    transport.subscribe(&topics.info_error);
+    transport.subscribe(&topics.info_id);
+    transport.subscribe(&topics.info_status);
+    transport.subscribe(&topics.info_ack);
 
-    term.clear_last_lines(1).unwrap();
-    println!(
+    interrupt::enter(device_name, interrupt::Phase::WaitingForDevice);
+    sayln!(
         "Waiting for status message from device '{}'...",
         device_name
     );
@@ -125,60 +448,119 @@ fn perform_op_once<Op: Operation>(op: Op, device_name: &str) -> ExitDisposition
         Some(&topics.info_status),
         &topics.info_id,
         &topics.info_error,
-        &rx,
+        transport,
     ) {
         None => {
-            println!("{}: Device is down!", PrettyHeader::Failed);
+            store_status(device_name, model::DeviceState::Down);
+            interrupt::seen_status(model::DeviceState::Down);
+            sayln!("{}: Device is down!", PrettyHeader::Failed);
+            op.report_down(device_name);
             return ExitDisposition::Abort;
         }
         Some(original_id_raw) => original_id_raw,
     };
 
-    let original_id =
-        decode_id_message(serde_json::from_str(&original_id_raw).expect("JSON parse error"));
+    let original_id_msg =
+        match decode::parse_id_message(&original_id_raw.payload, original_id_raw.content_type) {
+            Ok(id) => id,
+            Err(e) => {
+                sayln!(
+                    "{}: Could not read the device's id message ({})!",
+                    PrettyHeader::Failed,
+                    e
+                );
+                return ExitDisposition::Abort;
+            }
+        };
+
+    let (original_id_msg, stale) =
+        match refresh_id_message(&topics, transport, &original_id_raw, original_id_msg) {
+            None => {
+                sayln!(
+                    "{}: Device is offline, despite its retained 'Up' status!",
+                    PrettyHeader::Failed
+                );
+                sayln!("It did not answer a request for its current status.");
+                return ExitDisposition::Abort;
+            }
+            Some(refreshed) => refreshed,
+        };
+
+    store_status(device_name, model::DeviceState::Up);
+    cache::store_id(device_name, &original_id_msg);
+    let mut original_id = decode_id_message(original_id_msg);
+    original_id.stale = stale;
+    interrupt::seen_id(&original_id);
 
-    term.clear_last_lines(1).unwrap();
+    ui::clear_last_lines(1);
     decode::print_parts_legend();
-    println!();
-    println!("{}", original_id.ota_info.fmt);
-    println!();
+    sayln!();
+    sayln!("{}", original_id.ota_info.fmt);
+    if stale {
+        sayln!(
+            "{}",
+            style(format!(
+                "(Retained by the broker for over {}, so may be out of date.)",
+                decode::format_age(STALE_AFTER)
+            ))
+            .yellow()
+        );
+    }
+    sayln!();
 
-    let ed = op.perform(&topics, (&mut client, &rx), &original_id);
+    if !require_capabilities(original_id.msg.schema, op.required_capabilities()) {
+        return ExitDisposition::Abort;
+    }
+
+    let ed = op.perform(&topics, transport, &original_id);
 
     if let ExitDisposition::Abort = ed {
         return ExitDisposition::Abort;
     }
 
     // The let binding forces the match to be exhaustive.
-    let _ = match (&ed, op.get_wait_strategy()) {
-        (ExitDisposition::Abort, _) => {}
-        (_, None) => {}
+    let waited = match (&ed, op.get_wait_strategy()) {
+        (ExitDisposition::Abort, _) => WaitStatus::Finished,
+        (_, None) => WaitStatus::Finished,
         (_, Some(PostOperationWaitStrategy::PowerCycle)) => {
-            println!("Waiting for device 'Down' message...");
+            interrupt::enter(device_name, interrupt::Phase::WaitingForRestart);
+            sayln!("Waiting for device 'Down' message...");
 
-            mqtt_wait_for_status_message(&topics.info_status, model::DeviceState::Down, &rx);
+            mqtt_wait_for_status_message(&topics.info_status, model::DeviceState::Down, transport);
+            store_status(device_name, model::DeviceState::Down);
+            interrupt::seen_status(model::DeviceState::Down);
 
-            term.clear_last_lines(1).unwrap();
-            println!("Waiting for device 'Up' message...");
+            ui::clear_last_lines(1);
+            sayln!("Waiting for device 'Up' message...");
 
-            mqtt_wait_for_status_message(&topics.info_status, model::DeviceState::Up, &rx);
+            mqtt_wait_for_status_message(&topics.info_status, model::DeviceState::Up, transport);
+            store_status(device_name, model::DeviceState::Up);
+            interrupt::seen_status(model::DeviceState::Up);
 
-            println!("Device reconnected!");
+            sayln!("Device reconnected!");
+            WaitStatus::Finished
         }
         (ExitDisposition::Ok, Some(PostOperationWaitStrategy::IdMessage)) => {
-            println!();
-            mqtt_wait_for_id_condition(&topics, &rx, &original_id, |o_id, c_id| {
+            interrupt::enter(device_name, interrupt::Phase::WaitingForReport);
+            sayln!();
+            mqtt_wait_for_id_condition(&topics, transport, &original_id, |o_id, c_id| {
                 op.exit_ok_is_finished_waiting(o_id, c_id)
-            });
+            })
         }
         (ExitDisposition::Retry, Some(PostOperationWaitStrategy::IdMessage)) => {
-            println!();
-            mqtt_wait_for_id_condition(&topics, &rx, &original_id, |o_id, c_id| {
+            interrupt::enter(device_name, interrupt::Phase::WaitingForReport);
+            sayln!();
+            mqtt_wait_for_id_condition(&topics, transport, &original_id, |o_id, c_id| {
                 op.exit_retry_is_finished_waiting(o_id, c_id)
-            });
+            })
         }
     };
 
+    if let WaitStatus::Failed(reason) = waited {
+        sayln!("{}: {}!", PrettyHeader::Failed, reason);
+        return ExitDisposition::Abort;
+    }
+
     if let ExitDisposition::Ok = ed {
         op.print_completed_message();
     }
@@ -187,70 +569,81 @@ fn perform_op_once<Op: Operation>(op: Op, device_name: &str) -> ExitDisposition
 }
 
 // Returns `true` if the operation completed, and `false` if it should be
-// retried. (If an un-retriable error occurs, the program will exit.)
-pub(crate) fn perform_op<Op: Operation>(op: Op, device_name: &str) -> bool {
-    loop {
-        match perform_op_once(op, device_name) {
-            ExitDisposition::Retry => {
-                println!("Retrying operation...");
-                println!();
-
-                return false;
-            }
-            ExitDisposition::Ok => {
-                return true;
-            }
-            ExitDisposition::Abort => {
-                std::process::exit(-1);
-            }
+// retried. (If an un-retriable error occurs, the program will exit.) With
+// `force`, a mutating operation overrides another's lock on the device.
+pub fn perform_op<Op: Operation>(op: Op, device_name: &str, force: bool) -> bool {
+    let ed = perform_op_once(op, device_name, force);
+    interrupt::leave();
+
+    match ed {
+        ExitDisposition::Retry => {
+            sayln!("Retrying operation...");
+            sayln!();
+
+            false
+        }
+        ExitDisposition::Ok => true,
+        ExitDisposition::Abort => {
+            std::process::exit(-1);
         }
     }
 }
 
+// Returns once `condition` is no longer `Waiting`, with what it was instead.
 fn mqtt_wait_for_id_condition<
-    FCond: Fn(&decode::DecodedIdMessage, &decode::DecodedIdMessage) -> bool,
+    FCond: Fn(&decode::DecodedIdMessage, &decode::DecodedIdMessage) -> WaitStatus,
 >(
     topics: &TopicBundle,
-    rx: &Receiver<MqttPacket>,
+    transport: &mut dyn Transport,
     original_id: &decode::DecodedIdMessage,
     condition: FCond,
-) {
-    let term = Term::stdout();
-
-    println!("Waiting for response(s)...");
+) -> WaitStatus {
+    sayln!("Waiting for response(s)...");
 
     loop {
         let raw_id =
-            mqtt_wait_for_id_message(None, &topics.info_id, &topics.info_error, rx).unwrap();
-        let current_id =
-            decode_id_message(serde_json::from_str(&raw_id).expect("JSON parse error"));
-
-        term.clear_last_lines(1).unwrap();
-        println!("{}", current_id.ota_info.fmt);
-        println!();
-
-        if condition(original_id, &current_id) {
-            break;
+            mqtt_wait_for_id_message(None, &topics.info_id, &topics.info_error, transport).unwrap();
+        let current_id_msg = match decode::parse_id_message(&raw_id.payload, raw_id.content_type) {
+            Ok(id) => id,
+            Err(e) => {
+                warn_unreadable("id message", &e);
+                continue;
+            }
+        };
+        cache::store_id(&topics.device_name, &current_id_msg);
+        let current_id = decode_id_message(current_id_msg);
+        interrupt::seen_id(&current_id);
+
+        ui::clear_last_lines(1);
+        sayln!("{}", current_id.ota_info.fmt);
+        sayln!();
+
+        match condition(original_id, &current_id) {
+            WaitStatus::Waiting => {}
+            status => return status,
         }
 
-        println!("Waiting for newer response(s)...");
+        sayln!("Waiting for newer response(s)...");
     }
 }
 
+fn store_status(device_name: &str, state: model::DeviceState) {
+    cache::store_status(device_name, &model::StatusMessage { state });
+}
+
 fn mqtt_wait_for_status_message(
     topic_info_status: &str,
     target_state: model::DeviceState,
-    rx: &Receiver<mqtt::MqttPacket>,
+    transport: &mut dyn Transport,
 ) {
     loop {
-        let msg = rx.recv().unwrap();
+        let msg = transport.recv().unwrap();
 
         if msg.topic == topic_info_status {
-            let status: model::StatusMessage =
-                serde_json::from_str(&msg.payload).expect("JSON parse error");
-
-            if status.state == target_state {
-                return;
+            match msg.parse::<model::StatusMessage>() {
+                Ok(status) if status.state == target_state => return,
+                Ok(_) => {}
+                Err(e) => warn_unreadable("status message", &e),
             }
         }
     }
@@ -263,37 +656,38 @@ fn mqtt_wait_for_id_message(
     topic_info_status: Option<&str>,
     topic_info_id: &str,
     topic_info_error: &str,
-    rx: &Receiver<mqtt::MqttPacket>,
-) -> Option<String> {
+    transport: &mut dyn Transport,
+) -> Option<MqttPacket> {
     let mut up_state_seen = false;
-    let mut last_raw_id: Option<String> = None;
+    let mut last_raw_id: Option<MqttPacket> = None;
 
     loop {
-        let msg = rx.recv().unwrap();
+        let msg = transport.recv().unwrap();
 
         if msg.topic == topic_info_error {
-            println!("{} ({})", style("Log Error").red(), msg.payload);
+            sayln!("{} ({})", style("Log Error").red(), msg.payload_text());
             // Prevent the message being eaten when the previous line is cleared.
-            println!();
+            sayln!();
         }
 
-        if topic_info_status.map_or(false, |topic| msg.topic == topic) {
-            let status: model::StatusMessage =
-                serde_json::from_str(&msg.payload).expect("JSON parse error");
-
-            match status.state {
-                model::DeviceState::Up => {
+        if topic_info_status == Some(msg.topic.as_str()) {
+            match msg
+                .parse::<model::StatusMessage>()
+                .map(|status| status.state)
+            {
+                Ok(model::DeviceState::Up) => {
                     up_state_seen = true;
                     if last_raw_id.is_some() {
                         break;
                     }
                 }
-                model::DeviceState::Down => return None,
+                Ok(model::DeviceState::Down) => return None,
+                Err(e) => warn_unreadable("status message", &e),
             }
         }
 
         if msg.topic == topic_info_id {
-            last_raw_id = Some(msg.payload);
+            last_raw_id = Some(msg);
 
             if up_state_seen || topic_info_status.is_none() {
                 break;
@@ -0,0 +1,178 @@
+// Publishing to any of a device's `_cmd/*` topics, for the app-specific
+// commands which don't warrant an operation of their own.
+
+use console::style;
+use rumqttc::QoS;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+
+use super::{PrettyHeader, TopicBundle, TOPIC_PREFIX};
+use crate::data::decode::format_age;
+use crate::net::mqtt::MqttPacket;
+use crate::net::transport::{self, Transport};
+use crate::ui;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to wait for the broker to hand over a retained response, which
+// would be from some earlier command rather than ours.
+const SETTLE: Duration = Duration::from_millis(500);
+
+pub struct Request {
+    // Of the `_cmd/<name>` topic to publish to.
+    pub name: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+    // Of the `_info/<name>` topic to wait for a response on, if any.
+    pub response: Option<String>,
+    pub timeout: Duration,
+}
+
+// Topic names end up in topics we publish to, where wildcards aren't allowed.
+pub fn parse_name(name: &str) -> Result<String, String> {
+    if name.is_empty() {
+        Err("name cannot be empty".to_owned())
+    } else if name.contains(['+', '#']) {
+        Err(format!("'{}' contains an MQTT wildcard", name))
+    } else {
+        Ok(name.to_owned())
+    }
+}
+
+pub fn parse_qos(qos: &str) -> Result<QoS, String> {
+    match qos {
+        "0" => Ok(QoS::AtMostOnce),
+        "1" => Ok(QoS::AtLeastOnce),
+        "2" => Ok(QoS::ExactlyOnce),
+        _ => Err(format!("unknown QoS '{}', expected 0, 1 or 2", qos)),
+    }
+}
+
+// The payload to send, given as JSON on the command line or as `@<file>`,
+// which is sent as is (e.g. for CBOR). Without either, an empty JSON object.
+pub fn load_payload(arg: Option<&str>) -> Result<Vec<u8>, String> {
+    match arg {
+        None => Ok(b"{}".to_vec()),
+        Some(arg) if arg.starts_with('@') => super::topics::load_payload(arg),
+        Some(arg) => serde_json::from_str::<serde_json::Value>(arg)
+            .map(|_| arg.as_bytes().to_vec())
+            .map_err(|e| format!("payload is not valid JSON: {}", e)),
+    }
+}
+
+fn device_topic(device_name: &str, kind: &str, name: &str) -> String {
+    format!("{}{}/{}/{}", TOPIC_PREFIX, device_name, kind, name)
+}
+
+// JSON and CBOR responses are shown as indented JSON, anything else as text.
+fn print_response(msg: &MqttPacket) {
+    let value = msg.parse::<serde_json::Value>().ok();
+
+    if ui::output_format().is_machine() {
+        match value {
+            Some(value) => ui::emit(&value),
+            None => ui::emit(&msg.payload_text()),
+        }
+        return;
+    }
+
+    sayln!("Response on '{}':", msg.topic);
+    match value {
+        Some(value) => sayln!(
+            "{}",
+            serde_json::to_string_pretty(&value).expect("Could not build JSON")
+        ),
+        None => sayln!("{}", msg.payload_text()),
+    }
+}
+
+fn drain(transport: &mut dyn Transport) {
+    while transport.recv_timeout(SETTLE).is_ok() {}
+}
+
+fn wait_for_response(
+    transport: &mut dyn Transport,
+    topics: &TopicBundle,
+    topic: &str,
+    timeout: Duration,
+) -> Result<MqttPacket, RecvTimeoutError> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let msg = transport.recv_timeout(deadline.saturating_duration_since(Instant::now()))?;
+
+        if msg.topic == topics.info_error {
+            sayln!("{} ({})", style("Log Error").red(), msg.payload_text());
+        }
+
+        if msg.topic == topic {
+            return Ok(msg);
+        }
+    }
+}
+
+// Sends the command over an already connected `transport`, returning whether
+// it went out (and was responded to, if a response was asked for).
+pub fn perform_on(transport: &mut dyn Transport, device_name: &str, request: &Request) -> bool {
+    let topics = TopicBundle::new(device_name);
+    let cmd_topic = device_topic(device_name, "_cmd", &request.name);
+    let response_topic = request
+        .response
+        .as_ref()
+        .map(|name| device_topic(device_name, "_info", name));
+
+    if let Some(topic) = &response_topic {
+        transport.subscribe(topic);
+        transport.subscribe(&topics.info_error);
+        drain(transport);
+    }
+
+    transport.publish_qos(&cmd_topic, &request.payload, request.qos, request.retain);
+
+    let topic = match response_topic {
+        None => {
+            sayln!(
+                "{}: Command sent to '{}'.",
+                PrettyHeader::Success,
+                cmd_topic
+            );
+            return true;
+        }
+        Some(topic) => topic,
+    };
+
+    sayln!("Waiting for response on '{}'...", topic);
+
+    match wait_for_response(transport, &topics, &topic, request.timeout) {
+        Ok(msg) => {
+            ui::clear_last_lines(1);
+            print_response(&msg);
+            true
+        }
+        Err(RecvTimeoutError::Timeout) => {
+            sayln!(
+                "{}: No response from device '{}' within {}!",
+                PrettyHeader::Failed,
+                device_name,
+                format_age(request.timeout)
+            );
+            false
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            sayln!("{}: Lost connection to broker!", PrettyHeader::Failed);
+            false
+        }
+    }
+}
+
+pub fn perform(device_name: &str, request: Request) -> ! {
+    sayln!("Connecting to broker...");
+    let mut transport = transport::connect();
+    ui::clear_last_lines(1);
+
+    let ok = perform_on(&mut *transport, device_name, &request);
+    transport.disconnect();
+
+    std::process::exit(if ok { 0 } else { -1 });
+}
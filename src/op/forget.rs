@@ -0,0 +1,72 @@
+// Clearing out what a decommissioned device leaves behind, which would
+// otherwise keep it in `iota list` for good: the messages retained under its
+// topics on the broker, and our cached state.
+
+use std::time::Duration;
+
+use super::{topics, PrettyHeader, TOPIC_PREFIX};
+use crate::cache;
+use crate::net::transport::{self, Transport};
+use crate::ui;
+
+// Shows what there is to remove for `device_name`, and removes it if the user
+// agrees (or `yes`). Returns `false` if they didn't.
+pub fn perform_on(
+    transport: &mut dyn Transport,
+    device_name: &str,
+    settle: Duration,
+    yes: bool,
+) -> bool {
+    let prefix = TOPIC_PREFIX.to_owned() + device_name + "/";
+    let retained = topics::collect_retained(transport, &(prefix.clone() + "#"), settle);
+    let cached = cache::load(device_name).is_some();
+
+    if retained.is_empty() && !cached {
+        sayln!("Nothing is known about device '{}'.", device_name);
+        return true;
+    }
+
+    sayln!("This will remove, for device '{}':", device_name);
+    for (topic, msg) in retained.iter() {
+        sayln!(
+            "  retained message on '{}' ({} B)",
+            topic,
+            msg.payload.len()
+        );
+    }
+    if cached {
+        sayln!("  its cached state");
+    }
+    sayln!();
+
+    if !yes && !ui::confirm("Forget the device?") {
+        sayln!("Nothing was removed.");
+        return false;
+    }
+
+    for topic in retained.keys() {
+        transport.publish(topic, b"", true);
+    }
+    cache::forget_device(device_name);
+    cache::forget_retained(&prefix);
+
+    sayln!(
+        "{}: Forgot device '{}' ({} retained messages cleared).",
+        PrettyHeader::Success,
+        device_name,
+        retained.len()
+    );
+
+    true
+}
+
+pub fn perform(device_name: &str, settle: Duration, yes: bool) -> ! {
+    sayln!("Connecting to broker...");
+    let mut transport = transport::connect();
+    ui::clear_last_lines(1);
+
+    let ok = perform_on(&mut *transport, device_name, settle, yes);
+    transport.disconnect();
+
+    std::process::exit(if ok { 0 } else { -1 });
+}
@@ -0,0 +1,253 @@
+// Ctrl-C handling for operations. Rather than leaving the user to guess
+// whether the device got a command (or is halfway through flashing one), we
+// say how far the operation got and what the device was last seen doing, and
+// offer to put it back into a known state before disconnecting.
+
+use once_cell::sync::Lazy;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+use super::{command, PrettyHeader, TopicBundle};
+use crate::data::{decode, model};
+use crate::net::transport::Publisher;
+use crate::ui;
+
+// As for a shell, 128 + SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Connecting,
+    WaitingForDevice,
+    // Of the firmware image to the file host, before the device is involved.
+    Uploading,
+    Confirming,
+    Flashing,
+    WaitingForRestart,
+    WaitingForReport,
+}
+
+impl Phase {
+    // What we know about what the device got from us, at this point.
+    fn outcome(&self) -> &'static str {
+        match self {
+            Phase::Connecting
+            | Phase::WaitingForDevice
+            | Phase::Uploading
+            | Phase::Confirming => "Nothing was sent to the device.",
+            Phase::Flashing => {
+                "The device may still be writing the update, and will boot into it at its next restart if that completes."
+            }
+            Phase::WaitingForRestart => {
+                "The restart command was sent, but the device may not have restarted yet."
+            }
+            Phase::WaitingForReport => {
+                "The command was sent, but the device had not confirmed it yet."
+            }
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Connecting => write!(fmt, "connecting to the broker"),
+            Phase::WaitingForDevice => write!(fmt, "waiting for the device to report"),
+            Phase::Uploading => write!(fmt, "uploading the firmware image"),
+            Phase::Confirming => write!(fmt, "waiting for confirmation"),
+            Phase::Flashing => write!(fmt, "the device was flashing the update"),
+            Phase::WaitingForRestart => write!(fmt, "waiting for the device to restart"),
+            Phase::WaitingForReport => write!(fmt, "waiting for the device to respond"),
+        }
+    }
+}
+
+// What we can offer to send to get the device back into a known state.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    Restart,
+    Rollback,
+}
+
+impl Recovery {
+    fn question(&self) -> &'static str {
+        match self {
+            Recovery::Restart => {
+                "Restart the device now, abandoning the update if still in progress?"
+            }
+            Recovery::Rollback => "Roll back the update pending verification?",
+        }
+    }
+
+    fn send(&self, topics: &TopicBundle, schema: u32, publisher: &dyn Publisher) {
+        match self {
+            Recovery::Restart => publisher.publish(
+                &topics.cmd_restart,
+                serde_json::to_string(&command(schema, model::RestartCommand {}))
+                    .expect("Could not build JSON")
+                    .as_bytes(),
+                false,
+            ),
+            Recovery::Rollback => publisher.publish(
+                &topics.cmd_ota,
+                serde_json::to_string(&command(schema, model::OtaCommand::Rollback))
+                    .expect("Could not build JSON")
+                    .as_bytes(),
+                false,
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recovery::Restart => write!(fmt, "Restart"),
+            Recovery::Rollback => write!(fmt, "Rollback"),
+        }
+    }
+}
+
+struct Seen {
+    at: Instant,
+    what: String,
+}
+
+#[derive(Default)]
+struct State {
+    device_name: Option<String>,
+    phase: Option<Phase>,
+    recovery: Option<Recovery>,
+    last_seen: Option<Seen>,
+    // Of the device's last id message, to send any recovery command in.
+    schema: Option<u32>,
+    publisher: Option<Box<dyn Publisher>>,
+    lock_topic: Option<String>,
+}
+
+static STATE: Lazy<Mutex<State>> = Lazy::new(|| Mutex::new(State::default()));
+
+static HANDLING: AtomicBool = AtomicBool::new(false);
+
+pub fn install() {
+    ctrlc::set_handler(|| {
+        // A second Ctrl-C gets out without any more questions.
+        if HANDLING.swap(true, Ordering::SeqCst) {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+
+        // On a thread of its own, so that the above can still happen while we
+        // wait for an answer.
+        thread::spawn(report_and_exit);
+    })
+    .expect("Could not install Ctrl-C handler");
+}
+
+// Marks the start of `phase` of an operation on `device_name`, with nothing
+// offered to recover from it unless `offer` is called too.
+pub fn enter(device_name: &str, phase: Phase) {
+    let mut state = STATE.lock().unwrap();
+
+    if state.device_name.as_deref() != Some(device_name) {
+        state.device_name = Some(device_name.to_owned());
+        state.last_seen = None;
+        state.schema = None;
+    }
+    state.phase = Some(phase);
+    state.recovery = None;
+}
+
+pub fn offer(recovery: Recovery) {
+    STATE.lock().unwrap().recovery = Some(recovery);
+}
+
+// Once the operation is over, interrupting just exits as usual.
+pub fn leave() {
+    let mut state = STATE.lock().unwrap();
+
+    state.phase = None;
+    state.recovery = None;
+    state.publisher = None;
+}
+
+pub fn attach(publisher: Option<Box<dyn Publisher>>) {
+    STATE.lock().unwrap().publisher = publisher;
+}
+
+// The device lock we hold, to be released if we're interrupted.
+pub fn hold_lock(topic: Option<String>) {
+    STATE.lock().unwrap().lock_topic = topic;
+}
+
+pub fn seen_status(status: model::DeviceState) {
+    seen(status.to_string());
+}
+
+pub fn seen_id(id: &decode::DecodedIdMessage) {
+    STATE.lock().unwrap().schema = Some(id.msg.schema);
+    seen(match &id.ota_info.running {
+        None => "up, on an unknown partition".to_owned(),
+        Some(running) => {
+            let part = match running.part {
+                decode::RunningOnPart::Factory => "factory".to_owned(),
+                decode::RunningOnPart::Ota { id } => format!("ota_{}", id),
+            };
+            format!("running {} ({})", part, running.ota_state)
+        }
+    });
+}
+
+fn seen(what: String) {
+    STATE.lock().unwrap().last_seen = Some(Seen {
+        at: Instant::now(),
+        what,
+    });
+}
+
+fn report_and_exit() {
+    // Held until we exit, so the operation can't move on underneath us.
+    let mut state = STATE.lock().unwrap();
+
+    let (device_name, phase) = match (state.device_name.clone(), state.phase) {
+        (Some(device_name), Some(phase)) => (device_name, phase),
+        _ => std::process::exit(EXIT_INTERRUPTED),
+    };
+
+    sayln!();
+    sayln!("{}: Stopped while {}.", PrettyHeader::Interrupted, phase);
+    sayln!("{}", phase.outcome());
+
+    match &state.last_seen {
+        None => sayln!("Device '{}' was not seen.", device_name),
+        Some(seen) => sayln!(
+            "Device '{}' was last seen {}, {} ago.",
+            device_name,
+            seen.what,
+            decode::format_age(seen.at.elapsed())
+        ),
+    }
+
+    let publisher = state.publisher.take();
+
+    if let (Some(recovery), Some(publisher)) = (state.recovery, &publisher) {
+        sayln!();
+        if ui::confirm(recovery.question()) {
+            // Recovery is only offered once the device has been seen.
+            let schema = state.schema.unwrap_or(model::LEGACY_SCHEMA_VERSION);
+            recovery.send(&TopicBundle::new(&device_name), schema, publisher.as_ref());
+            sayln!("{} command sent.", recovery);
+        }
+    }
+
+    if let Some(publisher) = publisher {
+        if let Some(topic) = &state.lock_topic {
+            publisher.publish(topic, b"", true);
+        }
+        publisher.disconnect();
+    }
+
+    std::process::exit(EXIT_INTERRUPTED);
+}
@@ -1,34 +1,264 @@
-use console::Term;
+use console::style;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use rumqttc::QoS;
 use single::Single;
+use std::cmp::Ordering;
+use std::str::FromStr;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{collections::HashMap, io, io::Write};
 
-use crate::{data::decode, net::mqtt, op::TopicBundle};
+use crate::{
+    cache,
+    data::{decode, model, report},
+    net::mqtt::MqttPacket,
+    net::transport,
+    op::TopicBundle,
+    ui::{self, OutputFormat},
+};
 
 static INFO_TOPIC_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"hoek/iot/([a-zA-Z0-9-_]+)/_info/([a-zA-Z0-9-_]+)").unwrap());
 
+// The build date, as reported by the `__DATE__` and `__TIME__` macros.
+type BuildDate = (u32, u32, u32, String);
+
+fn parse_build_date(date: &str, time: &str) -> Option<BuildDate> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let mut parts = date.split_whitespace();
+    let month_name = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month_name)? as u32 + 1;
+    let day = parts.next()?.parse().ok()?;
+    let year = parts.next()?.parse().ok()?;
+
+    Some((year, month, day, time.to_owned()))
+}
+
+struct IdSummary {
+    project_name: String,
+    version: String,
+    build_date: Option<BuildDate>,
+    running_ota_state: Option<model::OtaState>,
+}
+
 struct DeviceDisplayInfo {
+    // When the device last published anything, as far as we know. Retained
+    // messages don't say when they were published, so until it publishes live
+    // we only know what the cache tells us.
+    last_seen: Option<SystemTime>,
+    // Whether each part came from the local cache rather than the broker.
+    status_cached: bool,
+    id_cached: bool,
+    status: Option<model::StatusMessage>,
     status_fmt: Option<String>,
+    id: Option<model::IdMessage>,
+    id_summary: Option<IdSummary>,
     id_fmt: Option<String>,
+    // Why the last message from the device couldn't be shown, if it couldn't.
+    warning: Option<String>,
 }
 
 impl DeviceDisplayInfo {
     fn new() -> Self {
         Self {
+            last_seen: None,
+            status_cached: false,
+            id_cached: false,
+            status: None,
             status_fmt: None,
+            id: None,
+            id_summary: None,
             id_fmt: None,
+            warning: None,
         }
     }
 
-    fn integrate_status_fmt(&mut self, status_fmt: String) {
-        self.status_fmt = Some(status_fmt);
+    fn from_cache(cached: cache::CachedDevice) -> Self {
+        let mut info = Self::new();
+
+        if let Some(status) = cached.status {
+            info.last_seen = info.last_seen.max(Some(status.seen_at()));
+            info.status_cached = true;
+            info.integrate_status(status.value);
+        }
+
+        if let Some(id) = cached.id {
+            info.last_seen = info.last_seen.max(Some(id.seen_at()));
+            info.id_cached = true;
+            info.integrate_id(id.value);
+        }
+
+        info
+    }
+
+    fn is_cached(&self) -> bool {
+        self.status_cached || self.id_cached
     }
 
-    fn integrate_id_fmt(&mut self, id_fmt: String) {
-        self.id_fmt = Some(id_fmt);
+    fn integrate_status(&mut self, status: model::StatusMessage) {
+        self.status_fmt = Some(decode::decode_status_message(&status));
+        self.status = Some(status);
+    }
+
+    fn integrate_id(&mut self, id: model::IdMessage) {
+        let id = decode::decode_id_message(id);
+
+        let app_desc = &id.msg.software.app_desc;
+        self.id_summary = Some(IdSummary {
+            project_name: app_desc.project_name.clone(),
+            version: app_desc.version.clone(),
+            build_date: parse_build_date(&app_desc.date, &app_desc.time),
+            running_ota_state: id
+                .ota_info
+                .running
+                .as_ref()
+                .map(|running| running.ota_state.clone()),
+        });
+        self.id_fmt = Some(id.ota_info.fmt);
+        self.id = Some(id.msg);
+    }
+}
+
+pub const DEFAULT_SETTLE: Duration = Duration::from_secs(2);
+
+pub enum Mode {
+    Live,
+    Once { settle: Duration },
+}
+
+pub struct Filter {
+    pub name: Option<Regex>,
+    pub state: Option<model::DeviceState>,
+    pub project: Option<String>,
+    pub version: Option<String>,
+    pub ota_state: Option<model::OtaState>,
+}
+
+impl Filter {
+    fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.state.is_none()
+            && self.project.is_none()
+            && self.version.is_none()
+            && self.ota_state.is_none()
+    }
+
+    fn matches(&self, device_name: &str, info: &DeviceDisplayInfo) -> bool {
+        let state = info.status.as_ref().map(|status| status.state);
+        let summary = info.id_summary.as_ref();
+
+        self.name.as_ref().is_none_or(|re| re.is_match(device_name))
+            && self.state.is_none_or(|s| state == Some(s))
+            && self
+                .project
+                .as_ref()
+                .is_none_or(|p| summary.is_some_and(|id| id.project_name == *p))
+            && self
+                .version
+                .as_ref()
+                .is_none_or(|v| summary.is_some_and(|id| id.version == *v))
+            && self
+                .ota_state
+                .as_ref()
+                .is_none_or(|s| summary.is_some_and(|id| id.running_ota_state.as_ref() == Some(s)))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SortKey {
+    Name,
+    Version,
+    BuildDate,
+    LastSeen,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(SortKey::Name),
+            "version" => Ok(SortKey::Version),
+            "date" => Ok(SortKey::BuildDate),
+            "last-seen" => Ok(SortKey::LastSeen),
+            _ => Err(format!("unknown sort key '{}'", s)),
+        }
+    }
+}
+
+pub struct Order {
+    pub key: SortKey,
+    pub reverse: bool,
+}
+
+impl Order {
+    fn compare(
+        &self,
+        (a_name, a): &(&String, &DeviceDisplayInfo),
+        (b_name, b): &(&String, &DeviceDisplayInfo),
+    ) -> Ordering {
+        // Devices which haven't reported the sort field always go last, even
+        // when the rest are reversed, so this returns that ordering apart.
+        fn by<T>(
+            a: Option<T>,
+            b: Option<T>,
+            cmp: impl Fn(&T, &T) -> Ordering,
+        ) -> (Ordering, Ordering) {
+            match (a, b) {
+                (Some(a), Some(b)) => (Ordering::Equal, cmp(&a, &b)),
+                (a, b) => (a.is_none().cmp(&b.is_none()), Ordering::Equal),
+            }
+        }
+
+        fn summary(info: &DeviceDisplayInfo) -> Option<&IdSummary> {
+            info.id_summary.as_ref()
+        }
+
+        let (missing, ord) = match self.key {
+            SortKey::Name => (Ordering::Equal, Ordering::Equal),
+            SortKey::Version => by(
+                summary(a).map(|id| id.version.as_str()),
+                summary(b).map(|id| id.version.as_str()),
+                |a, b| compare_versions(a, b),
+            ),
+            SortKey::BuildDate => by(
+                summary(a).and_then(|id| id.build_date.as_ref()),
+                summary(b).and_then(|id| id.build_date.as_ref()),
+                |a, b| a.cmp(b),
+            ),
+            // Most recently seen first.
+            SortKey::LastSeen => by(a.last_seen, b.last_seen, |a, b| b.cmp(a)),
+        };
+
+        let ord = ord.then_with(|| a_name.cmp(b_name));
+
+        missing.then(if self.reverse { ord.reverse() } else { ord })
+    }
+}
+
+// Compares dot-separated components numerically where both are numbers (so
+// that 1.10 comes after 1.9), and as strings otherwise.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a_parts = a.split('.');
+    let mut b_parts = b.split('.');
+
+    loop {
+        let ord = match (a_parts.next(), b_parts.next()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a), Some(b)) => match (a.parse::<u64>(), b.parse::<u64>()) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                _ => a.cmp(b),
+            },
+        };
+
+        if ord != Ordering::Equal {
+            return ord;
+        }
     }
 }
 
@@ -36,27 +266,61 @@ const FIRST_INDENT: &str = "  * ";
 const SECOND_INDENT: &str = "    ";
 const NL_INDENT: &str = "\n    ";
 
-fn print_device_display_info(devs: &HashMap<String, DeviceDisplayInfo>) -> usize {
-    let mut devs_sorted: Vec<(&String, &DeviceDisplayInfo)> = devs.iter().collect();
-    devs_sorted.sort_by_key(|(s, _)| *s);
+fn select_devices<'a>(
+    devs: &'a HashMap<String, DeviceDisplayInfo>,
+    filter: &Filter,
+    order: &Order,
+) -> Vec<(&'a String, &'a DeviceDisplayInfo)> {
+    let mut devs_sorted: Vec<(&String, &DeviceDisplayInfo)> = devs
+        .iter()
+        .filter(|(device_name, info)| filter.matches(device_name, info))
+        .collect();
+    devs_sorted.sort_by(|a, b| order.compare(a, b));
+    devs_sorted
+}
 
+fn print_device_display_info(devs: &[(&String, &DeviceDisplayInfo)]) -> usize {
     let mut lines_printed = 1;
 
     println!();
-    for (device_name, info) in devs_sorted {
+    for (device_name, info) in devs {
         print!("{}{}: ", FIRST_INDENT, device_name);
 
         if let Some(status_fmt) = &info.status_fmt {
-            println!("{}", status_fmt.replace("\n", NL_INDENT));
-            lines_printed += 1 + status_fmt.chars().filter(|c| *c == '\n').count();
-        } else {
-            println!();
+            print!("{}", status_fmt.replace("\n", NL_INDENT));
+            lines_printed += status_fmt.chars().filter(|c| *c == '\n').count();
+        }
+
+        if let (true, Some(last_seen)) = (info.is_cached(), info.last_seen) {
+            let age = SystemTime::now()
+                .duration_since(last_seen)
+                .unwrap_or_default();
+            print!(
+                " {}",
+                style(format!(
+                    "(cached, last seen {} ago)",
+                    decode::format_age(age)
+                ))
+                .dim()
+            );
+        }
+
+        println!();
+        lines_printed += 1;
+
+        if let Some(warning) = &info.warning {
+            println!("{}{}", SECOND_INDENT, style(warning).yellow());
             lines_printed += 1;
         }
 
         if let Some(id_fmt) = &info.id_fmt {
-            println!("{}{}", SECOND_INDENT, id_fmt.replace("\n", NL_INDENT));
             lines_printed += 1 + id_fmt.chars().filter(|c| *c == '\n').count();
+            let id_fmt = id_fmt.replace("\n", NL_INDENT);
+            if info.id_cached {
+                println!("{}{}", SECOND_INDENT, style(id_fmt).dim());
+            } else {
+                println!("{}{}", SECOND_INDENT, id_fmt);
+            }
         }
 
         println!();
@@ -66,64 +330,337 @@ fn print_device_display_info(devs: &HashMap<String, DeviceDisplayInfo>) -> usize
     lines_printed
 }
 
-pub fn perform() -> ! {
+fn emit_device_reports(devs: &[(&String, &DeviceDisplayInfo)]) {
+    let reports: Vec<report::DeviceReport> = devs
+        .iter()
+        .map(|(device_name, info)| {
+            let mut report = report::DeviceReport::new(
+                device_name,
+                info.status.as_ref().map(|status| status.state),
+                info.id.as_ref(),
+            );
+            report.cached = info.is_cached();
+            report.last_seen = info
+                .last_seen
+                .and_then(|last_seen| last_seen.duration_since(UNIX_EPOCH).ok())
+                .map(|t| t.as_secs());
+            report.warning = info.warning.as_deref();
+            report
+        })
+        .collect();
+
+    match ui::output_format() {
+        OutputFormat::Jsonl => reports.iter().for_each(ui::emit),
+        _ => ui::emit(&reports),
+    }
+}
+
+// Returns the name of the device which the message was about, unless there was
+// nothing to integrate.
+fn integrate_message(
+    devs: &mut HashMap<String, DeviceDisplayInfo>,
+    msg: MqttPacket,
+) -> Option<String> {
+    let captures = INFO_TOPIC_REGEX.captures_iter(&msg.topic).single().unwrap();
+
+    assert!(captures.len() == 3);
+
+    let device_name = captures.get(1).unwrap().as_str();
+    let suffix = captures.get(2).unwrap().as_str();
+
+    // What's left behind when a retained message is cleared, e.g. by `forget`.
+    if msg.payload.is_empty() {
+        return None;
+    }
+
+    let dev = devs
+        .entry(device_name.to_owned())
+        .or_insert_with(DeviceDisplayInfo::new);
+    if !msg.retain {
+        dev.last_seen = Some(SystemTime::now());
+    }
+
+    match suffix {
+        "status" => match msg.parse() {
+            Ok(status) => {
+                cache::store_status(device_name, &status);
+                dev.status_cached = false;
+                dev.warning = None;
+                dev.integrate_status(status);
+            }
+            Err(e) => dev.warning = Some(format!("Unreadable status message: {}", e)),
+        },
+        "id" => match decode::parse_id_message(&msg.payload, msg.content_type) {
+            Ok(id) => {
+                cache::store_id(device_name, &id);
+                dev.id_cached = false;
+                dev.warning = None;
+                dev.integrate_id(id);
+            }
+            Err(e) => dev.warning = Some(format!("Unreadable id message: {}", e)),
+        },
+        _ => panic!("unknown suffix {}", suffix),
+    };
+
+    Some(device_name.to_owned())
+}
+
+pub fn perform(mode: Mode, filter: Filter, order: Order) -> ! {
     let topics = TopicBundle::new("+");
 
-    let term = Term::stdout();
+    let format = ui::output_format();
 
-    println!("Connecting to broker...");
+    sayln!("Connecting to broker...");
 
-    let (mut client, rx) = mqtt::connect();
+    let mut transport = transport::connect();
 
-    term.clear_last_lines(1).unwrap();
-    println!("Listing discovered devices...");
-    decode::print_parts_legend();
+    ui::clear_last_lines(1);
+    sayln!("Listing discovered devices...");
+    if !format.is_machine() {
+        decode::print_parts_legend();
+    }
 
-    client
-        .subscribe(topics.info_status, QoS::ExactlyOnce)
-        .unwrap();
+    transport.subscribe(&topics.info_status);
+    transport.subscribe(&topics.info_id);
 
-    client.subscribe(topics.info_id, QoS::ExactlyOnce).unwrap();
+    // Start from whatever we last saw, so that devices whose retained messages
+    // have gone missing still show up.
+    let mut devs: HashMap<String, DeviceDisplayInfo> = cache::load_all()
+        .into_iter()
+        .map(|(device_name, cached)| (device_name, DeviceDisplayInfo::from_cache(cached)))
+        .collect();
 
-    let mut devs: HashMap<String, DeviceDisplayInfo> = HashMap::new();
-    let mut last_line_count = 0;
+    match mode {
+        Mode::Live => {
+            let mut last_line_count = 0;
 
-    loop {
-        let msg = rx.recv().unwrap();
+            loop {
+                let device_name = match integrate_message(&mut devs, transport.recv().unwrap()) {
+                    Some(device_name) => device_name,
+                    None => continue,
+                };
 
-        let captures = INFO_TOPIC_REGEX
-            .captures_iter(&msg.topic)
-            .into_iter()
-            .single()
-            .unwrap();
-
-        assert!(captures.len() == 3);
-
-        let device_name = captures.get(1).unwrap().as_str();
-        let suffix = captures.get(2).unwrap().as_str();
-
-        let dev = devs
-            .entry(device_name.to_owned())
-            .or_insert_with(DeviceDisplayInfo::new);
-
-        match suffix {
-            "status" => dev.integrate_status_fmt(decode::decode_status_message(
-                &serde_json::from_str(&msg.payload).expect("JSON parse error"),
-            )),
-            "id" => dev.integrate_id_fmt(
-                decode::decode_id_message(
-                    serde_json::from_str(&msg.payload).expect("JSON parse error"),
-                )
-                .ota_info
-                .fmt,
-            ),
-            _ => panic!("unknown suffix {}", suffix),
+                if format.is_machine() {
+                    // Stream each device's updated state as it comes in.
+                    if filter.matches(&device_name, &devs[&device_name]) {
+                        emit_device_reports(&[(&device_name, &devs[&device_name])]);
+                    }
+                    continue;
+                }
+
+                if ui::is_plain() {
+                    // We can't redraw the table, so just append each update.
+                    if filter.matches(&device_name, &devs[&device_name]) {
+                        print_device_display_info(&[(&device_name, &devs[&device_name])]);
+                    }
+                    continue;
+                }
+
+                ui::clear_last_lines(last_line_count);
+                last_line_count =
+                    print_device_display_info(&select_devices(&devs, &filter, &order));
+
+                print!("<Press Ctrl-C to stop live updates>");
+                io::stdout().flush().unwrap();
+            }
+        }
+        Mode::Once { settle } => {
+            // Retained messages arrive in a burst just after subscribing, so
+            // we just collect everything which shows up in the settle window.
+            let deadline = Instant::now() + settle;
+
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+
+                match transport.recv_timeout(deadline - now) {
+                    Ok(msg) => {
+                        integrate_message(&mut devs, msg);
+                    }
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        sayln!("Lost connection to broker!");
+                        std::process::exit(-1);
+                    }
+                }
+            }
+
+            let devs_shown = select_devices(&devs, &filter, &order);
+            if format.is_machine() {
+                emit_device_reports(&devs_shown);
+            } else {
+                print_device_display_info(&devs_shown);
+            }
+
+            if devs_shown.is_empty() && !filter.is_empty() {
+                sayln!("No matching devices found.");
+                std::process::exit(-1);
+            }
+
+            std::process::exit(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::payload;
+
+    // Integrating messages caches them, which mustn't touch the real cache.
+    fn isolate_cache() {
+        let test = std::thread::current()
+            .name()
+            .unwrap_or("main")
+            .replace("::", "-");
+        let dir = std::env::temp_dir().join("iota-tests").join(test);
+        let _ = std::fs::remove_dir_all(&dir);
+        cache::isolate(dir);
+    }
+
+    fn message(topic: &str, payload: &[u8]) -> MqttPacket {
+        MqttPacket {
+            topic: topic.to_owned(),
+            payload: payload.to_vec(),
+            content_type: payload::sniff(payload),
+            retain: true,
+        }
+    }
+
+    #[test]
+    fn cleared_messages_are_skipped() {
+        isolate_cache();
+        let mut devs = HashMap::new();
+
+        let name = integrate_message(&mut devs, message("hoek/iot/dev/_info/status", b""));
+
+        assert_eq!(name, None);
+        assert!(devs.is_empty());
+    }
+
+    #[test]
+    fn unreadable_messages_warn_per_device() {
+        isolate_cache();
+        let mut devs = HashMap::new();
+
+        for (suffix, payload) in [("status", &b"{\"state\":"[..]), ("id", b"\xff\x00")] {
+            let topic = format!("hoek/iot/dev-{}/_info/{}", suffix, suffix);
+            let name = integrate_message(&mut devs, message(&topic, payload));
+            assert_eq!(name.as_deref(), Some(&*format!("dev-{}", suffix)));
+        }
+
+        assert!(devs["dev-status"].status.is_none());
+        assert!(devs["dev-status"]
+            .warning
+            .as_ref()
+            .unwrap()
+            .contains("status"));
+        assert!(devs["dev-id"].id.is_none());
+        assert!(devs["dev-id"].warning.as_ref().unwrap().contains("id"));
+    }
+
+    // A device which reported `version`, built on `date`, and last seen `ago`
+    // seconds ago (where any of it is known).
+    fn device(version: Option<&str>, date: Option<&str>, ago: Option<u64>) -> DeviceDisplayInfo {
+        let mut info = DeviceDisplayInfo::new();
+        info.last_seen = ago.map(|ago| SystemTime::now() - Duration::from_secs(ago));
+        info.id_summary = version.map(|version| IdSummary {
+            project_name: "app".to_owned(),
+            version: version.to_owned(),
+            build_date: date.and_then(|date| parse_build_date(date, "12:00:00")),
+            running_ota_state: None,
+        });
+        info
+    }
+
+    fn sorted(devs: &HashMap<String, DeviceDisplayInfo>, key: SortKey, reverse: bool) -> Vec<&str> {
+        let filter = Filter {
+            name: None,
+            state: None,
+            project: None,
+            version: None,
+            ota_state: None,
         };
 
-        term.clear_last_lines(last_line_count).unwrap();
-        last_line_count = print_device_display_info(&devs);
+        select_devices(devs, &filter, &Order { key, reverse })
+            .into_iter()
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn versions_compare_by_component() {
+        for (a, b) in [
+            ("1.9", "1.10"),
+            ("1.2", "1.2.1"),
+            ("1.2.9", "2.0"),
+            // Not semver, but still compared component by component.
+            ("v1.9", "v1.10"),
+            ("1.0", "1.0-rc1"),
+            ("1.x", "1.y"),
+            ("2021-02-28", "2021-03-01"),
+            ("", "0"),
+        ] {
+            assert_eq!(compare_versions(a, b), Ordering::Less, "{} < {}", a, b);
+            assert_eq!(compare_versions(b, a), Ordering::Greater, "{} > {}", b, a);
+        }
+
+        assert_eq!(compare_versions("1.2", "1.2"), Ordering::Equal);
+        // Numerically equal, so only told apart to keep the order total.
+        assert_eq!(compare_versions("1.02", "1.2"), Ordering::Less);
+    }
+
+    #[test]
+    fn each_order_sorts_and_reverses() {
+        let devs: HashMap<String, DeviceDisplayInfo> = vec![
+            ("a", device(Some("1.10.0"), Some("Mar  1 2021"), None)),
+            ("b", device(Some("1.9.0"), Some("Jan  5 2022"), Some(60))),
+            ("c", device(None, None, Some(5))),
+            ("d", device(Some("2.0.0"), Some("Dec 31 2020"), Some(3600))),
+        ]
+        .into_iter()
+        .map(|(name, info)| (name.to_owned(), info))
+        .collect();
 
-        print!("<Press Ctrl-C to stop live updates>");
-        io::stdout().flush().unwrap();
+        assert_eq!(sorted(&devs, SortKey::Name, false), ["a", "b", "c", "d"]);
+        assert_eq!(sorted(&devs, SortKey::Name, true), ["d", "c", "b", "a"]);
+
+        // Devices which didn't report the sort field go last either way.
+        assert_eq!(sorted(&devs, SortKey::Version, false), ["b", "a", "d", "c"]);
+        assert_eq!(sorted(&devs, SortKey::Version, true), ["d", "a", "b", "c"]);
+
+        assert_eq!(
+            sorted(&devs, SortKey::BuildDate, false),
+            ["d", "a", "b", "c"]
+        );
+        assert_eq!(
+            sorted(&devs, SortKey::BuildDate, true),
+            ["b", "a", "d", "c"]
+        );
+
+        assert_eq!(
+            sorted(&devs, SortKey::LastSeen, false),
+            ["c", "b", "d", "a"]
+        );
+        assert_eq!(sorted(&devs, SortKey::LastSeen, true), ["d", "b", "c", "a"]);
+    }
+
+    #[test]
+    fn only_live_messages_count_as_seen() {
+        isolate_cache();
+        let mut devs = HashMap::new();
+        let status = br#"{"state":"up"}"#;
+
+        integrate_message(&mut devs, message("hoek/iot/dev/_info/status", status));
+        assert_eq!(devs["dev"].last_seen, None);
+
+        let live = MqttPacket {
+            retain: false,
+            ..message("hoek/iot/dev/_info/status", status)
+        };
+        integrate_message(&mut devs, live);
+        assert!(devs["dev"].last_seen.is_some());
     }
 }
@@ -0,0 +1,178 @@
+// Advisory per-device locking, so that two people can't run conflicting
+// operations (e.g. `iota ota` and `iota rollback`) against a device at once.
+// The lock is a message retained on the device's `_lock` topic, which every
+// mutating operation takes before sending anything, and clears when done.
+
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{interrupt, PrettyHeader, TOPIC_PREFIX};
+use crate::data::{decode::format_age, model};
+use crate::net::{mqtt, transport::Transport};
+
+// Long enough for an OTA update over a slow link, after which an abandoned
+// lock (e.g. from a crash) no longer gets in anyone's way.
+pub const LOCK_TTL: Duration = Duration::from_secs(10 * 60);
+
+// How long to wait for the broker to hand over a retained lock, if any.
+const SETTLE: Duration = Duration::from_millis(500);
+
+pub struct Lock {
+    topic: String,
+}
+
+pub fn lock_topic(device_name: &str) -> String {
+    TOPIC_PREFIX.to_owned() + device_name + "/_lock"
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+fn owner() -> String {
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_owned());
+
+    format!("{}@{}", user, mqtt::hostname())
+}
+
+// The last lock we're sent before things go quiet, if it wasn't cleared.
+fn read_lock(transport: &mut dyn Transport, topic: &str) -> Option<model::LockMessage> {
+    let mut lock = None;
+
+    while let Ok(msg) = transport.recv_timeout(SETTLE) {
+        if msg.topic == topic {
+            // Anything unreadable can't be holding the device for anyone.
+            lock = msg.parse().ok();
+        }
+    }
+
+    lock
+}
+
+// Takes the lock on `device_name` for `op`, or prints who holds it and returns
+// `None`. A stale lock is taken over, and a live one only with `force`.
+pub fn acquire(
+    transport: &mut dyn Transport,
+    device_name: &str,
+    op: &str,
+    force: bool,
+) -> Option<Lock> {
+    let topic = lock_topic(device_name);
+    transport.subscribe(&topic);
+
+    let now = unix_now();
+
+    if let Some(held) = read_lock(transport, &topic) {
+        if held.expires <= now {
+            sayln!(
+                "Taking over stale lock on device '{}', held by {} for `{}` (expired {} ago).",
+                device_name,
+                held.owner,
+                held.op,
+                format_age(Duration::from_secs(now - held.expires))
+            );
+        } else if force {
+            sayln!(
+                "Overriding lock on device '{}', held by {} for `{}`.",
+                device_name,
+                held.owner,
+                held.op
+            );
+        } else {
+            sayln!(
+                "{}: Device '{}' is locked by {} for `{}` (client {}, expires in {})!",
+                PrettyHeader::Failed,
+                device_name,
+                held.owner,
+                held.op,
+                held.client_id,
+                format_age(Duration::from_secs(held.expires - now))
+            );
+            sayln!("Use `--force` to override the lock.");
+
+            return None;
+        }
+    }
+
+    let ours = model::LockMessage {
+        owner: owner(),
+        client_id: mqtt::client_id().to_owned(),
+        op: op.to_owned(),
+        expires: now + LOCK_TTL.as_secs(),
+    };
+
+    transport.publish(
+        &topic,
+        serde_json::to_string(&ours)
+            .expect("Could not build JSON")
+            .as_bytes(),
+        true,
+    );
+
+    // If someone else took the lock at the same time, the broker sends us both
+    // in the same order it sends them to everyone else, and the last one wins.
+    match read_lock(transport, &topic) {
+        Some(winner) if winner.client_id != ours.client_id => {
+            sayln!(
+                "{}: Device '{}' was locked by {} for `{}` at the same time!",
+                PrettyHeader::Failed,
+                device_name,
+                winner.owner,
+                winner.op
+            );
+
+            None
+        }
+        _ => {
+            interrupt::hold_lock(Some(topic.clone()));
+            Some(Lock { topic })
+        }
+    }
+}
+
+impl Lock {
+    pub fn release(self, transport: &mut dyn Transport) {
+        transport.publish(&self.topic, b"", true);
+        interrupt::hold_lock(None);
+    }
+
+    // Keeps the lock until the returned guard is dropped, however the
+    // operation using `transport` through it ends (including by panicking).
+    pub fn hold(self, transport: &mut dyn Transport) -> Held<'_> {
+        Held {
+            transport,
+            lock: Some(self),
+        }
+    }
+}
+
+pub struct Held<'a> {
+    transport: &'a mut dyn Transport,
+    lock: Option<Lock>,
+}
+
+impl<'a> Deref for Held<'a> {
+    type Target = dyn Transport + 'a;
+
+    fn deref(&self) -> &Self::Target {
+        self.transport
+    }
+}
+
+impl DerefMut for Held<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.transport
+    }
+}
+
+impl Drop for Held<'_> {
+    fn drop(&mut self) {
+        if let Some(lock) = self.lock.take() {
+            lock.release(self.transport);
+        }
+    }
+}
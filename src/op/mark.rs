@@ -1,11 +1,9 @@
-use rumqttc::QoS;
 use single::Single;
 use std::fmt;
-use std::sync::mpsc::Receiver;
 
 use crate::{
     data::{decode, model},
-    net::mqtt,
+    net::transport::Transport,
     op::{self, PrettyHeader},
 };
 
@@ -25,14 +23,14 @@ impl fmt::Display for Mark {
 }
 
 impl Mark {
-    fn get_ota_command(&self) -> model::OtaCommand {
+    fn get_ota_command(&self) -> model::OtaCommand<'_> {
         match self {
             Mark::Validate => model::OtaCommand::Validate,
             Mark::Rollback => model::OtaCommand::Rollback,
         }
     }
 
-    fn is_acceptable_initial_ota_state(&self, state: model::OtaState) -> bool {
+    fn is_acceptable_initial_ota_state(&self, state: &model::OtaState) -> bool {
         matches!(
             (self, state),
             (Mark::Validate, model::OtaState::PendingVerify)
@@ -45,71 +43,99 @@ impl Mark {
         &self,
         original_id: &decode::DecodedIdMessage,
         current_id: &decode::DecodedIdMessage,
-    ) -> bool {
+    ) -> op::WaitStatus {
+        // We checked that the original id had this before sending any command,
+        // and we just keep waiting if a later report is missing it.
+        let original = original_id.ota_info.running.as_ref().unwrap();
+        let current = match &current_id.ota_info.running {
+            None => return op::WaitStatus::Waiting,
+            Some(current) => current,
+        };
+
         match self {
             Mark::Validate => {
-                if current_id.ota_info.running_addr != original_id.ota_info.running_addr {
-                    panic!(
-                        "unexpectedly running on partition with address 0x{:x}, instead of 0x{:x}",
-                        current_id.ota_info.running_addr, original_id.ota_info.running_addr
-                    );
+                if current.addr != original.addr {
+                    return op::WaitStatus::Failed(format!(
+                        "Device unexpectedly running on partition with address 0x{:x}, instead of 0x{:x}",
+                        current.addr, original.addr
+                    ));
                 }
 
-                match (
-                    current_id.ota_info.running_on_part,
-                    current_id.ota_info.running_ota_state,
-                ) {
-                    (decode::RunningOnPart::Factory, _) => {
-                        panic!("unexpectedly running on factory partition after validate!")
+                match (current.part, &current.ota_state) {
+                    (decode::RunningOnPart::Factory, _) => op::WaitStatus::Failed(
+                        "Device unexpectedly running on factory partition after validate"
+                            .to_owned(),
+                    ),
+                    (decode::RunningOnPart::Ota { .. }, model::OtaState::PendingVerify) => {
+                        op::WaitStatus::Waiting
+                    }
+                    (decode::RunningOnPart::Ota { .. }, model::OtaState::Valid) => {
+                        op::WaitStatus::Finished
                     }
-                    (decode::RunningOnPart::Ota { .. }, model::OtaState::PendingVerify) => false,
-                    (decode::RunningOnPart::Ota { .. }, model::OtaState::Valid) => true,
-                    (decode::RunningOnPart::Ota { .. }, state) => {
-                        panic!(
-                            "unexpectedly running on partition with ota state {:?}",
+                    (decode::RunningOnPart::Ota { .. }, model::OtaState::Unknown(state)) => {
+                        op::WaitStatus::Failed(format!(
+                            "Device reports unknown OTA state '{}'",
                             state
-                        );
+                        ))
                     }
+                    (decode::RunningOnPart::Ota { .. }, state) => op::WaitStatus::Failed(format!(
+                        "Device unexpectedly running on partition with OTA state {:?}",
+                        state
+                    )),
                 }
             }
             Mark::Rollback => {
-                let original_part_current_state = current_id
+                let original_part_current_state = match current_id
                     .msg
                     .software
                     .partitions
                     .list
                     .iter()
-                    .filter(|p| p.address == original_id.ota_info.running_addr)
+                    .filter(|p| p.address == original.addr)
                     .single()
-                    .unwrap();
+                {
+                    Ok(part) => part,
+                    // Likely a garbled report, so wait for the next one.
+                    Err(_) => {
+                        sayln!(
+                            "Device did not report the partition at 0x{:x} exactly once.",
+                            original.addr
+                        );
+                        return op::WaitStatus::Waiting;
+                    }
+                };
 
-                let running_part_has_changed =
-                    current_id.ota_info.running_addr != original_id.ota_info.running_addr;
+                let running_part_has_changed = current.addr != original.addr;
 
-                match original_part_current_state.ota_state {
+                match &original_part_current_state.ota_state {
                     model::OtaState::PendingVerify => {
                         if running_part_has_changed {
-                            panic!(
-                                "unexpectedly running on partition with address 0x{:x}, instead of 0x{:x}, with original partition still in state `PendingVerify`",
-                                current_id.ota_info.running_addr, original_id.ota_info.running_addr);
+                            return op::WaitStatus::Failed(format!(
+                                "Device unexpectedly running on partition with address 0x{:x}, instead of 0x{:x}, with original partition still in state `PendingVerify`",
+                                current.addr, original.addr
+                            ));
                         }
 
-                        false
+                        op::WaitStatus::Waiting
                     }
                     model::OtaState::Invalid => {
                         if !running_part_has_changed {
-                            panic!(
-                                "unexpectedly running on partition original partition after state has changed to `Invalid`");
+                            return op::WaitStatus::Failed(
+                                "Device unexpectedly still running on original partition after its state changed to `Invalid`"
+                                    .to_owned(),
+                            );
                         }
 
-                        true
-                    }
-                    state => {
-                        panic!(
-                            "unexpectedly running on partition with ota state {:?}",
-                            state
-                        );
+                        op::WaitStatus::Finished
                     }
+                    model::OtaState::Unknown(state) => op::WaitStatus::Failed(format!(
+                        "Device reports unknown OTA state '{}'",
+                        state
+                    )),
+                    state => op::WaitStatus::Failed(format!(
+                        "Device unexpectedly reports original partition with OTA state {:?}",
+                        state
+                    )),
                 }
             }
         }
@@ -124,13 +150,18 @@ impl op::Operation for Operation {
     fn perform(
         &self,
         topics: &super::TopicBundle,
-        (client, _): (&mut rumqttc::Client, &Receiver<mqtt::MqttPacket>),
+        transport: &mut dyn Transport,
         id: &decode::DecodedIdMessage,
     ) -> op::ExitDisposition {
-        match id.ota_info.running_on_part {
+        let running = match op::require_running_part(id) {
+            None => return op::ExitDisposition::Abort,
+            Some(running) => running,
+        };
+
+        match running.part {
             decode::RunningOnPart::Ota { .. } => {}
             decode::RunningOnPart::Factory => {
-                println!(
+                sayln!(
                     "{}: Cannot modify OTA state of a factory partition!",
                     op::PrettyHeader::Failed,
                 );
@@ -139,14 +170,24 @@ impl op::Operation for Operation {
             }
         };
 
+        if let model::OtaState::Unknown(state) = &running.ota_state {
+            sayln!(
+                "{}: Device reports unknown OTA state '{}'!",
+                op::PrettyHeader::Failed,
+                state
+            );
+
+            return op::ExitDisposition::Abort;
+        }
+
         if !self
             .mark
-            .is_acceptable_initial_ota_state(id.ota_info.running_ota_state)
+            .is_acceptable_initial_ota_state(&running.ota_state)
         {
-            println!(
+            sayln!(
                 "{}: OTA state of running parition ({:?}) is not acceptable for a {} operation!",
                 op::PrettyHeader::Failed,
-                id.ota_info.running_ota_state,
+                running.ota_state,
                 self.mark
             );
 
@@ -154,7 +195,7 @@ impl op::Operation for Operation {
         }
 
         if !id.msg.software.partitions.is_rollback_possible {
-            println!(
+            sayln!(
                 "{}: Device reports that rollback is not possible!",
                 op::PrettyHeader::Failed,
             );
@@ -162,37 +203,49 @@ impl op::Operation for Operation {
             return op::ExitDisposition::Abort;
         }
 
-        println!("Sending {} command...", self.mark);
+        sayln!("Sending {} command...", self.mark);
 
         // Note that the rollback command actually causes a device restart when it successfully completes.
-        client
-            .publish(
-                &topics.cmd_ota,
-                QoS::ExactlyOnce,
-                false,
-                serde_json::to_string(&self.mark.get_ota_command())
-                    .expect("Could not build JSON")
-                    .as_bytes(),
-            )
-            .unwrap();
-
-        op::ExitDisposition::Ok
+        match op::send_command(
+            topics,
+            transport,
+            id,
+            &topics.cmd_ota,
+            self.mark.get_ota_command(),
+        ) {
+            op::Ack::Rejected => op::ExitDisposition::Abort,
+            op::Ack::Accepted | op::Ack::Missing => op::ExitDisposition::Ok,
+        }
+    }
+
+    fn required_capabilities(&self) -> &'static [decode::Capability] {
+        match self.mark {
+            Mark::Validate => &[decode::Capability::OtaValidate],
+            Mark::Rollback => &[decode::Capability::OtaRollback],
+        }
     }
 
     fn get_wait_strategy(&self) -> Option<op::PostOperationWaitStrategy> {
         Some(op::PostOperationWaitStrategy::IdMessage)
     }
 
+    fn lock_name(&self) -> Option<&'static str> {
+        match self.mark {
+            Mark::Validate => Some("validate"),
+            Mark::Rollback => Some("rollback"),
+        }
+    }
+
     fn exit_ok_is_finished_waiting(
         &self,
         original_id: &decode::DecodedIdMessage,
         current_id: &decode::DecodedIdMessage,
-    ) -> bool {
+    ) -> op::WaitStatus {
         self.mark.is_command_completed(original_id, current_id)
     }
 
     fn print_completed_message(&self) {
-        println!(
+        sayln!(
             "{}: Operation {} (of running partition) successful!",
             PrettyHeader::Success,
             self.mark
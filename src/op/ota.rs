@@ -1,14 +1,11 @@
 use console::style;
-use console::Term;
-use rumqttc::QoS;
 use std::fmt::Display;
-use std::io::{self, Read};
-use std::sync::mpsc::Receiver;
 
 use crate::{
     data::{decode, model},
-    net::mqtt,
-    op,
+    net::transport::Transport,
+    op::{self, interrupt},
+    ui,
 };
 
 impl Display for model::OtaMessage {
@@ -46,76 +43,117 @@ impl op::Operation for Operation<'_> {
     fn perform(
         &self,
         topics: &super::TopicBundle,
-        (client, rx): (&mut rumqttc::Client, &Receiver<mqtt::MqttPacket>),
+        transport: &mut dyn Transport,
         id: &decode::DecodedIdMessage,
     ) -> op::ExitDisposition {
-        let term = Term::stdout();
+        let running = match op::require_running_part(id) {
+            None => return op::ExitDisposition::Abort,
+            Some(running) => running,
+        };
 
-        match (id.ota_info.running_on_part, id.ota_info.running_ota_state) {
+        match (running.part, &running.ota_state) {
             (_, model::OtaState::PendingVerify) => {
-                println!(
+                sayln!(
                     "{}: Device reports OTA update already pending!",
                     op::PrettyHeader::Failed
                 );
-                println!("Use `iota validate` or `iota rollback` to clear this status (use Ctrl-C to abort the current operation).");
-                println!();
-                println!("<Press any key to restart device and retry>");
-                io::stdin().read_exact(&mut [0]).unwrap();
+                sayln!("Use `iota validate` or `iota rollback` to clear this status (use Ctrl-C to abort the current operation).");
+                sayln!();
+                sayln!("<Press any key to restart device and retry>");
+                interrupt::enter(&topics.device_name, interrupt::Phase::Confirming);
+                interrupt::offer(interrupt::Recovery::Rollback);
+                ui::wait_for_key();
 
-                client
-                    .publish(&topics.cmd_restart, QoS::ExactlyOnce, false, "")
-                    .unwrap();
+                if op::send_command(
+                    topics,
+                    transport,
+                    id,
+                    &topics.cmd_restart,
+                    model::RestartCommand {},
+                ) == op::Ack::Rejected
+                {
+                    return op::ExitDisposition::Abort;
+                }
 
-                println!("Restart command sent...");
+                sayln!("Restart command sent...");
 
                 return op::ExitDisposition::Retry;
             }
             (decode::RunningOnPart::Factory, model::OtaState::NotPresent)
             | (decode::RunningOnPart::Ota { .. }, model::OtaState::Valid)
             | (decode::RunningOnPart::Ota { .. }, model::OtaState::Undefined) => {}
-            state => {
-                panic!(
-                    "running on partition with unexpected OTA state: {:?}",
+            (_, model::OtaState::Unknown(state)) => {
+                sayln!(
+                    "{}: Device reports unknown OTA state '{}'!",
+                    op::PrettyHeader::Failed,
                     state
                 );
+
+                return op::ExitDisposition::Abort;
+            }
+            (_, state) => {
+                sayln!(
+                    "{}: Device running on partition with unexpected OTA state {:?}!",
+                    op::PrettyHeader::Failed,
+                    state
+                );
+
+                return op::ExitDisposition::Abort;
             }
         };
 
-        println!("Sending OTA command...");
-
-        client
-            .publish(
-                &topics.cmd_ota,
-                QoS::ExactlyOnce,
-                false,
-                serde_json::to_string(&model::OtaCommand::Update {
-                    url: self.url,
-                    ca_cert: self.ca_cert,
-                })
-                .expect("Could not build JSON")
-                .as_bytes(),
-            )
-            .unwrap();
-
-        term.clear_last_lines(1).unwrap();
-        println!("OTA command sent, listening for updates...");
+        if id.ota_info.next_update_addr.is_none() {
+            sayln!(
+                "{}: Device reports no free OTA partition for upload!",
+                op::PrettyHeader::Failed
+            );
+
+            return op::ExitDisposition::Abort;
+        }
+
+        sayln!("Sending OTA command...");
+
+        if op::send_command(
+            topics,
+            transport,
+            id,
+            &topics.cmd_ota,
+            model::OtaCommand::Update {
+                url: self.url,
+                ca_cert: self.ca_cert,
+            },
+        ) == op::Ack::Rejected
+        {
+            return op::ExitDisposition::Abort;
+        }
+
+        interrupt::enter(&topics.device_name, interrupt::Phase::Flashing);
+        interrupt::offer(interrupt::Recovery::Restart);
+
+        ui::clear_last_lines(1);
+        sayln!("OTA command sent, listening for updates...");
 
         loop {
-            let msg = rx.recv().unwrap();
+            let msg = transport.recv().unwrap();
 
             if msg.topic == topics.info_ota {
-                let ota_state: model::OtaMessage =
-                    serde_json::from_str(&msg.payload).expect("JSON parse error");
+                let ota_state: model::OtaMessage = match msg.parse() {
+                    Ok(ota_state) => ota_state,
+                    Err(e) => {
+                        op::warn_unreadable("OTA progress message", &e);
+                        continue;
+                    }
+                };
 
-                println!("  ota: {}", ota_state);
+                sayln!("  ota: {}", ota_state);
 
                 match ota_state {
                     model::OtaMessage::Done => {
-                        println!("OTA upload complete, restarting device...");
+                        sayln!("OTA upload complete, restarting device...");
                         break;
                     }
                     state if state.is_terminal() => {
-                        println!("OTA upload failed, aborting");
+                        sayln!("OTA upload failed, aborting");
                         return op::ExitDisposition::Abort;
                     }
                     _ => {}
@@ -123,43 +161,68 @@ impl op::Operation for Operation<'_> {
             }
 
             if msg.topic == topics.info_error {
-                println!("{} ({})", style("Log Error").red(), msg.payload);
+                sayln!("{} ({})", style("Log Error").red(), msg.payload_text());
             }
         }
 
-        client
-            .publish(&topics.cmd_restart, QoS::ExactlyOnce, false, "")
-            .unwrap();
+        // The new firmware has to report in anyway, to tell whether it booted.
+        if op::send_command(
+            topics,
+            transport,
+            id,
+            &topics.cmd_restart,
+            model::RestartCommand {},
+        ) == op::Ack::Rejected
+        {
+            return op::ExitDisposition::Abort;
+        }
 
         op::ExitDisposition::Ok
     }
 
+    fn required_capabilities(&self) -> &'static [decode::Capability] {
+        &[decode::Capability::OtaUpdate, decode::Capability::Restart]
+    }
+
     fn get_wait_strategy(&self) -> Option<op::PostOperationWaitStrategy> {
         Some(op::PostOperationWaitStrategy::IdMessage)
     }
 
+    fn lock_name(&self) -> Option<&'static str> {
+        Some("ota")
+    }
+
     fn exit_ok_is_finished_waiting(
         &self,
         original_id: &decode::DecodedIdMessage,
         current_id: &decode::DecodedIdMessage,
-    ) -> bool {
-        match current_id.ota_info.running_on_part {
-            decode::RunningOnPart::Factory => {
-                panic!("unexpectedly running on factory partition after OTA!")
-            }
+    ) -> op::WaitStatus {
+        // Both of these were checked before the OTA command was sent.
+        let original = original_id.ota_info.running.as_ref().unwrap();
+        let next_update_addr = original_id.ota_info.next_update_addr.unwrap();
+
+        let current = match &current_id.ota_info.running {
+            None => return op::WaitStatus::Waiting,
+            Some(current) => current,
+        };
+
+        match current.part {
+            decode::RunningOnPart::Factory => op::WaitStatus::Failed(
+                "Device unexpectedly running on factory partition after OTA".to_owned(),
+            ),
             decode::RunningOnPart::Ota { .. } => {
-                if current_id.ota_info.running_addr == original_id.ota_info.running_addr {
-                    return false;
+                if current.addr == original.addr {
+                    return op::WaitStatus::Waiting;
                 }
 
-                if current_id.ota_info.running_addr == original_id.ota_info.next_update_addr {
-                    return true;
+                if current.addr == next_update_addr {
+                    return op::WaitStatus::Finished;
                 }
 
-                panic!(
-                    "unexpectedly running on partition with address 0x{:x}, instead of 0x{:x}",
-                    current_id.ota_info.running_addr, original_id.ota_info.next_update_addr
-                );
+                op::WaitStatus::Failed(format!(
+                    "Device unexpectedly running on partition with address 0x{:x}, instead of 0x{:x}",
+                    current.addr, next_update_addr
+                ))
             }
         }
     }
@@ -168,16 +231,26 @@ impl op::Operation for Operation<'_> {
         &self,
         _: &decode::DecodedIdMessage,
         current_id: &decode::DecodedIdMessage,
-    ) -> bool {
-        current_id.ota_info.running_ota_state != model::OtaState::PendingVerify
+    ) -> op::WaitStatus {
+        let finished = current_id
+            .ota_info
+            .running
+            .as_ref()
+            .is_some_and(|running| running.ota_state != model::OtaState::PendingVerify);
+
+        if finished {
+            op::WaitStatus::Finished
+        } else {
+            op::WaitStatus::Waiting
+        }
     }
 
     fn print_completed_message(&self) {
-        println!(
+        sayln!(
             "{}: Device restarted, OTA successful!",
             op::PrettyHeader::Success
         );
-        println!("Use `iota validate <device>` to mark the update as permanent.");
-        println!("Use `iota rollback <device>` to rollback to the previous version.");
+        sayln!("Use `iota validate <device>` to mark the update as permanent.");
+        sayln!("Use `iota rollback <device>` to rollback to the previous version.");
     }
 }
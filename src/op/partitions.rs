@@ -0,0 +1,109 @@
+use serde::Serialize;
+
+use crate::{
+    data::{decode, model, partition_table, report},
+    net::transport::Transport,
+    op, ui,
+};
+
+// Leaves room for the indent, and keeps the map readable on wide terminals.
+const MAX_MAP_WIDTH: usize = 96;
+const INDENT: &str = "  ";
+
+#[derive(Serialize)]
+struct PartitionsReport<'a> {
+    #[serde(flatten)]
+    device: report::DeviceReport<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    differences: Option<Vec<partition_table::Difference>>,
+}
+
+pub struct Operation {
+    pub expect: Option<Vec<partition_table::ExpectedPartition>>,
+}
+
+impl op::Operation for Operation {
+    fn perform(
+        &self,
+        topics: &super::TopicBundle,
+        _: &mut dyn Transport,
+        id: &decode::DecodedIdMessage,
+    ) -> op::ExitDisposition {
+        let partitions = &id.msg.software.partitions;
+        let differences = self
+            .expect
+            .as_ref()
+            .map(|expect| partition_table::compare(expect, &partitions.list));
+        let mismatched = differences
+            .as_ref()
+            .is_some_and(|differences| !differences.is_empty());
+
+        if ui::output_format().is_machine() {
+            ui::emit(&PartitionsReport {
+                device: report::DeviceReport::new(
+                    &topics.device_name,
+                    Some(model::DeviceState::Up),
+                    Some(&id.msg),
+                ),
+                differences,
+            });
+        } else {
+            let flash_size = id.msg.hardware.as_ref().and_then(|hw| hw.flash_size);
+            let width = (ui::term().size().1 as usize)
+                .saturating_sub(INDENT.len())
+                .clamp(16, MAX_MAP_WIDTH);
+
+            sayln!("Partition table:");
+            for line in decode::format_partition_table(partitions).lines() {
+                sayln!("{}{}", INDENT, line);
+            }
+            sayln!();
+            sayln!("Flash map:");
+            for line in decode::format_flash_map(partitions, flash_size, width).lines() {
+                sayln!("{}{}", INDENT, line);
+            }
+            sayln!();
+
+            if let Some(differences) = &differences {
+                sayln!("Differences from expected layout:");
+                if differences.is_empty() {
+                    sayln!("{}none", INDENT);
+                }
+                for difference in differences {
+                    sayln!("{}{}", INDENT, difference);
+                }
+                sayln!();
+            }
+        }
+
+        if mismatched {
+            sayln!(
+                "{}: Device partition table does not match the expected layout!",
+                op::PrettyHeader::Failed
+            );
+            return op::ExitDisposition::Abort;
+        }
+
+        op::ExitDisposition::Ok
+    }
+
+    fn required_capabilities(&self) -> &'static [decode::Capability] {
+        &[]
+    }
+
+    fn get_wait_strategy(&self) -> Option<op::PostOperationWaitStrategy> {
+        None
+    }
+
+    fn exit_ok_is_finished_waiting(
+        &self,
+        _original_id: &super::decode::DecodedIdMessage,
+        _current_id: &super::decode::DecodedIdMessage,
+    ) -> op::WaitStatus {
+        op::WaitStatus::Finished
+    }
+
+    fn print_completed_message(&self) {
+        sayln!("{}: Partition table found!", op::PrettyHeader::Success);
+    }
+}
@@ -0,0 +1,232 @@
+// Checking that a device is actually responsive, rather than just retained as
+// up, by timing how long it takes to answer id requests (see `_cmd/id`).
+
+use serde::Serialize;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+
+use super::{command, require_capabilities, TopicBundle};
+use crate::data::{decode, model};
+use crate::net::transport::{self, Transport};
+use crate::ui;
+
+pub const DEFAULT_COUNT: usize = 4;
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long to wait for the broker to hand over the retained id message, which
+// tells us whether the device can be pinged at all.
+const SETTLE: Duration = Duration::from_millis(500);
+
+pub struct Options {
+    pub count: usize,
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub sent: usize,
+    pub rtts: Vec<Duration>,
+}
+
+impl Stats {
+    pub fn received(&self) -> usize {
+        self.rtts.len()
+    }
+
+    pub fn loss_percent(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+
+        100.0 * (self.sent - self.received()) as f64 / self.sent as f64
+    }
+
+    fn min(&self) -> Option<Duration> {
+        self.rtts.iter().min().copied()
+    }
+
+    fn max(&self) -> Option<Duration> {
+        self.rtts.iter().max().copied()
+    }
+
+    fn avg(&self) -> Option<Duration> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+
+        Some(self.rtts.iter().sum::<Duration>() / self.rtts.len() as u32)
+    }
+}
+
+#[derive(Serialize)]
+struct PingReport<'a> {
+    name: &'a str,
+    sent: usize,
+    received: usize,
+    loss_percent: f64,
+    min_ms: Option<f64>,
+    avg_ms: Option<f64>,
+    max_ms: Option<f64>,
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+fn fmt_ms(d: Option<Duration>) -> String {
+    d.map_or("-".to_owned(), |d| format!("{:.1}", ms(d)))
+}
+
+// The schema the device speaks, if it has an id message retained.
+fn retained_schema(topics: &TopicBundle, transport: &mut dyn Transport) -> Option<u32> {
+    let mut schema = None;
+
+    while let Ok(msg) = transport.recv_timeout(SETTLE) {
+        if msg.topic == topics.info_id {
+            schema = msg
+                .parse::<model::SchemaProbe>()
+                .ok()
+                .map(|probe| probe.schema);
+        }
+    }
+
+    schema
+}
+
+// Waits for the device's answer to an id request, ignoring anything retained.
+fn wait_for_answer(
+    topics: &TopicBundle,
+    transport: &mut dyn Transport,
+    timeout: Duration,
+) -> Result<(), RecvTimeoutError> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let msg = transport.recv_timeout(deadline.saturating_duration_since(Instant::now()))?;
+
+        if msg.topic == topics.info_id && !msg.retain {
+            return Ok(());
+        }
+    }
+}
+
+// Until the next ping, throwing away any late answers to the last one so that
+// they aren't taken for answers to the next. (If the connection was lost, the
+// next wait for an answer finds out.)
+fn wait_out(transport: &mut dyn Transport, interval: Duration) {
+    let deadline = Instant::now() + interval;
+
+    while transport
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        .is_ok()
+    {}
+}
+
+// Pings the device over an already connected `transport`, printing each round
+// trip as it goes. Returns `None` if the device can't be pinged.
+pub fn perform_on(
+    transport: &mut dyn Transport,
+    device_name: &str,
+    options: &Options,
+) -> Option<Stats> {
+    let topics = TopicBundle::new(device_name);
+    transport.subscribe(&topics.info_id);
+
+    // Without an id message retained we can't know, so try anyway.
+    let schema = retained_schema(&topics, transport).unwrap_or(model::SCHEMA_VERSION);
+    if !require_capabilities(schema, &[decode::Capability::IdRefresh]) {
+        return None;
+    }
+
+    sayln!("Pinging device '{}'...", device_name);
+
+    let mut stats = Stats::default();
+
+    for seq in 1..=options.count {
+        if seq > 1 {
+            wait_out(transport, options.interval);
+        }
+
+        transport.publish(
+            &topics.cmd_id,
+            serde_json::to_string(&command(schema, model::IdCommand {}))
+                .expect("Could not build JSON")
+                .as_bytes(),
+            false,
+        );
+        stats.sent += 1;
+        let sent_at = Instant::now();
+
+        match wait_for_answer(&topics, transport, options.timeout) {
+            Ok(()) => {
+                let rtt = sent_at.elapsed();
+                stats.rtts.push(rtt);
+                sayln!(
+                    "Answer from '{}': seq={} time={:.1} ms",
+                    device_name,
+                    seq,
+                    ms(rtt)
+                );
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                sayln!("No answer from '{}': seq={}", device_name, seq);
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                sayln!("Lost connection to broker!");
+                break;
+            }
+        }
+    }
+
+    Some(stats)
+}
+
+fn print_stats(device_name: &str, stats: &Stats) {
+    if ui::output_format().is_machine() {
+        ui::emit(&PingReport {
+            name: device_name,
+            sent: stats.sent,
+            received: stats.received(),
+            loss_percent: stats.loss_percent(),
+            min_ms: stats.min().map(ms),
+            avg_ms: stats.avg().map(ms),
+            max_ms: stats.max().map(ms),
+        });
+        return;
+    }
+
+    sayln!();
+    sayln!(
+        "{} sent, {} answered, {:.0}% loss",
+        stats.sent,
+        stats.received(),
+        stats.loss_percent()
+    );
+    sayln!(
+        "Round trip min/avg/max = {}/{}/{} ms",
+        fmt_ms(stats.min()),
+        fmt_ms(stats.avg()),
+        fmt_ms(stats.max())
+    );
+}
+
+pub fn perform(device_name: &str, options: Options) -> ! {
+    sayln!("Connecting to broker...");
+    let mut transport = transport::connect();
+    ui::clear_last_lines(1);
+
+    let stats = perform_on(&mut *transport, device_name, &options);
+    transport.disconnect();
+
+    match stats {
+        None => std::process::exit(-1),
+        Some(stats) => {
+            print_stats(device_name, &stats);
+
+            // As for `ping`, it's only a failure if nothing came back at all.
+            std::process::exit(if stats.received() > 0 { 0 } else { -1 });
+        }
+    }
+}
@@ -1,7 +1,8 @@
-use rumqttc::QoS;
-use std::sync::mpsc::Receiver;
-
-use crate::{data::decode, net::mqtt, op};
+use crate::{
+    data::{decode, model},
+    net::transport::Transport,
+    op,
+};
 
 pub struct Operation {}
 
@@ -9,31 +10,45 @@ impl op::Operation for Operation {
     fn perform(
         &self,
         topics: &super::TopicBundle,
-        (client, _): (&mut rumqttc::Client, &Receiver<mqtt::MqttPacket>),
-        _: &decode::DecodedIdMessage,
+        transport: &mut dyn Transport,
+        id: &decode::DecodedIdMessage,
     ) -> op::ExitDisposition {
-        println!("Sending restart command...");
-
-        client
-            .publish(&topics.cmd_restart, QoS::ExactlyOnce, false, "")
-            .unwrap();
+        sayln!("Sending restart command...");
+
+        // Even once acknowledged, we want to see the device come back.
+        match op::send_command(
+            topics,
+            transport,
+            id,
+            &topics.cmd_restart,
+            model::RestartCommand {},
+        ) {
+            op::Ack::Rejected => op::ExitDisposition::Abort,
+            op::Ack::Accepted | op::Ack::Missing => op::ExitDisposition::Ok,
+        }
+    }
 
-        op::ExitDisposition::Ok
+    fn required_capabilities(&self) -> &'static [decode::Capability] {
+        &[decode::Capability::Restart]
     }
 
     fn get_wait_strategy(&self) -> Option<op::PostOperationWaitStrategy> {
         Some(op::PostOperationWaitStrategy::PowerCycle)
     }
 
+    fn lock_name(&self) -> Option<&'static str> {
+        Some("restart")
+    }
+
     fn exit_ok_is_finished_waiting(
         &self,
         _original_id: &super::decode::DecodedIdMessage,
         _current_id: &super::decode::DecodedIdMessage,
-    ) -> bool {
-        true
+    ) -> op::WaitStatus {
+        op::WaitStatus::Finished
     }
 
     fn print_completed_message(&self) {
-        println!("{}: Restart completed!", op::PrettyHeader::Success);
+        sayln!("{}: Restart completed!", op::PrettyHeader::Success);
     }
 }
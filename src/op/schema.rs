@@ -0,0 +1,55 @@
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+
+use crate::data::{model, schema};
+
+pub fn export(out_dir: Option<&Path>) {
+    let documents = schema::documents();
+
+    let out_dir = match out_dir {
+        Some(out_dir) => out_dir,
+        None => {
+            let documents: serde_json::Map<String, serde_json::Value> = documents
+                .iter()
+                .map(|doc| {
+                    (
+                        doc.name.to_owned(),
+                        serde_json::to_value(doc).expect("Could not build JSON"),
+                    )
+                })
+                .collect();
+
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&json!({
+                    "schema_version": model::SCHEMA_VERSION,
+                    "documents": documents,
+                }))
+                .expect("Could not build JSON")
+            );
+            return;
+        }
+    };
+
+    fs::create_dir_all(out_dir).expect("could not create output directory");
+
+    for doc in documents.iter() {
+        let schema_path = out_dir.join(format!("{}.schema.json", doc.name));
+        let example_path = out_dir.join(format!("{}.example.json", doc.name));
+
+        fs::write(
+            &schema_path,
+            serde_json::to_string_pretty(&doc.schema).expect("Could not build JSON"),
+        )
+        .expect("could not write schema");
+        fs::write(
+            &example_path,
+            serde_json::to_string_pretty(&doc.example).expect("Could not build JSON"),
+        )
+        .expect("could not write example");
+
+        sayln!("Wrote {}", schema_path.display());
+        sayln!("Wrote {}", example_path.display());
+    }
+}
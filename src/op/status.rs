@@ -1,19 +1,49 @@
-use std::sync::mpsc::Receiver;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::{data::decode, net::mqtt, op};
+use crate::{
+    cache,
+    data::{decode, model, report},
+    net::transport::Transport,
+    op, ui,
+};
 
 pub struct Operation {}
 
 impl op::Operation for Operation {
     fn perform(
         &self,
-        _: &super::TopicBundle,
-        _: (&mut rumqttc::Client, &Receiver<mqtt::MqttPacket>),
-        _: &decode::DecodedIdMessage,
+        topics: &super::TopicBundle,
+        _: &mut dyn Transport,
+        id: &decode::DecodedIdMessage,
     ) -> op::ExitDisposition {
+        if ui::output_format().is_machine() {
+            // We only get here once the device has reported that it is up.
+            let mut report = report::DeviceReport::new(
+                &topics.device_name,
+                Some(model::DeviceState::Up),
+                Some(&id.msg),
+            );
+            report.stale = id.stale;
+            ui::emit(&report);
+        }
+
         op::ExitDisposition::Ok
     }
 
+    fn required_capabilities(&self) -> &'static [decode::Capability] {
+        &[]
+    }
+
+    fn report_down(&self, device_name: &str) {
+        if ui::output_format().is_machine() {
+            ui::emit(&report::DeviceReport::new(
+                device_name,
+                Some(model::DeviceState::Down),
+                None,
+            ));
+        }
+    }
+
     fn get_wait_strategy(&self) -> Option<op::PostOperationWaitStrategy> {
         None
     }
@@ -22,11 +52,70 @@ impl op::Operation for Operation {
         &self,
         _original_id: &super::decode::DecodedIdMessage,
         _current_id: &super::decode::DecodedIdMessage,
-    ) -> bool {
-        true
+    ) -> op::WaitStatus {
+        op::WaitStatus::Finished
     }
 
     fn print_completed_message(&self) {
-        println!("{}: Device status found!", op::PrettyHeader::Success);
+        sayln!("{}: Device status found!", op::PrettyHeader::Success);
     }
 }
+
+// Report whatever we last saw of the device, without connecting to the broker.
+pub fn perform_offline(device_name: &str) -> ! {
+    let cached = match cache::load(device_name) {
+        None => {
+            sayln!(
+                "{}: No cached state for device '{}'!",
+                op::PrettyHeader::Failed,
+                device_name
+            );
+            std::process::exit(-1);
+        }
+        Some(cached) => cached,
+    };
+
+    let last_seen = cached
+        .status
+        .as_ref()
+        .map(|status| status.seen_at())
+        .into_iter()
+        .chain(cached.id.as_ref().map(|id| id.seen_at()))
+        .max()
+        .unwrap_or(UNIX_EPOCH);
+    let state = cached.status.map(|status| status.value.state);
+
+    if ui::output_format().is_machine() {
+        let mut report =
+            report::DeviceReport::new(device_name, state, cached.id.as_ref().map(|id| &id.value));
+        report.cached = true;
+        report.last_seen = last_seen
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|t| t.as_secs());
+        ui::emit(&report);
+        std::process::exit(0);
+    }
+
+    let age = SystemTime::now()
+        .duration_since(last_seen)
+        .unwrap_or_default();
+    sayln!(
+        "Cached state of device '{}' (last seen {} ago):",
+        device_name,
+        decode::format_age(age)
+    );
+    match state {
+        Some(state) => sayln!("Status: {}", state),
+        None => sayln!("Status: ?"),
+    }
+
+    if let Some(id) = cached.id {
+        let id = decode::decode_id_message(id.value);
+        decode::print_parts_legend();
+        sayln!();
+        sayln!("{}", id.ota_info.fmt);
+    }
+
+    std::process::exit(0);
+}
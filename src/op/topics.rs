@@ -0,0 +1,287 @@
+// Raw access to the broker, for debugging with the same identity (and TLS
+// setup) as everything else, rather than with mosquitto_sub and copied certs.
+
+use console::style;
+use rumqttc::QoS;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::{PrettyHeader, TOPIC_PREFIX};
+use crate::cache;
+use crate::data::{decode::format_age, payload::ContentType};
+use crate::net::capture::Payload;
+use crate::net::mqtt::MqttPacket;
+use crate::net::transport::{self, Transport};
+use crate::ui::{self, OutputFormat};
+
+// Wildcards must make up a whole level, and `#` can only be the last one.
+pub fn parse_filter(filter: &str) -> Result<String, String> {
+    let levels: Vec<&str> = filter.split('/').collect();
+
+    let valid = !filter.is_empty()
+        && levels.iter().enumerate().all(|(idx, level)| match *level {
+            "+" => true,
+            "#" => idx == levels.len() - 1,
+            level => !level.contains(['+', '#']),
+        });
+
+    if valid {
+        Ok(filter.to_owned())
+    } else {
+        Err(format!("'{}' is not a valid MQTT topic filter", filter))
+    }
+}
+
+// A payload given on the command line, or `@<file>` to read it from a file.
+pub fn load_payload(arg: &str) -> Result<Vec<u8>, String> {
+    match arg.strip_prefix('@') {
+        None => Ok(arg.as_bytes().to_vec()),
+        Some(path) => std::fs::read(path).map_err(|e| format!("could not read '{}': {}", path, e)),
+    }
+}
+
+// On one line: CBOR as JSON, text as is (bar escaped newlines and the like),
+// and anything else in hex.
+fn summarize(msg: &MqttPacket) -> String {
+    match msg.content_type {
+        ContentType::Text => msg
+            .payload_text()
+            .chars()
+            .map(|c| match c {
+                c if c.is_control() => c.escape_default().to_string(),
+                c => c.to_string(),
+            })
+            .collect(),
+        ContentType::Cbor => match msg.parse::<serde_json::Value>() {
+            Ok(value) => format!("cbor {}", value),
+            Err(_) => format!("cbor? {}", hex(&msg.payload)),
+        },
+        ContentType::Binary => hex(&msg.payload),
+    }
+}
+
+fn hex(payload: &[u8]) -> String {
+    payload.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn truncate(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        return s.to_owned();
+    }
+
+    let mut truncated: String = s.chars().take(width.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+fn unix_secs(t: SystemTime) -> Option<u64> {
+    t.duration_since(UNIX_EPOCH).ok().map(|t| t.as_secs())
+}
+
+#[derive(Serialize)]
+struct TopicReport<'a> {
+    topic: &'a str,
+    size: usize,
+    // When we first saw this payload retained on the topic, if we have before.
+    first_seen: Option<u64>,
+    #[serde(flatten)]
+    payload: Payload,
+}
+
+#[derive(Serialize)]
+struct MessageReport<'a> {
+    topic: &'a str,
+    retain: bool,
+    #[serde(flatten)]
+    payload: Payload,
+}
+
+// However busy the namespace, we don't wait any longer than this.
+const COLLECT_DEADLINE: Duration = Duration::from_secs(30);
+
+// Collects the retained messages the broker hands over on subscribing to
+// `filter`, until it has gone quiet for `settle`.
+pub fn collect_retained(
+    transport: &mut dyn Transport,
+    filter: &str,
+    settle: Duration,
+) -> BTreeMap<String, MqttPacket> {
+    transport.subscribe(filter);
+
+    let deadline = Instant::now() + COLLECT_DEADLINE;
+    let mut retained = BTreeMap::new();
+
+    // Whether it went quiet or away, we have all we're going to get.
+    while let Ok(msg) =
+        transport.recv_timeout(settle.min(deadline.saturating_duration_since(Instant::now())))
+    {
+        // The broker sends what it retains before anything published live.
+        if !msg.retain {
+            break;
+        }
+
+        retained.insert(msg.topic.clone(), msg);
+
+        if Instant::now() >= deadline {
+            break;
+        }
+    }
+
+    retained
+}
+
+// Prints every topic holding a retained message under the namespace (or just
+// under `device_name`), with its size, age and payload.
+pub fn list_on(transport: &mut dyn Transport, device_name: Option<&str>, settle: Duration) {
+    let filter = match device_name {
+        None => TOPIC_PREFIX.to_owned() + "#",
+        Some(device_name) => TOPIC_PREFIX.to_owned() + device_name + "/#",
+    };
+
+    let retained = collect_retained(transport, &filter, settle);
+
+    let payloads: Vec<(&str, &[u8])> = retained
+        .values()
+        .map(|msg| (msg.topic.as_str(), msg.payload.as_slice()))
+        .collect();
+    let first_seen = cache::retained_first_seen(&payloads);
+
+    let format = ui::output_format();
+    if format.is_machine() {
+        let reports: Vec<TopicReport> = retained
+            .values()
+            .zip(first_seen)
+            .map(|(msg, first_seen)| TopicReport {
+                topic: &msg.topic,
+                size: msg.payload.len(),
+                first_seen: first_seen.and_then(unix_secs),
+                payload: Payload::new(&msg.payload),
+            })
+            .collect();
+
+        match format {
+            OutputFormat::Jsonl => reports.iter().for_each(ui::emit),
+            _ => ui::emit(&reports),
+        }
+        return;
+    }
+
+    if retained.is_empty() {
+        sayln!("No retained messages found under '{}'.", filter);
+        return;
+    }
+
+    let topic_width = retained.keys().map(|topic| topic.len()).max().unwrap_or(0);
+    let term_width = ui::term().size_checked().map(|(_, cols)| cols as usize);
+
+    for (msg, first_seen) in retained.values().zip(first_seen) {
+        let age = match first_seen {
+            None => "?".to_owned(),
+            Some(first_seen) => format_age(
+                SystemTime::now()
+                    .duration_since(first_seen)
+                    .unwrap_or_default(),
+            ),
+        };
+
+        let line = format!(
+            "{:topic_width$}  {:>7}  {:>4}  ",
+            msg.topic,
+            format!("{} B", msg.payload.len()),
+            age,
+            topic_width = topic_width
+        );
+        let payload = summarize(msg);
+        let payload = match term_width {
+            Some(cols) if !ui::is_plain() => truncate(&payload, cols.saturating_sub(line.len())),
+            _ => payload,
+        };
+
+        sayln!("{}{}", line, style(payload).dim());
+    }
+
+    sayln!();
+    sayln!("Ages are since each payload was first seen from this machine ('?' if new).");
+}
+
+// Prints every message published to topics matching `filter` as it arrives,
+// until the connection is lost.
+pub fn subscribe_on(transport: &mut dyn Transport, filter: &str) {
+    transport.subscribe(filter);
+
+    while let Ok(msg) = transport.recv() {
+        if ui::output_format().is_machine() {
+            ui::emit(&MessageReport {
+                topic: &msg.topic,
+                retain: msg.retain,
+                payload: Payload::new(&msg.payload),
+            });
+            continue;
+        }
+
+        let retained = if msg.retain {
+            style(" (retained)").dim().to_string()
+        } else {
+            String::new()
+        };
+        sayln!("{}{}: {}", msg.topic, retained, summarize(&msg));
+    }
+}
+
+pub fn publish_on(
+    transport: &mut dyn Transport,
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    retain: bool,
+) {
+    transport.publish_qos(topic, payload, qos, retain);
+
+    if retain && payload.is_empty() {
+        sayln!(
+            "{}: Cleared retained message on '{}'.",
+            PrettyHeader::Success,
+            topic
+        );
+    } else {
+        sayln!(
+            "{}: Published {} bytes to '{}'.",
+            PrettyHeader::Success,
+            payload.len(),
+            topic
+        );
+    }
+}
+
+fn connect() -> Box<dyn Transport> {
+    sayln!("Connecting to broker...");
+    let transport = transport::connect();
+    ui::clear_last_lines(1);
+
+    transport
+}
+
+pub fn list(device_name: Option<&str>, settle: Duration) -> ! {
+    let mut transport = connect();
+    list_on(&mut *transport, device_name, settle);
+    transport.disconnect();
+
+    std::process::exit(0);
+}
+
+pub fn subscribe(filter: &str) -> ! {
+    let mut transport = connect();
+    subscribe_on(&mut *transport, filter);
+
+    sayln!("Lost connection to broker!");
+    std::process::exit(-1);
+}
+
+pub fn publish(topic: &str, payload: &[u8], qos: QoS, retain: bool) -> ! {
+    let mut transport = connect();
+    publish_on(&mut *transport, topic, payload, qos, retain);
+    transport.disconnect();
+
+    std::process::exit(0);
+}
@@ -0,0 +1,566 @@
+// A simulated device, which speaks the device side of the protocol well enough
+// to drive every `op::*` against it. It knows nothing about the transport: it
+// is fed incoming messages and returns what a real device would do in response,
+// so it can sit behind a real broker (see `iota-sim`) or be driven directly.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::data::{decode, model, partition_table};
+use crate::net::transport::{MemoryTransport, Reply};
+use crate::op;
+
+// The default two-slot OTA layout from ESP-IDF.
+pub const DEFAULT_LAYOUT_CSV: &str = "\
+# Name,   Type, SubType, Offset, Size, Flags
+nvs,      data, nvs,     ,       16K,
+otadata,  data, ota,     ,       8K,
+phy_init, data, phy,     ,       4K,
+ota_0,    app,  ota_0,   ,       1M,
+ota_1,    app,  ota_1,   ,       1M,
+";
+
+pub struct Config {
+    pub name: String,
+    pub schema: u32,
+    pub app_desc: model::AppDesc,
+    pub layout: Vec<partition_table::ExpectedPartition>,
+    // Send CBOR rather than JSON payloads.
+    pub cbor: bool,
+    // How long an OTA download takes, and how many progress messages it sends.
+    pub ota_duration: Duration,
+    pub ota_progress_steps: usize,
+    // How long the device stays down for when restarting.
+    pub restart_delay: Duration,
+}
+
+impl Config {
+    pub fn new(name: &str) -> Self {
+        Config {
+            name: name.to_owned(),
+            schema: model::SCHEMA_VERSION,
+            app_desc: model::AppDesc {
+                project_name: "sim".to_owned(),
+                version: "1.0.0".to_owned(),
+                secure_version: 0,
+                date: "Jan  1 2021".to_owned(),
+                time: "00:00:00".to_owned(),
+            },
+            layout: partition_table::parse_csv(DEFAULT_LAYOUT_CSV)
+                .expect("default layout is valid"),
+            cbor: false,
+            ota_duration: Duration::from_secs(3),
+            ota_progress_steps: 3,
+            restart_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Action {
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        retain: bool,
+    },
+    Delay(Duration),
+}
+
+#[derive(Debug)]
+pub enum SimError {
+    NoAppPartition,
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimError::NoAppPartition => write!(fmt, "layout has no app partition to run"),
+        }
+    }
+}
+
+impl std::error::Error for SimError {}
+
+// What devices accept on `_cmd/ota`, i.e. the other end of `model::OtaCommand`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum OtaCommand {
+    Update {
+        #[allow(dead_code)]
+        url: String,
+    },
+    Validate,
+    Rollback,
+}
+
+// The part of the `model::Command` envelope devices care about. Older iota
+// versions didn't send one, or anything at all for a restart.
+#[derive(Debug, Deserialize)]
+struct Request {
+    request_id: Option<String>,
+}
+
+pub struct Device {
+    config: Config,
+    partitions: Vec<model::Partition>,
+    // The firmware written to each app partition, by address.
+    firmware: HashMap<usize, model::AppDesc>,
+    boot: usize,
+    running: usize,
+    last_invalid: Option<usize>,
+    booted_at: Instant,
+    reset_reason: &'static str,
+    updates: usize,
+}
+
+impl Device {
+    pub fn new(config: Config) -> Result<Self, SimError> {
+        let partitions: Vec<model::Partition> = config
+            .layout
+            .iter()
+            .map(|part| model::Partition {
+                flash_chip_id: 0,
+                part_type: part.part_type.clone(),
+                address: part.address,
+                size: part.size,
+                label: part.label.clone(),
+                encrypted: false,
+                ota_state: model::OtaState::NotPresent,
+            })
+            .collect();
+
+        // Start out on the first OTA slot as if it had been flashed and
+        // validated, or on the factory app if there are no OTA slots.
+        let running = partitions
+            .iter()
+            .filter_map(|part| match part.part_type {
+                model::PartitionType::App(model::PartitionAppSubtype::Ota { id }) => {
+                    Some((id, part.address))
+                }
+                _ => None,
+            })
+            .min()
+            .map(|(_, addr)| addr)
+            .or_else(|| {
+                partitions
+                    .iter()
+                    .find(|part| matches!(part.part_type, model::PartitionType::App(_)))
+                    .map(|part| part.address)
+            })
+            .ok_or(SimError::NoAppPartition)?;
+
+        let mut firmware = HashMap::new();
+        firmware.insert(running, config.app_desc.clone());
+
+        let mut device = Device {
+            config,
+            partitions,
+            firmware,
+            boot: running,
+            running,
+            last_invalid: None,
+            booted_at: Instant::now(),
+            reset_reason: "poweron",
+            updates: 0,
+        };
+
+        if device.is_ota_slot(running) {
+            device.set_state(running, model::OtaState::Valid);
+        }
+
+        Ok(device)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn topic(&self, suffix: &str) -> String {
+        format!("{}{}/{}", op::TOPIC_PREFIX, self.config.name, suffix)
+    }
+
+    pub fn subscriptions(&self) -> Vec<String> {
+        let mut subscriptions = vec![self.topic("_cmd/ota"), self.topic("_cmd/restart")];
+        if self.supports(decode::Capability::IdRefresh) {
+            subscriptions.push(self.topic("_cmd/id"));
+        }
+
+        subscriptions
+    }
+
+    // The retained status message the broker should publish if we vanish.
+    pub fn last_will(&self) -> (String, Vec<u8>) {
+        (
+            self.topic("_info/status"),
+            self.encode(&model::StatusMessage {
+                state: model::DeviceState::Down,
+            }),
+        )
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        if self.config.cbor {
+            let mut payload = Vec::new();
+            ciborium::ser::into_writer(value, &mut payload).expect("Could not build CBOR");
+            payload
+        } else {
+            serde_json::to_vec(value).expect("Could not build JSON")
+        }
+    }
+
+    fn publish<T: Serialize>(&self, suffix: &str, value: &T, retain: bool) -> Action {
+        Action::Publish {
+            topic: self.topic(suffix),
+            payload: self.encode(value),
+            retain,
+        }
+    }
+
+    fn error(&self, message: &str) -> Action {
+        Action::Publish {
+            topic: self.topic("_info/error"),
+            payload: message.as_bytes().to_vec(),
+            retain: false,
+        }
+    }
+
+    fn ack(&self, request_id: String, status: model::AckStatus, reason: Option<String>) -> Action {
+        self.publish(
+            "_info/ack",
+            &model::AckMessage {
+                request_id,
+                status,
+                reason,
+            },
+            false,
+        )
+    }
+
+    fn supports(&self, capability: decode::Capability) -> bool {
+        self.config.schema >= capability.min_schema_version()
+    }
+
+    fn partition(&self, addr: usize) -> &model::Partition {
+        self.partitions
+            .iter()
+            .find(|part| part.address == addr)
+            .expect("simulator lost track of a partition")
+    }
+
+    fn state(&self, addr: usize) -> &model::OtaState {
+        &self.partition(addr).ota_state
+    }
+
+    fn set_state(&mut self, addr: usize, state: model::OtaState) {
+        if let Some(part) = self.partitions.iter_mut().find(|part| part.address == addr) {
+            part.ota_state = state;
+        }
+    }
+
+    fn is_ota_slot(&self, addr: usize) -> bool {
+        matches!(
+            self.partition(addr).part_type,
+            model::PartitionType::App(model::PartitionAppSubtype::Ota { .. })
+        )
+    }
+
+    fn ota_slots(&self) -> Vec<usize> {
+        let mut slots: Vec<(usize, usize)> = self
+            .partitions
+            .iter()
+            .filter_map(|part| match part.part_type {
+                model::PartitionType::App(model::PartitionAppSubtype::Ota { id }) => {
+                    Some((id, part.address))
+                }
+                _ => None,
+            })
+            .collect();
+        slots.sort_unstable();
+        slots.into_iter().map(|(_, addr)| addr).collect()
+    }
+
+    // As `esp_ota_get_next_update_partition`: the OTA slot after the running
+    // one, wrapping around, or the first one when running the factory app.
+    fn next_update(&self) -> Option<usize> {
+        let slots = self.ota_slots();
+        let next = match slots.iter().position(|addr| *addr == self.running) {
+            None => slots.first().copied(),
+            Some(idx) => slots.get((idx + 1) % slots.len()).copied(),
+        };
+
+        next.filter(|addr| *addr != self.running)
+    }
+
+    // The app a rollback would return to: another OTA slot holding valid
+    // firmware, or else the factory app.
+    fn rollback_target(&self) -> Option<usize> {
+        let ota = self.ota_slots().into_iter().find(|addr| {
+            *addr != self.running
+                && self.firmware.contains_key(addr)
+                && *self.state(*addr) == model::OtaState::Valid
+        });
+
+        ota.or_else(|| {
+            self.partitions
+                .iter()
+                .find(|part| {
+                    part.address != self.running
+                        && part.part_type
+                            == model::PartitionType::App(model::PartitionAppSubtype::Factory)
+                })
+                .map(|part| part.address)
+        })
+    }
+
+    pub fn id_message(&self) -> model::IdMessage {
+        let flash_size = self
+            .partitions
+            .iter()
+            .map(|part| part.address + part.size)
+            .max()
+            .unwrap_or(0)
+            .next_power_of_two();
+
+        model::IdMessage {
+            schema: self.config.schema,
+            software: model::Software {
+                app_desc: self.firmware[&self.running].clone(),
+                partitions: model::Partitions {
+                    boot: Some(self.boot),
+                    running: Some(self.running),
+                    last_invalid: self.last_invalid,
+                    next_update: self.next_update(),
+                    is_rollback_possible: self.rollback_target().is_some(),
+                    list: self.partitions.clone(),
+                },
+            },
+            hardware: Some(model::Hardware {
+                chip_model: Some("esp32".to_owned()),
+                chip_revision: Some(3),
+                mac: Some("02:00:00:00:00:01".to_owned()),
+                flash_size: Some(flash_size),
+            }),
+            runtime: Some(model::Runtime {
+                idf_version: Some("v4.4".to_owned()),
+                uptime_s: Some(self.booted_at.elapsed().as_secs()),
+                reset_reason: Some(self.reset_reason.to_owned()),
+                free_heap: Some(150 * 1024),
+                rssi: None,
+            }),
+        }
+    }
+
+    // What the device publishes whenever it (re)connects.
+    pub fn announce(&self) -> Vec<Action> {
+        vec![
+            self.publish(
+                "_info/status",
+                &model::StatusMessage {
+                    state: model::DeviceState::Up,
+                },
+                true,
+            ),
+            self.publish("_info/id", &self.id_message(), true),
+        ]
+    }
+
+    // As the bootloader does with app rollback enabled: a freshly written app
+    // gets one boot to validate itself, and is abandoned if it doesn't.
+    fn boot(&mut self) {
+        match self.state(self.boot) {
+            model::OtaState::New => self.set_state(self.boot, model::OtaState::PendingVerify),
+            model::OtaState::PendingVerify => {
+                self.set_state(self.boot, model::OtaState::Aborted);
+                self.last_invalid = Some(self.boot);
+                self.running = self.boot;
+                if let Some(target) = self.rollback_target() {
+                    self.boot = target;
+                }
+            }
+            _ => {}
+        }
+
+        self.running = self.boot;
+        self.booted_at = Instant::now();
+    }
+
+    fn restart(&mut self, reason: &'static str) -> Vec<Action> {
+        let mut actions = vec![
+            self.publish(
+                "_info/status",
+                &model::StatusMessage {
+                    state: model::DeviceState::Down,
+                },
+                true,
+            ),
+            Action::Delay(self.config.restart_delay),
+        ];
+
+        self.reset_reason = reason;
+        self.boot();
+
+        actions.extend(self.announce());
+        actions
+    }
+
+    // The command handlers return why they refused a command, if they did.
+
+    fn ota_update(&mut self) -> Result<Vec<Action>, String> {
+        if *self.state(self.running) == model::OtaState::PendingVerify {
+            return Err("OTA refused: running app is pending verification".to_owned());
+        }
+
+        let target = self
+            .next_update()
+            .ok_or_else(|| "OTA refused: no partition to update".to_owned())?;
+
+        let steps = self.config.ota_progress_steps.max(1);
+        let step_delay = self.config.ota_duration / steps as u32;
+        let size_kb = self.partition(target).size / 1024 / 2;
+
+        let mut actions = vec![self.publish("_info/ota", &model::OtaMessage::Start, false)];
+        for step in 1..=steps {
+            actions.push(Action::Delay(step_delay));
+            actions.push(self.publish(
+                "_info/ota",
+                &model::OtaMessage::InProgress {
+                    rx_kb: size_kb * step / steps,
+                },
+                false,
+            ));
+        }
+
+        // We don't download the image, so just make the new firmware
+        // distinguishable from the old.
+        self.updates += 1;
+        let mut app_desc = self.firmware[&self.running].clone();
+        app_desc.version = format!("{}+sim.{}", self.config.app_desc.version, self.updates);
+        self.firmware.insert(target, app_desc);
+        self.set_state(target, model::OtaState::New);
+        self.boot = target;
+
+        actions.push(self.publish("_info/ota", &model::OtaMessage::Done, false));
+        Ok(actions)
+    }
+
+    fn validate(&mut self) -> Result<Vec<Action>, String> {
+        if *self.state(self.running) != model::OtaState::PendingVerify {
+            return Err("validate refused: running app is not pending verification".to_owned());
+        }
+
+        self.set_state(self.running, model::OtaState::Valid);
+        Ok(vec![self.publish("_info/id", &self.id_message(), true)])
+    }
+
+    fn rollback(&mut self) -> Result<Vec<Action>, String> {
+        let target = self
+            .rollback_target()
+            .ok_or_else(|| "rollback refused: no app to roll back to".to_owned())?;
+
+        self.set_state(self.running, model::OtaState::Invalid);
+        self.last_invalid = Some(self.running);
+        self.boot = target;
+
+        Ok(self.restart("sw"))
+    }
+
+    pub fn handle(&mut self, topic: &str, payload: &[u8]) -> Vec<Action> {
+        let is_update = |payload: &[u8]| {
+            matches!(
+                serde_json::from_slice::<OtaCommand>(payload),
+                Ok(OtaCommand::Update { .. })
+            )
+        };
+
+        let outcome = if topic == self.topic("_cmd/restart") {
+            Ok(self.restart("sw"))
+        } else if topic == self.topic("_cmd/id") && self.supports(decode::Capability::IdRefresh) {
+            Ok(vec![self.publish("_info/id", &self.id_message(), true)])
+        } else if topic == self.topic("_cmd/ota") {
+            match serde_json::from_slice::<OtaCommand>(payload) {
+                Ok(OtaCommand::Update { .. }) => self.ota_update(),
+                Ok(OtaCommand::Validate) => self.validate(),
+                Ok(OtaCommand::Rollback) => self.rollback(),
+                Err(e) => Err(format!("bad OTA command: {}", e)),
+            }
+        } else {
+            return vec![];
+        };
+
+        let request_id = serde_json::from_slice::<Request>(payload)
+            .ok()
+            .and_then(|request| request.request_id)
+            .filter(|_| self.supports(decode::Capability::Ack));
+
+        let mut actions = Vec::new();
+        match outcome {
+            Ok(done) => {
+                if let Some(request_id) = request_id {
+                    actions.push(self.ack(request_id, model::AckStatus::Accepted, None));
+                }
+                actions.extend(done);
+            }
+            Err(reason) => {
+                if let Some(request_id) = request_id {
+                    actions.push(self.ack(
+                        request_id,
+                        model::AckStatus::Rejected,
+                        Some(reason.clone()),
+                    ));
+                }
+                actions.push(self.error(&reason));
+                if is_update(payload) {
+                    actions.push(self.publish("_info/ota", &model::OtaMessage::Fail, false));
+                }
+            }
+        }
+
+        actions
+    }
+}
+
+// What the device publishes of `actions`, for an in-memory broker to deliver.
+pub fn replies(actions: Vec<Action>) -> Vec<Reply> {
+    actions
+        .into_iter()
+        .filter_map(|action| match action {
+            Action::Publish {
+                topic,
+                payload,
+                retain,
+            } => Some(Reply {
+                topic,
+                payload,
+                retain,
+            }),
+            Action::Delay(_) => None,
+        })
+        .collect()
+}
+
+// Puts `device` on the far end of an in-memory broker, as if it had been up
+// all along. Delays are skipped, since nothing else is going on.
+pub fn memory_transport(device: Device) -> MemoryTransport {
+    memory_transport_responding(device, |device, topic, payload| {
+        device.handle(topic, payload)
+    })
+}
+
+// As `memory_transport`, but with `respond` deciding what the device does with
+// each message instead, e.g. to have it misbehave.
+pub fn memory_transport_responding(
+    mut device: Device,
+    mut respond: impl FnMut(&mut Device, &str, &[u8]) -> Vec<Action> + 'static,
+) -> MemoryTransport {
+    let announce = replies(device.announce());
+
+    let mut transport =
+        MemoryTransport::new(move |topic, payload| replies(respond(&mut device, topic, payload)));
+    for reply in announce {
+        transport.inject(reply);
+    }
+
+    transport
+}
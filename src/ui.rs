@@ -0,0 +1,242 @@
+use console::Term;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Human,
+    Json,
+    Jsonl,
+}
+
+impl OutputFormat {
+    pub fn is_machine(&self) -> bool {
+        !matches!(self, OutputFormat::Human)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(OutputFormat::Human),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            _ => Err(format!("unknown output format '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!("unknown color choice '{}'", s)),
+        }
+    }
+}
+
+struct Settings {
+    output: OutputFormat,
+    plain: bool,
+}
+
+static SETTINGS: OnceCell<Settings> = OnceCell::new();
+
+pub fn init(output: OutputFormat, color: ColorChoice) {
+    let plain = match color {
+        ColorChoice::Always => false,
+        ColorChoice::Never => true,
+        ColorChoice::Auto => std::env::var_os("NO_COLOR").is_some() || !term_for(output).is_term(),
+    };
+
+    console::set_colors_enabled(!plain);
+    console::set_colors_enabled_stderr(!plain);
+
+    if SETTINGS.set(Settings { output, plain }).is_err() {
+        panic!("ui settings already initialized");
+    }
+}
+
+pub fn output_format() -> OutputFormat {
+    SETTINGS
+        .get()
+        .map_or(OutputFormat::Human, |settings| settings.output)
+}
+
+// In plain mode we must not rely on colour or cursor movement, since the
+// output is probably being redirected to a file or another program.
+pub fn is_plain() -> bool {
+    SETTINGS.get().is_some_and(|settings| settings.plain)
+}
+
+// Human-oriented progress output goes to stderr when stdout is reserved for
+// machine-readable output.
+fn term_for(output: OutputFormat) -> Term {
+    if output.is_machine() {
+        Term::stderr()
+    } else {
+        Term::stdout()
+    }
+}
+
+pub fn term() -> Term {
+    term_for(output_format())
+}
+
+pub fn clear_last_lines(n: usize) {
+    let term = term();
+
+    if !is_plain() && term.is_term() {
+        term.clear_last_lines(n).unwrap();
+    }
+}
+
+thread_local! {
+    static CAPTURED: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// Runs `f`, collecting everything it would have said instead of printing it,
+// so that tests can check what an operation told the user.
+pub fn capture<R>(f: impl FnOnce() -> R) -> (R, String) {
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(String::new()));
+    let result = f();
+    let output = CAPTURED.with(|captured| captured.borrow_mut().take().unwrap_or_default());
+
+    (result, output)
+}
+
+// Returns whether `line` was captured, rather than still needing printing.
+fn capture_line(line: &str) -> bool {
+    CAPTURED.with(|captured| match captured.borrow_mut().as_mut() {
+        None => false,
+        Some(output) => {
+            output.push_str(line);
+            output.push('\n');
+            true
+        }
+    })
+}
+
+pub fn say(line: &str) {
+    if !capture_line(line) {
+        term().write_line(line).unwrap();
+    }
+}
+
+fn is_capturing() -> bool {
+    CAPTURED.with(|captured| captured.borrow().is_some())
+}
+
+// Input is read on a thread of its own, so that a prompt on another thread
+// (i.e. the Ctrl-C handler) can take it over from a wait already in progress.
+static INPUT: Lazy<Mutex<Receiver<u8>>> = Lazy::new(|| {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut byte = [0];
+        while io::stdin().read(&mut byte).expect("Could not read stdin") > 0 {
+            if tx.send(byte[0]).is_err() {
+                break;
+            }
+        }
+    });
+
+    Mutex::new(rx)
+});
+
+static INPUT_TAKEN: AtomicBool = AtomicBool::new(false);
+
+// Blocks until the user presses enter (or any key, on a terminal), except
+// while capturing, when nobody is there to press it.
+pub fn wait_for_key() {
+    if is_capturing() {
+        return;
+    }
+
+    loop {
+        if INPUT_TAKEN.load(Ordering::SeqCst) {
+            // Whatever took the input over decides what happens next.
+            loop {
+                thread::park();
+            }
+        }
+
+        match INPUT
+            .lock()
+            .unwrap()
+            .recv_timeout(Duration::from_millis(100))
+        {
+            Ok(_) => return,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => panic!("stdin closed"),
+        }
+    }
+}
+
+// Asks a yes or no question, taking the input over from anything else waiting
+// on it. No answer at all, e.g. because nobody is at a terminal, is a no.
+pub fn confirm(question: &str) -> bool {
+    if is_capturing() || !term().is_term() {
+        return false;
+    }
+
+    INPUT_TAKEN.store(true, Ordering::SeqCst);
+    let input = INPUT.lock().unwrap();
+
+    term().write_str(&format!("{} [y/N] ", question)).unwrap();
+
+    let mut answer = Vec::new();
+    while let Ok(byte) = input.recv() {
+        if byte == b'\n' {
+            break;
+        }
+        answer.push(byte);
+    }
+
+    String::from_utf8_lossy(&answer)
+        .trim()
+        .eq_ignore_ascii_case("y")
+}
+
+#[macro_export]
+macro_rules! sayln {
+    () => {
+        $crate::ui::say("")
+    };
+    ($($arg:tt)*) => {
+        $crate::ui::say(&format!($($arg)*))
+    };
+}
+
+pub fn emit<T: Serialize>(value: &T) {
+    let json = match output_format() {
+        OutputFormat::Human => panic!("machine-readable output requested in human mode"),
+        OutputFormat::Json => serde_json::to_string_pretty(value),
+        OutputFormat::Jsonl => serde_json::to_string(value),
+    }
+    .expect("Could not build JSON");
+
+    if !capture_line(&json) {
+        println!("{}", json);
+    }
+}
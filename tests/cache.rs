@@ -0,0 +1,104 @@
+use iota::cache;
+use iota::data::model;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The cache is found through the environment, which all the tests in this
+// binary share, so each holds this while it uses its own cache directory.
+static ENV: Mutex<()> = Mutex::new(());
+
+fn isolate_cache(name: &str) -> (MutexGuard<'static, ()>, PathBuf) {
+    let guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("cache-{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    std::env::set_var("XDG_CACHE_HOME", &dir);
+
+    (guard, dir.join("iota"))
+}
+
+fn up() -> model::StatusMessage {
+    model::StatusMessage {
+        state: model::DeviceState::Up,
+    }
+}
+
+#[test]
+fn stored_state_loads_back() {
+    let (_guard, _) = isolate_cache("load");
+
+    assert!(cache::load("dev-a").is_none());
+    assert!(cache::load_all().is_empty());
+
+    let before = SystemTime::now() - Duration::from_secs(1);
+    cache::store_status("dev-a", &up());
+    cache::store_status("dev-b", &up());
+
+    let cached = cache::load("dev-a").unwrap();
+    let status = cached.status.unwrap();
+    assert_eq!(status.value.state, model::DeviceState::Up);
+    assert!(status.seen_at() >= before);
+    assert!(cached.id.is_none());
+
+    let mut names: Vec<String> = cache::load_all()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["dev-a", "dev-b"]);
+}
+
+#[test]
+fn stored_state_is_replaced_and_forgotten() {
+    let (_guard, root) = isolate_cache("store");
+
+    cache::store_status("dev-a", &up());
+    cache::store_status(
+        "dev-a",
+        &model::StatusMessage {
+            state: model::DeviceState::Down,
+        },
+    );
+
+    let cached = cache::load("dev-a").unwrap();
+    assert_eq!(cached.status.unwrap().value.state, model::DeviceState::Down);
+    assert!(root.join("devices/dev-a.json").exists());
+
+    // Names which would escape the cache directory are never stored.
+    cache::store_status("../dev-a", &up());
+    assert!(cache::load("../dev-a").is_none());
+
+    assert!(cache::forget_device("dev-a"));
+    assert!(cache::load("dev-a").is_none());
+    assert!(!cache::forget_device("dev-a"));
+}
+
+#[test]
+fn retained_payloads_are_first_seen_until_they_change() {
+    let (_guard, root) = isolate_cache("expiry");
+    let topic = "hoek/iot/dev-a/_info/id";
+
+    assert_eq!(cache::retained_first_seen(&[(topic, b"a")]), [None]);
+
+    // Age what we've seen, as if it had been retained since the epoch.
+    let path = root.join("topics.json");
+    let mut seen: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    // The hash is FNV-1a, which has to stay the same from one build to the next.
+    assert_eq!(seen[topic]["value"], 0xaf63_dc4c_8601_ec8cu64);
+    seen[topic]["seen_at"] = 0.into();
+    fs::write(&path, serde_json::to_vec(&seen).unwrap()).unwrap();
+
+    assert_eq!(
+        cache::retained_first_seen(&[(topic, b"a")]),
+        [Some(UNIX_EPOCH)]
+    );
+
+    // A new payload on the topic starts over.
+    assert_eq!(cache::retained_first_seen(&[(topic, b"b")]), [None]);
+    assert!(cache::retained_first_seen(&[(topic, b"b")])[0].unwrap() > UNIX_EPOCH);
+
+    cache::forget_retained("hoek/iot/dev-a/");
+    assert_eq!(cache::retained_first_seen(&[(topic, b"b")]), [None]);
+}
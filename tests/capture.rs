@@ -0,0 +1,64 @@
+use iota::net::capture::{self, RecordingTransport};
+use iota::op::{self, ExitDisposition};
+use iota::{sim, ui};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const DEVICE: &str = "sim-device";
+
+fn replay(path: &Path, cache: &Path) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_iota"))
+        .arg("replay")
+        .arg(path)
+        .env("XDG_CACHE_HOME", cache)
+        .output()
+        .unwrap();
+    let replayed = String::from_utf8_lossy(&output.stdout).into_owned();
+
+    assert!(output.status.success(), "{}", replayed);
+    assert!(!replayed.contains("diverged"), "{}", replayed);
+    replayed
+}
+
+// The capture session is global, so this is the only test in this binary.
+#[test]
+fn recorded_session_replays_identically() {
+    let tmp = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let cache = tmp.join("capture-cache");
+    let _ = fs::remove_dir_all(&cache);
+    std::env::set_var("XDG_CACHE_HOME", &cache);
+    let path = tmp.join("restart.capture.jsonl");
+
+    let argv = ["iota", "--color", "never", "restart", DEVICE];
+    capture::record(&path, argv.iter().map(|arg| arg.to_string()).collect()).unwrap();
+
+    // Without id refreshes, whether the retained id is stale comes from the
+    // cache, which the recording has just populated.
+    let mut config = sim::Config::new(DEVICE);
+    config.schema = 2;
+    let mut transport =
+        RecordingTransport::new(sim::memory_transport(sim::Device::new(config).unwrap()));
+    let (ed, recorded) = ui::capture(|| {
+        op::perform_op_locked_on(&op::restart::Operation {}, &mut transport, DEVICE, false)
+    });
+    assert_eq!(ed, ExitDisposition::Ok);
+    assert!(recorded.contains("Device reconnected!"), "{}", recorded);
+
+    // Make it look as if we had seen every retained message long ago.
+    let topics_path = cache.join("iota/topics.json");
+    let mut topics: serde_json::Value =
+        serde_json::from_slice(&fs::read(&topics_path).unwrap()).unwrap();
+    for seen in topics.as_object_mut().unwrap().values_mut() {
+        seen["seen_at"] = 0.into();
+    }
+    fs::write(&topics_path, serde_json::to_vec(&topics).unwrap()).unwrap();
+    let cached = fs::read(&topics_path).unwrap();
+
+    let replayed = replay(&path, &cache);
+    assert!(replayed.contains("Device reconnected!"), "{}", replayed);
+    assert!(!replayed.contains("may be out of date"), "{}", replayed);
+
+    assert_eq!(replay(&path, &cache), replayed);
+    assert_eq!(fs::read(&topics_path).unwrap(), cached);
+}
@@ -0,0 +1,130 @@
+use iota::net::transport::{MemoryTransport, Reply};
+use iota::op::{self, cmd};
+use iota::{cache, ui};
+use rumqttc::QoS;
+use std::path::Path;
+use std::time::Duration;
+
+const DEVICE: &str = "sim-device";
+
+// Gives each test a cache of its own, away from the real one.
+fn isolate_cache() {
+    let test = std::thread::current()
+        .name()
+        .unwrap_or("main")
+        .replace("::", "-");
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(module_path!())
+        .join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    cache::isolate(dir);
+}
+
+fn topic(kind: &str, name: &str) -> String {
+    format!("{}{}/{}/{}", op::TOPIC_PREFIX, DEVICE, kind, name)
+}
+
+// A device which answers `_cmd/echo` on `_info/echo` with what it was sent.
+fn device() -> MemoryTransport {
+    isolate_cache();
+    MemoryTransport::new(|t, payload| {
+        if t == topic("_cmd", "echo") {
+            vec![Reply {
+                topic: topic("_info", "echo"),
+                payload: payload.to_vec(),
+                retain: true,
+            }]
+        } else {
+            Vec::new()
+        }
+    })
+}
+
+fn request(name: &str, payload: &str, response: Option<&str>) -> cmd::Request {
+    cmd::Request {
+        name: name.to_owned(),
+        payload: cmd::load_payload(Some(payload)).unwrap(),
+        qos: QoS::AtLeastOnce,
+        retain: false,
+        response: response.map(str::to_owned),
+        timeout: Duration::from_millis(100),
+    }
+}
+
+#[test]
+fn command_is_published_without_waiting() {
+    let mut transport = device();
+
+    let (ok, out) = ui::capture(|| {
+        cmd::perform_on(
+            &mut transport,
+            DEVICE,
+            &request("led", r#"{"on":true}"#, None),
+        )
+    });
+
+    assert!(ok);
+    assert!(out.contains("Command sent to"), "{}", out);
+    assert_eq!(
+        transport.published(),
+        &[(topic("_cmd", "led"), br#"{"on":true}"#.to_vec())]
+    );
+}
+
+#[test]
+fn response_is_pretty_printed() {
+    let mut transport = device();
+
+    let (ok, out) = ui::capture(|| {
+        cmd::perform_on(
+            &mut transport,
+            DEVICE,
+            &request("echo", r#"{"n":1}"#, Some("echo")),
+        )
+    });
+
+    assert!(ok);
+    assert!(out.contains("Response on"), "{}", out);
+    assert!(out.contains("{\n  \"n\": 1\n}"), "{}", out);
+}
+
+#[test]
+fn retained_response_from_earlier_is_ignored() {
+    let mut transport = device();
+    transport.inject(Reply {
+        topic: topic("_info", "echo"),
+        payload: br#"{"n":0}"#.to_vec(),
+        retain: true,
+    });
+
+    let (ok, out) = ui::capture(|| {
+        cmd::perform_on(
+            &mut transport,
+            DEVICE,
+            &request("echo", r#"{"n":1}"#, Some("echo")),
+        )
+    });
+
+    assert!(ok);
+    assert!(out.contains("\"n\": 1"), "{}", out);
+    assert!(!out.contains("\"n\": 0"), "{}", out);
+}
+
+#[test]
+fn missing_response_fails() {
+    let mut transport = device();
+
+    let (ok, out) =
+        ui::capture(|| cmd::perform_on(&mut transport, DEVICE, &request("led", "{}", Some("led"))));
+
+    assert!(!ok);
+    assert!(out.contains("FAILED"), "{}", out);
+}
+
+#[test]
+fn payload_must_be_json_or_file() {
+    assert_eq!(cmd::load_payload(None).unwrap(), b"{}");
+    assert!(cmd::load_payload(Some("{oops")).is_err());
+    assert!(cmd::load_payload(Some("@/nonexistent/payload.cbor")).is_err());
+    assert!(cmd::parse_name("led/+").is_err());
+}
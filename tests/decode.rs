@@ -0,0 +1,596 @@
+use iota::data::{decode, model, payload};
+use iota::ui;
+use proptest::prelude::*;
+use std::path::Path;
+use std::sync::Once;
+
+// Rendering differs in plain mode, which is also the mode we can parse the
+// flags back out of.
+fn init_plain() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| ui::init(ui::OutputFormat::Human, ui::ColorChoice::Never));
+}
+
+fn ota_state() -> impl Strategy<Value = model::OtaState> {
+    prop_oneof![
+        Just(model::OtaState::NotPresent),
+        Just(model::OtaState::New),
+        Just(model::OtaState::PendingVerify),
+        Just(model::OtaState::Valid),
+        Just(model::OtaState::Invalid),
+        Just(model::OtaState::Aborted),
+        Just(model::OtaState::Undefined),
+        "x_[a-z_]{0,8}".prop_map(model::OtaState::Unknown),
+    ]
+}
+
+fn part_type() -> impl Strategy<Value = model::PartitionType> {
+    use model::{PartitionAppSubtype as App, PartitionDataSubtype as Data, PartitionType};
+
+    prop_oneof![
+        Just(PartitionType::App(App::Factory)),
+        Just(PartitionType::App(App::Test)),
+        (0..16usize).prop_map(|id| PartitionType::App(App::Ota { id })),
+        "x_[a-z]{0,6}".prop_map(|name| PartitionType::App(App::Unknown(name))),
+        prop_oneof![
+            Just(Data::Ota),
+            Just(Data::Phy),
+            Just(Data::Nvs),
+            Just(Data::CoreDump),
+            Just(Data::NvsKeys),
+            Just(Data::EfuseEm),
+            Just(Data::Esphttpd),
+            Just(Data::Fat),
+            Just(Data::Spiffs),
+            "x_[a-z]{0,6}".prop_map(Data::Unknown),
+        ]
+        .prop_map(PartitionType::Data),
+        ("x_[a-z]{0,6}", "[a-z]{0,6}")
+            .prop_map(|(name, subtype)| PartitionType::Unknown { name, subtype }),
+    ]
+}
+
+// Mostly plausible flash addresses, but sometimes anything at all.
+fn address() -> impl Strategy<Value = usize> {
+    prop_oneof![
+        4 => (0..64usize).prop_map(|n| n * 0x10000),
+        1 => any::<usize>(),
+    ]
+}
+
+fn partition() -> impl Strategy<Value = model::Partition> {
+    (
+        0..4usize,
+        part_type(),
+        address(),
+        address(),
+        "[a-z0-9_]{0,20}",
+        any::<bool>(),
+        ota_state(),
+    )
+        .prop_map(
+            |(flash_chip_id, part_type, address, size, label, encrypted, ota_state)| {
+                model::Partition {
+                    flash_chip_id,
+                    part_type,
+                    address,
+                    size,
+                    label,
+                    encrypted,
+                    ota_state,
+                }
+            },
+        )
+}
+
+// Each flag either points at one of the partitions, nowhere, or somewhere
+// which isn't a partition at all.
+fn flag(addrs: Vec<usize>) -> impl Strategy<Value = Option<usize>> {
+    prop_oneof![
+        Just(None),
+        address().prop_map(Some),
+        proptest::sample::select(if addrs.is_empty() { vec![0] } else { addrs }).prop_map(Some),
+    ]
+}
+
+fn partitions() -> impl Strategy<Value = model::Partitions> {
+    prop::collection::vec(partition(), 0..12).prop_flat_map(|list| {
+        let addrs: Vec<usize> = list.iter().map(|p| p.address).collect();
+        (
+            flag(addrs.clone()),
+            flag(addrs.clone()),
+            flag(addrs.clone()),
+            flag(addrs),
+            any::<bool>(),
+            Just(list),
+        )
+            .prop_map(
+                |(boot, running, last_invalid, next_update, is_rollback_possible, list)| {
+                    model::Partitions {
+                        boot,
+                        running,
+                        last_invalid,
+                        next_update,
+                        is_rollback_possible,
+                        list,
+                    }
+                },
+            )
+    })
+}
+
+fn id_message() -> impl Strategy<Value = model::IdMessage> {
+    let app_desc = (
+        "[ -~]{0,16}",
+        "[ -~]{0,16}",
+        any::<usize>(),
+        "[ -~]{0,12}",
+        "[ -~]{0,8}",
+    )
+        .prop_map(
+            |(project_name, version, secure_version, date, time)| model::AppDesc {
+                project_name,
+                version,
+                secure_version,
+                date,
+                time,
+            },
+        );
+
+    let hardware = (
+        proptest::option::of("[a-z0-9]{0,8}"),
+        any::<Option<usize>>(),
+        proptest::option::of("[0-9a-f:]{0,17}"),
+        any::<Option<usize>>(),
+    )
+        .prop_map(
+            |(chip_model, chip_revision, mac, flash_size)| model::Hardware {
+                chip_model,
+                chip_revision,
+                mac,
+                flash_size,
+            },
+        );
+
+    let runtime = (
+        proptest::option::of("[ -~]{0,8}"),
+        any::<Option<u64>>(),
+        proptest::option::of("[a-z_]{0,10}"),
+        any::<Option<usize>>(),
+        any::<Option<i32>>(),
+    )
+        .prop_map(
+            |(idf_version, uptime_s, reset_reason, free_heap, rssi)| model::Runtime {
+                idf_version,
+                uptime_s,
+                reset_reason,
+                free_heap,
+                rssi,
+            },
+        );
+
+    (
+        1..5u32,
+        app_desc,
+        partitions(),
+        prop::option::of(hardware),
+        prop::option::of(runtime),
+    )
+        .prop_map(
+            |(schema, app_desc, partitions, hardware, runtime)| model::IdMessage {
+                schema,
+                software: model::Software {
+                    app_desc,
+                    partitions,
+                },
+                hardware,
+                runtime,
+            },
+        )
+}
+
+fn json_value() -> impl Strategy<Value = serde_json::Value> {
+    let leaf = prop_oneof![
+        Just(serde_json::Value::Null),
+        any::<bool>().prop_map(serde_json::Value::from),
+        any::<i64>().prop_map(serde_json::Value::from),
+        "[a-z_]{0,10}".prop_map(serde_json::Value::from),
+    ];
+
+    leaf.prop_recursive(4, 32, 6, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..6).prop_map(serde_json::Value::from),
+            prop::collection::btree_map(
+                prop_oneof![
+                    Just("schema".to_owned()),
+                    Just("software".to_owned()),
+                    Just("partitions".to_owned()),
+                    Just("list".to_owned()),
+                    Just("state".to_owned()),
+                    "[a-z_]{1,10}"
+                ],
+                inner,
+                0..6
+            )
+            .prop_map(|map| serde_json::Value::Object(map.into_iter().collect())),
+        ]
+    })
+}
+
+fn encode_cbor<T: serde::Serialize>(value: &T) -> Vec<u8> {
+    let mut payload = Vec::new();
+    ciborium::ser::into_writer(value, &mut payload).unwrap();
+    payload
+}
+
+fn render(id: model::IdMessage) -> decode::DecodedIdMessage {
+    let partitions = &id.software.partitions;
+    let flash_size = id.hardware.as_ref().and_then(|hw| hw.flash_size);
+    decode::format_partition_table(partitions);
+    decode::format_flash_map(partitions, flash_size, 80);
+
+    decode::decode_id_message(id)
+}
+
+proptest! {
+    #[test]
+    fn id_message_roundtrips(id in id_message()) {
+        let expected = serde_json::to_value(&id).unwrap();
+
+        let json = serde_json::to_vec(&id).unwrap();
+        let parsed = decode::parse_id_message(&json, payload::sniff(&json)).unwrap();
+        prop_assert_eq!(serde_json::to_value(&parsed).unwrap(), expected.clone());
+
+        let cbor = encode_cbor(&id);
+        let parsed = decode::parse_id_message(&cbor, payload::sniff(&cbor)).unwrap();
+        prop_assert_eq!(serde_json::to_value(&parsed).unwrap(), expected);
+    }
+
+    #[test]
+    fn id_message_renders(id in id_message()) {
+        init_plain();
+        render(id);
+    }
+
+    // In plain mode each partition is shown as `<part>:<state>[<flags>]`, in
+    // address order, and the flags must agree with the reported addresses.
+    #[test]
+    fn legend_flags_match_addresses(id in id_message()) {
+        init_plain();
+
+        let partitions = id.software.partitions.clone();
+        let decoded = render(id);
+
+        let line = decoded
+            .ota_info
+            .fmt
+            .lines()
+            .find_map(|line| line.trim_start().strip_prefix("Partitions: "))
+            .unwrap();
+        let cells = line.split(", running on ").next().unwrap();
+
+        let mut list: Vec<&model::Partition> = partitions.list.iter().collect();
+        list.sort_by_key(|p| p.address);
+
+        let cells: Vec<&str> = if cells.is_empty() { vec![] } else { cells.split(' ').collect() };
+        prop_assert_eq!(cells.len(), list.len());
+
+        for (cell, part) in cells.iter().zip(list) {
+            let flags = cell
+                .strip_suffix(']')
+                .and_then(|cell| cell.rsplit_once('['))
+                .map_or("", |(_, flags)| flags);
+
+            for (flag, addr) in [
+                ('R', partitions.running),
+                ('B', partitions.boot),
+                ('U', partitions.next_update),
+                ('I', partitions.last_invalid),
+            ] {
+                prop_assert_eq!(flags.contains(flag), addr == Some(part.address), "{} in {}", flag, cell);
+            }
+        }
+    }
+
+    #[test]
+    fn arbitrary_json_never_panics(value in json_value()) {
+        init_plain();
+
+        let json = serde_json::to_vec(&value).unwrap();
+        let cbor = encode_cbor(&value);
+
+        for payload in [&json, &cbor] {
+            let content_type = payload::sniff(payload);
+            if let Ok(id) = decode::parse_id_message(payload, content_type) {
+                render(id);
+            }
+            if let Ok(status) = payload::parse::<model::StatusMessage>(payload, content_type) {
+                decode::decode_status_message(&status);
+            }
+            let _ = payload::parse::<model::OtaMessage>(payload, content_type);
+        }
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+        init_plain();
+
+        let content_type = payload::sniff(&bytes);
+        if let Ok(id) = decode::parse_id_message(&bytes, content_type) {
+            render(id);
+        }
+        let _ = payload::parse::<model::StatusMessage>(&bytes, content_type);
+        let _ = payload::parse::<model::OtaMessage>(&bytes, content_type);
+    }
+}
+
+// Version 1 firmware doesn't say which schema it speaks, but some already
+// reported what it runs on.
+#[test]
+fn unversioned_id_message_keeps_hardware() {
+    let json = serde_json::json!({
+        "software": {
+            "app_desc": {
+                "magic_word": 0xabcd5432u32,
+                "secure_version": 0,
+                "version": "1.0.0",
+                "project_name": "app",
+                "time": "12:00:00",
+                "date": "Jan  1 2021",
+                "idf_ver": "v4.2",
+                "app_elf_sha256": "00",
+            },
+            "partitions": { "is_rollback_possible": false, "list": [] },
+        },
+        "hardware": {
+            "chip_model": "esp32",
+            "chip_revision": 1,
+            "mac": "02:00:00:00:00:01",
+            "flash_size": 4194304,
+        },
+    });
+    let json = serde_json::to_vec(&json).unwrap();
+
+    let id = decode::parse_id_message(&json, payload::sniff(&json)).unwrap();
+
+    assert_eq!(id.schema, model::LEGACY_SCHEMA_VERSION);
+    assert_eq!(id.hardware.unwrap().chip_model.as_deref(), Some("esp32"));
+}
+
+#[test]
+fn partial_hardware_and_runtime_render_as_unknown() {
+    init_plain();
+
+    let id: model::IdMessage = serde_json::from_value(serde_json::json!({
+        "software": {
+            "app_desc": {
+                "version": "1.0.0",
+                "project_name": "app",
+                "secure_version": 0,
+                "time": "12:00:00",
+                "date": "Jan  1 2021",
+            },
+            "partitions": { "is_rollback_possible": false, "list": [] },
+        },
+        "hardware": { "chip_model": "esp32" },
+        "runtime": { "uptime_s": 60 },
+    }))
+    .unwrap();
+
+    let decoded = decode::decode_id_message(id);
+
+    assert!(
+        decoded
+            .ota_info
+            .fmt
+            .contains("Hardware: esp32 rev unknown, unknown flash, MAC unknown"),
+        "{}",
+        decoded.ota_info.fmt
+    );
+    assert!(
+        decoded
+            .ota_info
+            .fmt
+            .contains("Runtime: ESP-IDF unknown, up 1m, last reset: unknown, unknown free heap"),
+        "{}",
+        decoded.ota_info.fmt
+    );
+}
+
+fn id_fixture(version: u32) -> Vec<u8> {
+    std::fs::read(
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/data")
+            .join(format!("id-v{}.json", version)),
+    )
+    .unwrap()
+}
+
+fn parse_id_fixture(version: u32) -> model::IdMessage {
+    let json = id_fixture(version);
+    decode::parse_id_message(&json, payload::sniff(&json)).unwrap()
+}
+
+// Version 1 firmware sent its hardware however it liked, which we only keep
+// where it fits.
+#[test]
+fn id_message_v1_keeps_what_fits() {
+    let id = parse_id_fixture(1);
+
+    assert_eq!(id.schema, model::LEGACY_SCHEMA_VERSION);
+    assert!(id.hardware.is_none());
+    assert_eq!(id.runtime.unwrap().uptime_s, Some(3600));
+}
+
+#[test]
+fn id_message_v2_has_software_only() {
+    let id = parse_id_fixture(2);
+
+    assert_eq!(id.schema, 2);
+    assert_eq!(id.software.partitions.running, Some(0x110000));
+    assert!(id.hardware.is_none());
+    assert!(id.runtime.is_none());
+}
+
+#[test]
+fn id_message_v3_has_hardware() {
+    let id = parse_id_fixture(3);
+
+    assert_eq!(id.schema, 3);
+    assert_eq!(id.hardware.unwrap().flash_size, Some(4 * 1024 * 1024));
+    assert!(id.runtime.is_none());
+}
+
+#[test]
+fn id_message_v4_has_everything() {
+    let id = parse_id_fixture(4);
+
+    assert_eq!(id.schema, model::SCHEMA_VERSION);
+    assert_eq!(id.hardware.unwrap().chip_model.as_deref(), Some("esp32"));
+    assert_eq!(id.runtime.unwrap().rssi, Some(-60));
+}
+
+// Unlike a version we don't know, one we do has to be what it says it is.
+#[test]
+fn id_message_of_known_version_must_fit() {
+    let mut json: serde_json::Value = serde_json::from_slice(&id_fixture(4)).unwrap();
+    json["hardware"]["flash_size"] = "4MB".into();
+    let json = serde_json::to_vec(&json).unwrap();
+
+    assert!(decode::parse_id_message(&json, payload::sniff(&json)).is_err());
+}
+
+#[test]
+fn id_message_of_newer_version_is_best_effort() {
+    init_plain();
+
+    let id = parse_id_fixture(5);
+
+    assert_eq!(id.schema, 5);
+    assert!(id.hardware.is_none());
+    assert_eq!(id.runtime.as_ref().unwrap().rssi, Some(-60));
+
+    let decoded = decode::decode_id_message(id);
+    assert!(decoded
+        .warnings
+        .iter()
+        .any(|warning| matches!(warning, decode::DecodeWarning::NewerSchema { version: 5 })));
+}
+
+fn partition_json(subtype: serde_json::Value, ota_state: &str) -> serde_json::Value {
+    serde_json::json!({
+        "flash_chip_id": 0,
+        "type": { "name": "data", "subtype": subtype },
+        "address": 0x9000,
+        "size": 0x6000,
+        "label": "storage",
+        "encrypted": false,
+        "ota_state": ota_state,
+    })
+}
+
+#[test]
+fn bare_partition_subtypes_are_tolerated() {
+    for (subtype, expected) in [
+        (
+            serde_json::json!({ "name": "nvs" }),
+            model::PartitionDataSubtype::Nvs,
+        ),
+        (serde_json::json!("nvs"), model::PartitionDataSubtype::Nvs),
+        (
+            serde_json::json!("littlefs"),
+            model::PartitionDataSubtype::Unknown("littlefs".to_owned()),
+        ),
+        (
+            serde_json::json!(0x99),
+            model::PartitionDataSubtype::Unknown("0x99".to_owned()),
+        ),
+    ] {
+        let part: model::Partition =
+            serde_json::from_value(partition_json(subtype.clone(), "undefined")).unwrap();
+        assert_eq!(
+            part.part_type,
+            model::PartitionType::Data(expected),
+            "{}",
+            subtype
+        );
+    }
+}
+
+#[test]
+fn partition_subtype_schema_describes_every_form() {
+    let doc = iota::data::schema::documents()
+        .into_iter()
+        .find(|doc| doc.name == "id_message")
+        .unwrap();
+    let schema = serde_json::to_value(&doc.schema).unwrap();
+
+    let forms: Vec<&str> = schema["definitions"]["PartitionSubtype"]["anyOf"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|form| form["type"].as_str().unwrap())
+        .collect();
+    assert_eq!(forms, ["object", "string", "integer"]);
+}
+
+#[test]
+fn unknown_ota_state_renders_as_unknown() {
+    init_plain();
+
+    let part = partition_json(serde_json::json!({ "name": "nvs" }), "x_new");
+    let id: model::IdMessage = serde_json::from_value(serde_json::json!({
+        "software": {
+            "app_desc": {
+                "version": "1.0.0",
+                "project_name": "app",
+                "secure_version": 0,
+                "time": "12:00:00",
+                "date": "Jan  1 2021",
+            },
+            "partitions": { "is_rollback_possible": false, "list": [part] },
+        },
+    }))
+    .unwrap();
+
+    let decoded = decode::decode_id_message(id);
+
+    assert!(
+        decoded.ota_info.fmt.contains("Partitions: ?:?,"),
+        "{}",
+        decoded.ota_info.fmt
+    );
+}
+
+#[test]
+fn flash_map_survives_partitions_at_end_of_address_space() {
+    init_plain();
+
+    let part: model::Partition = serde_json::from_value(partition_json(
+        serde_json::json!({ "name": "nvs" }),
+        "valid",
+    ))
+    .unwrap();
+    let partitions = model::Partitions {
+        boot: None,
+        running: None,
+        last_invalid: None,
+        next_update: None,
+        is_rollback_possible: false,
+        list: vec![model::Partition {
+            address: usize::MAX - 0x1000,
+            size: usize::MAX,
+            ..part
+        }],
+    };
+
+    decode::format_flash_map(&partitions, Some(usize::MAX), 80);
+}
+
+#[test]
+fn sizes_are_formatted_in_the_largest_whole_unit() {
+    assert_eq!(decode::format_size(2 * 1024 * 1024), "2M");
+    assert_eq!(decode::format_size(0x6000), "24K");
+    assert_eq!(decode::format_size(0x180000), "1536K");
+    assert_eq!(decode::format_size(0x1001), "4097");
+}
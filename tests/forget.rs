@@ -0,0 +1,105 @@
+use iota::cache;
+use iota::data::model;
+use iota::net::transport::{MemoryTransport, Reply};
+use iota::op::{self, forget};
+use iota::ui;
+use std::path::Path;
+use std::time::Duration;
+
+// Gives each test a cache of its own, away from the real one.
+fn isolate_cache() {
+    let test = std::thread::current()
+        .name()
+        .unwrap_or("main")
+        .replace("::", "-");
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(module_path!())
+        .join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    cache::isolate(dir);
+}
+
+fn topic(device_name: &str, suffix: &str) -> String {
+    format!("{}{}/{}", op::TOPIC_PREFIX, device_name, suffix)
+}
+
+fn broker(device_name: &str) -> MemoryTransport {
+    isolate_cache();
+
+    let mut transport = MemoryTransport::new(|_, _| Vec::new());
+    for (suffix, payload) in [
+        ("_info/status", &br#"{"state":"up"}"#[..]),
+        ("_info/id", &br#"{"schema":2}"#[..]),
+    ] {
+        transport.inject(Reply {
+            topic: topic(device_name, suffix),
+            payload: payload.to_vec(),
+            retain: true,
+        });
+    }
+    cache::store_status(
+        device_name,
+        &model::StatusMessage {
+            state: model::DeviceState::Up,
+        },
+    );
+
+    transport
+}
+
+fn forget(transport: &mut MemoryTransport, device_name: &str, yes: bool) -> (bool, String) {
+    ui::capture(|| forget::perform_on(transport, device_name, Duration::ZERO, yes))
+}
+
+#[test]
+fn forget_clears_retained_messages_and_cache() {
+    let mut transport = broker("retired-a");
+
+    let (ok, out) = forget(&mut transport, "retired-a", true);
+
+    assert!(ok);
+    assert!(
+        out.contains("retained message on 'hoek/iot/retired-a/_info/status'"),
+        "{}",
+        out
+    );
+    assert!(out.contains("its cached state"), "{}", out);
+    assert!(out.contains("2 retained messages cleared"), "{}", out);
+    assert!(cache::load("retired-a").is_none());
+
+    assert_eq!(
+        transport.published(),
+        &[
+            (topic("retired-a", "_info/id"), Vec::new()),
+            (topic("retired-a", "_info/status"), Vec::new()),
+        ]
+    );
+}
+
+#[test]
+fn forget_needs_confirmation() {
+    let mut transport = broker("retired-b");
+
+    // Nobody is there to confirm while capturing.
+    let (ok, out) = forget(&mut transport, "retired-b", false);
+
+    assert!(!ok);
+    assert!(out.contains("Nothing was removed."), "{}", out);
+    assert!(transport.published().is_empty());
+    assert!(cache::load("retired-b").is_some());
+}
+
+#[test]
+fn forget_unknown_device() {
+    isolate_cache();
+    let mut transport = MemoryTransport::new(|_, _| Vec::new());
+
+    let (ok, out) = forget(&mut transport, "never-seen", true);
+
+    assert!(ok);
+    assert!(
+        out.contains("Nothing is known about device 'never-seen'."),
+        "{}",
+        out
+    );
+}
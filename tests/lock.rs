@@ -0,0 +1,159 @@
+use iota::data::model;
+use iota::net::mqtt;
+use iota::net::transport::{MemoryTransport, Reply};
+use iota::op::lock;
+use iota::{cache, ui};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEVICE: &str = "sim-device";
+
+// Gives each test a cache of its own, away from the real one.
+fn isolate_cache() {
+    let test = std::thread::current()
+        .name()
+        .unwrap_or("main")
+        .replace("::", "-");
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(module_path!())
+        .join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    cache::isolate(dir);
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn lock_reply(client_id: &str, expires: u64) -> Reply {
+    let held = model::LockMessage {
+        owner: "bob@elsewhere".to_owned(),
+        client_id: client_id.to_owned(),
+        op: "rollback".to_owned(),
+        expires,
+    };
+
+    Reply {
+        topic: lock::lock_topic(DEVICE),
+        payload: serde_json::to_vec(&held).unwrap(),
+        retain: true,
+    }
+}
+
+fn broker() -> MemoryTransport {
+    isolate_cache();
+    MemoryTransport::new(|_, _| Vec::new())
+}
+
+fn acquire(transport: &mut MemoryTransport, force: bool) -> (Option<lock::Lock>, String) {
+    ui::capture(|| lock::acquire(transport, DEVICE, "ota", force))
+}
+
+// The payloads we have published to the lock topic, in order.
+fn lock_publishes(transport: &MemoryTransport) -> Vec<Vec<u8>> {
+    transport
+        .published()
+        .iter()
+        .filter(|(topic, _)| *topic == lock::lock_topic(DEVICE))
+        .map(|(_, payload)| payload.clone())
+        .collect()
+}
+
+#[test]
+fn free_lock_is_taken_and_released() {
+    let mut transport = broker();
+
+    let (held, out) = acquire(&mut transport, false);
+    assert!(out.is_empty(), "{}", out);
+
+    let published = lock_publishes(&transport);
+    assert_eq!(published.len(), 1);
+    let ours: model::LockMessage = serde_json::from_slice(&published[0]).unwrap();
+    assert_eq!(ours.client_id, mqtt::client_id());
+    assert_eq!(ours.op, "ota");
+    assert!(ours.expires > unix_now());
+
+    held.unwrap().release(&mut transport);
+    assert_eq!(lock_publishes(&transport).last().unwrap(), b"");
+}
+
+#[test]
+fn lock_is_released_when_operation_panics() {
+    let mut transport = broker();
+
+    let (held, _) = acquire(&mut transport, false);
+    let lock = held.unwrap();
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _held = lock.hold(&mut transport);
+        panic!("operation went wrong");
+    }));
+
+    assert!(result.is_err());
+    assert_eq!(lock_publishes(&transport).last().unwrap(), b"");
+}
+
+#[test]
+fn live_lock_is_refused() {
+    let mut transport = broker();
+    transport.inject(lock_reply("iota-other", unix_now() + 60));
+
+    let (held, out) = acquire(&mut transport, false);
+
+    assert!(held.is_none());
+    assert!(
+        out.contains("is locked by bob@elsewhere for `rollback`"),
+        "{}",
+        out
+    );
+    assert!(out.contains("--force"), "{}", out);
+    assert!(lock_publishes(&transport).is_empty());
+}
+
+#[test]
+fn live_lock_is_overridden_with_force() {
+    let mut transport = broker();
+    transport.inject(lock_reply("iota-other", unix_now() + 60));
+
+    let (held, out) = acquire(&mut transport, true);
+
+    assert!(held.is_some());
+    assert!(out.contains("Overriding lock"), "{}", out);
+}
+
+#[test]
+fn stale_lock_is_reported_and_taken_over() {
+    let mut transport = broker();
+    transport.inject(lock_reply("iota-other", unix_now() - 120));
+
+    let (held, out) = acquire(&mut transport, false);
+
+    assert!(held.is_some());
+    assert!(
+        out.contains("Taking over stale lock on device 'sim-device', held by bob@elsewhere"),
+        "{}",
+        out
+    );
+    assert!(out.contains("expired 2m ago"), "{}", out);
+}
+
+#[test]
+fn lock_taken_at_the_same_time_is_lost() {
+    isolate_cache();
+    let expires = unix_now() + 60;
+    let mut transport = MemoryTransport::new(move |topic, _| {
+        if topic == lock::lock_topic(DEVICE) {
+            vec![lock_reply("iota-other", expires)]
+        } else {
+            Vec::new()
+        }
+    });
+
+    let (held, out) = acquire(&mut transport, false);
+
+    assert!(held.is_none());
+    assert!(out.contains("at the same time"), "{}", out);
+}
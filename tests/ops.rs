@@ -0,0 +1,515 @@
+use iota::data::{decode, model, partition_table};
+use iota::net::transport::{MemoryTransport, Reply, Transport};
+use iota::op::{self, ExitDisposition};
+use iota::{cache, sim, ui};
+use std::path::Path;
+
+const DEVICE: &str = "sim-device";
+
+// Gives each test a cache of its own, away from the real one.
+fn isolate_cache() {
+    let test = std::thread::current()
+        .name()
+        .unwrap_or("main")
+        .replace("::", "-");
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(module_path!())
+        .join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    cache::isolate(dir);
+}
+
+fn device() -> MemoryTransport {
+    isolate_cache();
+    sim::memory_transport(sim::Device::new(sim::Config::new(DEVICE)).unwrap())
+}
+
+fn run<Op: op::Operation>(op: Op, transport: &mut dyn Transport) -> (ExitDisposition, String) {
+    ui::capture(|| op::perform_op_on(&op, transport, DEVICE))
+}
+
+fn ota() -> op::ota::Operation<'static> {
+    op::ota::Operation {
+        url: "https://example.invalid/firmware.bin",
+        ca_cert: "",
+    }
+}
+
+fn mark(mark: op::mark::Mark) -> op::mark::Operation {
+    op::mark::Operation { mark }
+}
+
+#[test]
+fn status_reports_device() {
+    let mut transport = device();
+
+    let (ed, out) = run(op::status::Operation {}, &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Ok);
+    assert!(out.contains("Reported firmware: sim/1.0.0"), "{}", out);
+    assert!(out.contains("Device status found!"), "{}", out);
+}
+
+#[test]
+fn status_fails_when_device_down() {
+    let mut transport = device();
+    transport.inject(Reply {
+        topic: format!("{}{}/_info/status", op::TOPIC_PREFIX, DEVICE),
+        payload: br#"{"state":"down"}"#.to_vec(),
+        retain: true,
+    });
+
+    let (ed, out) = run(op::status::Operation {}, &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Abort);
+    assert!(out.contains("Device is down!"), "{}", out);
+}
+
+#[test]
+fn restart_waits_for_power_cycle() {
+    let mut transport = device();
+
+    let (ed, out) = run(op::restart::Operation {}, &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Ok);
+    assert!(out.contains("Device reconnected!"), "{}", out);
+    assert!(transport
+        .published()
+        .iter()
+        .any(|(topic, _)| topic.ends_with("/_cmd/restart")));
+}
+
+#[test]
+fn ota_then_validate() {
+    let mut transport = device();
+
+    let (ed, out) = run(ota(), &mut transport);
+    assert_eq!(ed, ExitDisposition::Ok);
+    assert!(out.contains("OTA successful!"), "{}", out);
+    assert!(out.contains("running on ota 1 partition"), "{}", out);
+
+    let (ed, out) = run(mark(op::mark::Mark::Validate), &mut transport);
+    assert_eq!(ed, ExitDisposition::Ok);
+    assert!(out.contains("Operation validate"), "{}", out);
+}
+
+// Without acknowledgements, we can only tell the rollback worked from what
+// the device reports after restarting.
+#[test]
+fn ota_then_rollback_without_acks() {
+    isolate_cache();
+    let mut config = sim::Config::new(DEVICE);
+    config.schema = 2;
+    let mut transport = sim::memory_transport(sim::Device::new(config).unwrap());
+
+    let (ed, _) = run(ota(), &mut transport);
+    assert_eq!(ed, ExitDisposition::Ok);
+
+    let (ed, out) = run(mark(op::mark::Mark::Rollback), &mut transport);
+    assert_eq!(ed, ExitDisposition::Ok);
+    assert!(out.contains("Operation rollback"), "{}", out);
+    assert!(out.contains("running on ota 0 partition"), "{}", out);
+}
+
+#[test]
+fn ota_then_rollback_acknowledged() {
+    let mut transport = device();
+
+    let (ed, _) = run(ota(), &mut transport);
+    assert_eq!(ed, ExitDisposition::Ok);
+
+    let (ed, out) = run(mark(op::mark::Mark::Rollback), &mut transport);
+    assert_eq!(ed, ExitDisposition::Ok);
+    assert!(out.contains("Operation rollback"), "{}", out);
+    // An ack only says the device took the command, so we still wait to see
+    // it running on the previous partition.
+    assert!(out.contains("running on ota 0 partition"), "{}", out);
+}
+
+// A device which is up, but only does what `respond` says it does.
+fn device_responding(
+    respond: impl FnMut(&mut sim::Device, &str, &[u8]) -> Vec<sim::Action> + 'static,
+) -> MemoryTransport {
+    isolate_cache();
+    sim::memory_transport_responding(sim::Device::new(sim::Config::new(DEVICE)).unwrap(), respond)
+}
+
+#[test]
+fn rejected_command_reports_reason() {
+    // A device which refuses to restart.
+    let mut transport = device_responding(|device, topic, payload| {
+        if !topic.ends_with("/_cmd/restart") {
+            return device.handle(topic, payload);
+        }
+
+        let request: serde_json::Value = serde_json::from_slice(payload).unwrap();
+        vec![sim::Action::Publish {
+            topic: format!("{}{}/_info/ack", op::TOPIC_PREFIX, DEVICE),
+            payload: serde_json::to_vec(&model::AckMessage {
+                request_id: request["request_id"].as_str().unwrap().to_owned(),
+                status: model::AckStatus::Rejected,
+                reason: Some("busy".to_owned()),
+            })
+            .unwrap(),
+            retain: false,
+        }]
+    });
+
+    let (ed, out) = run(op::restart::Operation {}, &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Abort);
+    assert!(
+        out.contains("Device rejected the command (busy)!"),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn restart_down_before_ack_is_not_lost() {
+    // A device which goes down before getting round to acking the restart.
+    let mut transport = device_responding(|device, topic, payload| {
+        let mut actions = device.handle(topic, payload);
+        if topic.ends_with("/_cmd/restart") {
+            let ack = actions.remove(0);
+            actions.insert(1, ack);
+        }
+        actions
+    });
+
+    let (ed, out) = run(op::restart::Operation {}, &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Ok);
+    assert!(out.contains("Device reconnected!"), "{}", out);
+}
+
+#[test]
+fn status_requests_fresh_id() {
+    let mut transport = device();
+
+    let (ed, out) = run(op::status::Operation {}, &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Ok);
+    assert!(!out.contains("may be out of date"), "{}", out);
+    assert!(transport
+        .published()
+        .iter()
+        .any(|(topic, _)| topic.ends_with("/_cmd/id")));
+}
+
+#[test]
+fn garbled_status_while_refreshing_id_is_not_down() {
+    let mut transport = device_responding(|device, topic, payload| {
+        let mut actions = device.handle(topic, payload);
+        if topic.ends_with("/_cmd/id") {
+            actions.insert(
+                0,
+                sim::Action::Publish {
+                    topic: format!("{}{}/_info/status", op::TOPIC_PREFIX, DEVICE),
+                    payload: b"{\"state\":".to_vec(),
+                    retain: false,
+                },
+            );
+        }
+        actions
+    });
+
+    let (ed, out) = run(op::status::Operation {}, &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Ok);
+    assert!(!out.contains("Device is offline"), "{}", out);
+}
+
+#[test]
+fn silent_device_is_offline_despite_retained_status() {
+    // Whatever the broker retained, the device itself no longer answers.
+    let mut transport = device_responding(|_, _, _| Vec::new());
+
+    let (ed, out) = run(op::status::Operation {}, &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Abort);
+    assert!(
+        out.contains("Device is offline, despite its retained 'Up' status!"),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn old_firmware_is_not_asked_for_fresh_id() {
+    isolate_cache();
+    let mut config = sim::Config::new(DEVICE);
+    config.schema = 3;
+    let mut transport = sim::memory_transport(sim::Device::new(config).unwrap());
+
+    let (ed, _) = run(op::status::Operation {}, &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Ok);
+    assert!(transport.published().is_empty());
+}
+
+#[test]
+fn commands_are_sent_in_device_schema() {
+    isolate_cache();
+    let mut config = sim::Config::new(DEVICE);
+    config.schema = 3;
+    let mut transport = sim::memory_transport(sim::Device::new(config).unwrap());
+
+    let (ed, _) = run(op::restart::Operation {}, &mut transport);
+    assert_eq!(ed, ExitDisposition::Ok);
+
+    let (_, payload) = transport
+        .published()
+        .iter()
+        .find(|(topic, _)| topic.ends_with("/_cmd/restart"))
+        .unwrap();
+    let command: serde_json::Value = serde_json::from_slice(payload).unwrap();
+    assert_eq!(command["schema"], 3);
+}
+
+// Stands in for an operation which needs newer firmware than the device's.
+struct NeedsAck;
+
+impl op::Operation for NeedsAck {
+    fn perform(
+        &self,
+        _: &op::TopicBundle,
+        _: &mut dyn Transport,
+        _: &decode::DecodedIdMessage,
+    ) -> ExitDisposition {
+        panic!("performed on firmware without support");
+    }
+
+    fn required_capabilities(&self) -> &'static [decode::Capability] {
+        &[decode::Capability::Ack]
+    }
+
+    fn get_wait_strategy(&self) -> Option<op::PostOperationWaitStrategy> {
+        None
+    }
+
+    fn print_completed_message(&self) {}
+}
+
+#[test]
+fn old_firmware_is_refused() {
+    isolate_cache();
+    let mut config = sim::Config::new(DEVICE);
+    config.schema = 2;
+    let mut transport = sim::memory_transport(sim::Device::new(config).unwrap());
+
+    let (ed, out) = run(NeedsAck, &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Abort);
+    assert!(
+        out.contains(
+            "Device firmware too old for command acknowledgement (speaks schema v2, needs v3)!"
+        ),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn ota_retries_while_pending_verify() {
+    let mut transport = device();
+
+    let (ed, _) = run(ota(), &mut transport);
+    assert_eq!(ed, ExitDisposition::Ok);
+
+    // Restarting without validating makes the bootloader roll back, after
+    // which the update can be retried.
+    let (ed, out) = run(ota(), &mut transport);
+    assert_eq!(ed, ExitDisposition::Retry);
+    assert!(out.contains("OTA update already pending"), "{}", out);
+
+    let (ed, _) = run(ota(), &mut transport);
+    assert_eq!(ed, ExitDisposition::Ok);
+}
+
+#[test]
+fn validate_aborts_when_device_rolls_back_instead() {
+    // A device which takes any validate command for a rollback.
+    let mut transport = device_responding(|device, topic, payload| {
+        let mut command: serde_json::Value = serde_json::from_slice(payload).unwrap();
+        if command["type"] == "validate" {
+            command["type"] = "rollback".into();
+        }
+        device.handle(topic, &serde_json::to_vec(&command).unwrap())
+    });
+
+    let (ed, _) = run(ota(), &mut transport);
+    assert_eq!(ed, ExitDisposition::Ok);
+
+    let (ed, out) = run(mark(op::mark::Mark::Validate), &mut transport);
+    assert_eq!(ed, ExitDisposition::Abort);
+    assert!(
+        out.contains("FAILED: Device unexpectedly running on partition with address"),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn ota_aborts_on_unknown_ota_state() {
+    isolate_cache();
+    let mut config = sim::Config::new(DEVICE);
+    // Too old to be asked for a fresh id message, so the one below sticks.
+    config.schema = 3;
+    let device = sim::Device::new(config).unwrap();
+
+    let mut id = device.id_message();
+    let running = id.software.partitions.running;
+    for part in id.software.partitions.list.iter_mut() {
+        if Some(part.address) == running {
+            part.ota_state = model::OtaState::Unknown("x_new_state".to_owned());
+        }
+    }
+
+    let mut transport = sim::memory_transport(device);
+    transport.inject(Reply {
+        topic: format!("{}{}/_info/id", op::TOPIC_PREFIX, DEVICE),
+        payload: serde_json::to_vec(&id).unwrap(),
+        retain: true,
+    });
+
+    let (ed, out) = run(ota(), &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Abort);
+    assert!(
+        out.contains("Device reports unknown OTA state 'x_new_state'!"),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn validate_refused_when_not_pending() {
+    let mut transport = device();
+
+    let (ed, out) = run(mark(op::mark::Mark::Validate), &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Abort);
+    assert!(
+        out.contains("not acceptable for a validate operation"),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn rollback_refused_without_previous_app() {
+    let mut transport = device();
+
+    let (ed, out) = run(mark(op::mark::Mark::Rollback), &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Abort);
+    assert!(out.contains("rollback is not possible"), "{}", out);
+}
+
+#[test]
+fn validate_refused_on_factory_app() {
+    isolate_cache();
+    let mut config = sim::Config::new(DEVICE);
+    config.layout = partition_table::parse_csv("factory, app, factory, , 1M,\n").unwrap();
+    let mut transport = sim::memory_transport(sim::Device::new(config).unwrap());
+
+    let (ed, out) = run(mark(op::mark::Mark::Validate), &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Abort);
+    assert!(out.contains("factory partition"), "{}", out);
+}
+
+#[test]
+fn partitions_detects_layout_mismatch() {
+    let mut transport = device();
+    let expect = partition_table::parse_csv(&sim::DEFAULT_LAYOUT_CSV.replace(
+        "ota_1,    app,  ota_1,   ,       1M",
+        "ota_1,    app,  ota_1,   ,       2M",
+    ))
+    .unwrap();
+
+    let (ed, out) = run(
+        op::partitions::Operation {
+            expect: Some(expect),
+        },
+        &mut transport,
+    );
+
+    assert_eq!(ed, ExitDisposition::Abort);
+    assert!(
+        out.contains("resized  ota_1: expected 2M, device has 1M"),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn newer_schema_is_warned_about() {
+    isolate_cache();
+    let mut config = sim::Config::new(DEVICE);
+    config.schema = model::SCHEMA_VERSION + 1;
+    let mut transport = sim::memory_transport(sim::Device::new(config).unwrap());
+
+    let (ed, out) = run(op::status::Operation {}, &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Ok);
+    assert!(out.contains("Warning:"), "{}", out);
+}
+
+#[test]
+fn status_reports_cbor_device() {
+    isolate_cache();
+    let mut config = sim::Config::new(DEVICE);
+    config.cbor = true;
+    let mut transport = sim::memory_transport(sim::Device::new(config).unwrap());
+
+    let (ed, out) = run(op::status::Operation {}, &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Ok);
+    assert!(out.contains("Reported firmware: sim/1.0.0"), "{}", out);
+}
+
+#[test]
+fn unreadable_id_message_aborts() {
+    let mut transport = device();
+    transport.inject(Reply {
+        topic: format!("{}{}/_info/id", op::TOPIC_PREFIX, DEVICE),
+        payload: b"{\"software\":".to_vec(),
+        retain: true,
+    });
+
+    let (ed, out) = run(op::status::Operation {}, &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Abort);
+    assert!(
+        out.contains("Could not read the device's id message"),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn unreadable_fresh_id_message_is_skipped() {
+    // A device which garbles its first answer to an id request.
+    let mut transport = device_responding(|device, topic, payload| {
+        let mut actions = device.handle(topic, payload);
+        if topic.ends_with("/_cmd/id") {
+            actions.insert(
+                0,
+                sim::Action::Publish {
+                    topic: format!("{}{}/_info/id", op::TOPIC_PREFIX, DEVICE),
+                    payload: b"{\"software\":".to_vec(),
+                    retain: false,
+                },
+            );
+        }
+        actions
+    });
+
+    let (ed, out) = run(op::status::Operation {}, &mut transport);
+
+    assert_eq!(ed, ExitDisposition::Ok);
+    assert!(out.contains("Unreadable id message"), "{}", out);
+    assert!(out.contains("Device status found!"), "{}", out);
+}
@@ -0,0 +1,129 @@
+use iota::data::model::{self, PartitionAppSubtype as App, PartitionDataSubtype as Data};
+use iota::data::partition_table::{self, Difference, ExpectedPartition, TableError};
+use std::path::Path;
+
+fn data_path(name: &str) -> std::path::PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/data")
+        .join(name)
+}
+
+// As a device would report the partitions, which is what they're compared to.
+fn reported(parts: &[ExpectedPartition]) -> Vec<model::Partition> {
+    parts
+        .iter()
+        .map(|part| model::Partition {
+            flash_chip_id: 0,
+            part_type: part.part_type.clone(),
+            address: part.address,
+            size: part.size,
+            label: part.label.clone(),
+            encrypted: false,
+            ota_state: model::OtaState::Undefined,
+        })
+        .collect()
+}
+
+// `partitions.bin` is laid out as ESP-IDF's `gen_esp32part.py` writes it from
+// `partitions.csv`: entries, an MD5 entry, then erased flash up to 0xc00.
+#[test]
+fn bin_matches_csv() {
+    let bin = partition_table::load(&data_path("partitions.bin")).unwrap();
+    let csv = partition_table::load(&data_path("partitions.csv")).unwrap();
+
+    assert_eq!(bin, csv);
+    assert_eq!(bin.len(), 7);
+    assert_eq!(
+        bin[4],
+        ExpectedPartition {
+            label: "ota_0".to_owned(),
+            part_type: model::PartitionType::App(App::Ota { id: 0 }),
+            address: 0x110000,
+            size: 0x100000,
+        }
+    );
+    assert_eq!(
+        bin[6].part_type,
+        model::PartitionType::Data(Data::Unknown("littlefs".to_owned()))
+    );
+}
+
+#[test]
+fn bin_round_trips_against_device() {
+    let expected = partition_table::load(&data_path("partitions.bin")).unwrap();
+
+    assert!(partition_table::compare(&expected, &reported(&expected)).is_empty());
+}
+
+#[test]
+fn bad_bin_entries_are_errors() {
+    let bin = std::fs::read(data_path("partitions.bin")).unwrap();
+
+    match partition_table::parse_bin(&bin[..40]) {
+        Err(TableError::Bin { offset: 0x20, .. }) => {}
+        other => panic!("expected truncated entry error, got {:?}", other),
+    }
+
+    let mut bad_magic = bin.clone();
+    bad_magic[0x40] = 0;
+    match partition_table::parse_bin(&bad_magic) {
+        Err(TableError::Bin { offset: 0x40, .. }) => {}
+        other => panic!("expected bad magic error, got {:?}", other),
+    }
+}
+
+#[test]
+fn csv_offsets_out_of_range_are_errors() {
+    for csv in [
+        "big, data, nvs, 0xffffffffffffffff, 1,\n",
+        "big, data, nvs, 0xfffffffffffff000, 0xff0,\napp, app, factory, , 1M,\n",
+    ] {
+        match partition_table::parse_csv(csv) {
+            Err(TableError::Csv { .. }) => {}
+            other => panic!("expected error for {:?}, got {:?}", csv, other),
+        }
+    }
+}
+
+#[test]
+fn compare_reports_each_difference() {
+    let expected = partition_table::load(&data_path("partitions.csv")).unwrap();
+
+    let mut device = reported(&expected);
+    device.retain(|part| part.label != "phy_init");
+    for part in device.iter_mut() {
+        match part.label.as_str() {
+            "nvs" => part.size = 0x6000,
+            "ota_1" => part.address = 0x220000,
+            "storage" => part.part_type = model::PartitionType::Data(Data::Spiffs),
+            _ => {}
+        }
+    }
+    device.push(model::Partition {
+        label: "coredump".to_owned(),
+        part_type: model::PartitionType::Data(Data::CoreDump),
+        ..device[0].clone()
+    });
+
+    let diffs: Vec<String> = partition_table::compare(&expected, &device)
+        .iter()
+        .map(|diff| match diff {
+            Difference::Missing { label, .. } => format!("missing {}", label),
+            Difference::Extra { label, .. } => format!("extra {}", label),
+            Difference::Moved { label, .. } => format!("moved {}", label),
+            Difference::Resized { label, .. } => format!("resized {}", label),
+            Difference::Retyped { label, .. } => format!("retyped {}", label),
+        })
+        .collect();
+
+    assert_eq!(
+        diffs,
+        [
+            "resized nvs",
+            "missing phy_init",
+            "moved ota_1",
+            "retyped storage",
+            "extra coredump"
+        ]
+    );
+}
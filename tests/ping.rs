@@ -0,0 +1,62 @@
+use iota::op::ping;
+use iota::{cache, sim, ui};
+use std::path::Path;
+use std::time::Duration;
+
+const DEVICE: &str = "sim-device";
+
+// Gives each test a cache of its own, away from the real one.
+fn isolate_cache() {
+    let test = std::thread::current()
+        .name()
+        .unwrap_or("main")
+        .replace("::", "-");
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(module_path!())
+        .join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    cache::isolate(dir);
+}
+
+fn device(schema: u32) -> iota::net::transport::MemoryTransport {
+    isolate_cache();
+    let mut config = sim::Config::new(DEVICE);
+    config.schema = schema;
+    sim::memory_transport(sim::Device::new(config).unwrap())
+}
+
+fn options(count: usize) -> ping::Options {
+    ping::Options {
+        count,
+        interval: Duration::ZERO,
+        timeout: Duration::from_millis(100),
+    }
+}
+
+#[test]
+fn ping_times_each_answer() {
+    let mut transport = device(iota::data::model::SCHEMA_VERSION);
+
+    let (stats, out) = ui::capture(|| ping::perform_on(&mut transport, DEVICE, &options(3)));
+
+    let stats = stats.unwrap();
+    assert_eq!(stats.sent, 3);
+    assert_eq!(stats.received(), 3);
+    assert_eq!(stats.loss_percent(), 0.0);
+    assert!(out.contains("Answer from 'sim-device': seq=3"), "{}", out);
+}
+
+#[test]
+fn ping_needs_id_refresh() {
+    let mut transport = device(3);
+
+    let (stats, out) = ui::capture(|| ping::perform_on(&mut transport, DEVICE, &options(3)));
+
+    assert!(stats.is_none());
+    assert!(
+        out.contains("Device firmware too old for id refresh"),
+        "{}",
+        out
+    );
+    assert!(transport.published().is_empty());
+}
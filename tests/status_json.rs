@@ -0,0 +1,30 @@
+use iota::net::transport::Reply;
+use iota::op::{self, ExitDisposition};
+use iota::{cache, sim, ui};
+use std::path::Path;
+
+const DEVICE: &str = "sim-device";
+
+// The output format is global, so everything in this binary speaks JSON.
+#[test]
+fn status_of_down_device_is_reported() {
+    cache::isolate(Path::new(env!("CARGO_TARGET_TMPDIR")).join(module_path!()));
+    ui::init(ui::OutputFormat::Json, ui::ColorChoice::Never);
+
+    let mut transport = sim::memory_transport(sim::Device::new(sim::Config::new(DEVICE)).unwrap());
+    transport.inject(Reply {
+        topic: format!("{}{}/_info/status", op::TOPIC_PREFIX, DEVICE),
+        payload: br#"{"state":"down"}"#.to_vec(),
+        retain: true,
+    });
+
+    let (ed, out) =
+        ui::capture(|| op::perform_op_on(&op::status::Operation {}, &mut transport, DEVICE));
+
+    assert_eq!(ed, ExitDisposition::Abort);
+    let json = &out[out.find('{').expect(&out)..];
+    let report: serde_json::Value = serde_json::from_str(json).unwrap();
+    assert_eq!(report["name"], DEVICE);
+    assert_eq!(report["state"], "down");
+    assert_eq!(report["app_desc"], serde_json::Value::Null);
+}
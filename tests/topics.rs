@@ -0,0 +1,111 @@
+use iota::net::transport::{MemoryTransport, Reply, Transport};
+use iota::op::{self, topics};
+use iota::{cache, ui};
+use rumqttc::QoS;
+use std::path::Path;
+use std::time::Duration;
+
+// Gives each test a cache of its own, away from the real one.
+fn isolate_cache() {
+    let test = std::thread::current()
+        .name()
+        .unwrap_or("main")
+        .replace("::", "-");
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(module_path!())
+        .join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    cache::isolate(dir);
+}
+
+fn retained(topic: &str, payload: &[u8]) -> Reply {
+    Reply {
+        topic: format!("{}{}", op::TOPIC_PREFIX, topic),
+        payload: payload.to_vec(),
+        retain: true,
+    }
+}
+
+fn broker() -> MemoryTransport {
+    isolate_cache();
+
+    let mut transport = MemoryTransport::new(|_, _| Vec::new());
+    transport.inject(retained("dev-a/_info/status", br#"{"state":"up"}"#));
+    transport.inject(retained("dev-a/_info/id", &[0xa1, 0x61, 0x78, 0x01]));
+    transport.inject(retained("dev-b/_info/status", br#"{"state":"down"}"#));
+    transport
+}
+
+#[test]
+fn topics_lists_retained_messages() {
+    let mut transport = broker();
+
+    let ((), out) = ui::capture(|| topics::list_on(&mut transport, None, Duration::ZERO));
+
+    assert!(out.contains("hoek/iot/dev-a/_info/status"), "{}", out);
+    assert!(out.contains("hoek/iot/dev-b/_info/status"), "{}", out);
+    assert!(out.contains("14 B"), "{}", out);
+    assert!(out.contains(r#"cbor {"x":1}"#), "{}", out);
+}
+
+#[test]
+fn topics_can_be_limited_to_a_device() {
+    let mut transport = broker();
+
+    let ((), out) = ui::capture(|| topics::list_on(&mut transport, Some("dev-b"), Duration::ZERO));
+
+    assert!(out.contains("hoek/iot/dev-b/_info/status"), "{}", out);
+    assert!(!out.contains("dev-a"), "{}", out);
+}
+
+// Live traffic means the broker has handed over everything it retains, so a
+// busy namespace doesn't keep the collection going.
+#[test]
+fn collecting_retained_messages_stops_at_live_traffic() {
+    let mut transport = broker();
+    let filter = format!("{}#", op::TOPIC_PREFIX);
+    transport.subscribe(&filter);
+    transport.inject(Reply {
+        retain: false,
+        ..retained("dev-a/_info/status", br#"{"state":"down"}"#)
+    });
+
+    let retained = topics::collect_retained(&mut transport, &filter, Duration::from_secs(60));
+
+    assert_eq!(retained.len(), 3);
+    assert!(transport.recv_timeout(Duration::ZERO).unwrap().retain);
+}
+
+#[test]
+fn sub_marks_retained_messages() {
+    let mut transport = broker();
+
+    let ((), out) = ui::capture(|| topics::subscribe_on(&mut transport, "hoek/iot/+/_info/status"));
+
+    assert!(
+        out.contains(r#"hoek/iot/dev-a/_info/status (retained): {"state":"up"}"#),
+        "{}",
+        out
+    );
+    assert!(!out.contains("_info/id"), "{}", out);
+}
+
+#[test]
+fn pub_publishes_and_clears() {
+    let mut transport = broker();
+    let topic = "hoek/iot/dev-b/_info/status";
+
+    let ((), out) =
+        ui::capture(|| topics::publish_on(&mut transport, topic, b"", QoS::AtMostOnce, true));
+    assert!(out.contains("Cleared retained message"), "{}", out);
+
+    transport.subscribe(topic);
+    assert!(transport.recv().is_err());
+}
+
+#[test]
+fn filters_are_validated() {
+    assert!(topics::parse_filter("hoek/iot/+/_info/#").is_ok());
+    assert!(topics::parse_filter("hoek/iot/#/_info").is_err());
+    assert!(topics::parse_filter("hoek/iot/dev+").is_err());
+}